@@ -0,0 +1,57 @@
+//! Throughput of acquire/release pairs on independent resources as threads are added.
+//!
+//! Each thread works on its own resource, so with the sharded lock table the rate
+//! should grow with the number of threads, up to the number of cores.
+
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use lockserver::LockManager;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const OPS_PER_THREAD: u64 = 10_000;
+
+/// Time `threads` threads each doing `OPS_PER_THREAD` acquire/release pairs.
+fn run(manager: &Arc<LockManager>, threads: usize, expire_secs: Option<u64>) -> Duration {
+    let start = Instant::now();
+    let handles: Vec<_> = (0..threads)
+        .map(|t| {
+            let manager = manager.clone();
+            thread::spawn(move || {
+                let resource = format!("resource-{t}");
+                let owner = format!("owner-{t}");
+                for _ in 0..OPS_PER_THREAD {
+                    manager.acquire(&resource, &owner, expire_secs).unwrap();
+                    manager.release(&resource, &owner).unwrap();
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    start.elapsed()
+}
+
+fn bench_scaling(c: &mut Criterion, name: &str, expire_secs: Option<u64>) {
+    let cores = thread::available_parallelism().map_or(4, |n| n.get());
+    let mut group = c.benchmark_group(name);
+    let mut threads = 1;
+    while threads <= cores.max(4) {
+        group.throughput(Throughput::Elements(OPS_PER_THREAD * threads as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &n| {
+            let manager = Arc::new(LockManager::new());
+            b.iter_custom(|iters| (0..iters).map(|_| run(&manager, n, expire_secs)).sum());
+        });
+        threads *= 2;
+    }
+    group.finish();
+}
+
+fn independent_resources(c: &mut Criterion) {
+    bench_scaling(c, "independent_resources", None);
+    bench_scaling(c, "independent_resources_with_ttl", Some(60));
+}
+
+criterion_group!(benches, independent_resources);
+criterion_main!(benches);
@@ -0,0 +1,85 @@
+//! # audit
+//!
+//! Audit trail for administrative actions.
+//!
+//! Each action is printed to stdout and, if configured, appended as a JSON line to an
+//! audit log file.
+
+use serde::{Deserialize, Serialize};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// One administrative action.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    /// Unix timestamp in seconds.
+    pub at: u64,
+    /// The action taken, e.g. `"force_release"`.
+    pub action: String,
+    /// Address of the client that requested the action, if known.
+    pub client: Option<String>,
+    /// Identity of the API token the action was requested with, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub identity: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub owner: Option<String>,
+    /// `"ok"`, or the error the action failed with.
+    pub outcome: String,
+}
+
+impl AuditEntry {
+    /// An entry for `action` requested by `client`, timestamped now.
+    pub fn new(action: &str, client: Option<String>) -> Self {
+        Self {
+            at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap()
+                .as_secs(),
+            action: action.to_string(),
+            client,
+            identity: None,
+            resource: None,
+            owner: None,
+            outcome: String::new(),
+        }
+    }
+}
+
+/// Where audit entries are recorded.
+#[derive(Debug, Default)]
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+}
+
+impl AuditLog {
+    /// An audit log that only prints to stdout.
+    pub fn stdout() -> Self {
+        Self::default()
+    }
+
+    /// An audit log that also appends to the file at `path`, creating it if needed.
+    pub fn with_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Some(Mutex::new(file)),
+        })
+    }
+
+    /// Record `entry`.
+    pub fn record(&self, entry: &AuditEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        println!("AUDIT {}", String::from_utf8_lossy(&line));
+        if let Some(file) = &self.file {
+            line.push(b'\n');
+            let mut file = file.lock().unwrap();
+            file.write_all(&line)?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+}
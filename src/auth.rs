@@ -0,0 +1,228 @@
+//! # auth
+//!
+//! API tokens and what they allow.
+//!
+//! Each token maps to an identity, the resource prefixes it may touch and the
+//! operations it may perform. Tokens are read from a JSON file:
+//!
+//! ```json
+//! {
+//!   "tokens": [
+//!     { "token": "s3cr3t-a", "identity": "billing", "prefixes": ["billing/"],
+//!       "scopes": ["acquire", "release", "inspect"] },
+//!     { "token": "s3cr3t-b", "identity": "ops", "scopes": ["inspect", "admin"] }
+//!   ]
+//! }
+//! ```
+//!
+//! Lock owners are namespaced by identity: a client with a token from the file that
+//! claims owner `w1` holds its locks as `billing:w1`, so no other identity can release
+//! or renew them by claiming the same owner. See [`TokenStore::owner`].
+//!
+//! A token without `prefixes` may touch every resource. Several tokens may share an
+//! identity, so a token can be rotated by adding its replacement, moving clients over,
+//! and then removing it, reloading the file with [`TokenStore::reload`] at each step.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+
+/// An operation a token may be allowed to perform.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Take, renew and wait for locks and semaphore permits, and manage sessions.
+    Acquire,
+    /// Release locks and semaphore permits.
+    Release,
+    /// Inspect and list locks, and read cluster status.
+    Inspect,
+    /// Use the `/admin` endpoints.
+    Admin,
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scope::Acquire => "acquire",
+            Scope::Release => "release",
+            Scope::Inspect => "inspect",
+            Scope::Admin => "admin",
+        })
+    }
+}
+
+/// What one token allows.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Grant {
+    /// Who the token belongs to.
+    pub identity: String,
+    /// Resource name prefixes the token may touch; every resource if `None`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefixes: Option<Vec<String>>,
+    pub scopes: Vec<Scope>,
+}
+
+impl Grant {
+    /// Whether the token may perform `scope` on `resource`, or on no resource in
+    /// particular if `None`.
+    pub fn allows(&self, scope: Scope, resource: Option<&str>) -> bool {
+        self.scopes.contains(&scope) && resource.is_none_or(|r| self.covers(r))
+    }
+
+    /// Whether `resource` is within the token's prefixes.
+    pub fn covers(&self, resource: &str) -> bool {
+        self.prefixes
+            .as_ref()
+            .is_none_or(|prefixes| prefixes.iter().any(|p| resource.starts_with(p.as_str())))
+    }
+}
+
+/// A token and its grant, as stored in the token file.
+#[derive(Debug, Deserialize)]
+struct TokenEntry {
+    token: String,
+    #[serde(flatten)]
+    grant: Grant,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenFile {
+    tokens: Vec<TokenEntry>,
+}
+
+/// The tokens the server accepts.
+#[derive(Debug, Default)]
+pub struct TokenStore {
+    path: Option<PathBuf>, // file the tokens are loaded from, if any
+    tokens: RwLock<HashMap<String, Arc<Grant>>>,
+}
+
+impl TokenStore {
+    /// Load tokens from the JSON file at `path`.
+    pub fn from_file(path: impl AsRef<Path>) -> io::Result<Self> {
+        let store = Self {
+            path: Some(path.as_ref().to_path_buf()),
+            tokens: RwLock::default(),
+        };
+        store.reload()?;
+        Ok(store)
+    }
+
+    /// Tokens for a server without a token file: `secret` may acquire, release and
+    /// inspect any lock, and `admin_secret`, if set, may use the admin endpoints.
+    pub fn shared(secret: &str, admin_secret: Option<&str>) -> Self {
+        let mut tokens = HashMap::new();
+        tokens.insert(
+            secret.to_string(),
+            Arc::new(Grant {
+                identity: "shared".to_string(),
+                prefixes: None,
+                scopes: vec![Scope::Acquire, Scope::Release, Scope::Inspect],
+            }),
+        );
+        if let Some(admin_secret) = admin_secret {
+            tokens.insert(
+                admin_secret.to_string(),
+                Arc::new(Grant {
+                    identity: "admin".to_string(),
+                    prefixes: None,
+                    scopes: vec![Scope::Admin],
+                }),
+            );
+        }
+        Self {
+            path: None,
+            tokens: RwLock::new(tokens),
+        }
+    }
+
+    /// Re-read the token file, replacing every token at once. Returns the number of
+    /// tokens loaded.
+    ///
+    /// If the file cannot be read or is invalid, the current tokens are kept. Does
+    /// nothing for a store that has no file.
+    pub fn reload(&self) -> io::Result<usize> {
+        let Some(path) = &self.path else {
+            return Ok(self.len());
+        };
+        let file: TokenFile = serde_json::from_slice(&fs::read(path)?)?;
+        let mut tokens = HashMap::new();
+        for entry in file.tokens {
+            if entry.token.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("empty token for {:?}", entry.grant.identity),
+                ));
+            }
+            if entry.grant.identity.is_empty() || entry.grant.identity.contains(':') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("invalid identity {:?}", entry.grant.identity),
+                ));
+            }
+            if tokens.insert(entry.token, Arc::new(entry.grant)).is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "the same token is listed twice",
+                ));
+            }
+        }
+        let count = tokens.len();
+        *self.tokens.write().unwrap() = tokens;
+        Ok(count)
+    }
+
+    /// The grant for `token`, if it is valid.
+    pub fn authenticate(&self, token: &str) -> Option<Arc<Grant>> {
+        self.tokens.read().unwrap().get(token).cloned()
+    }
+
+    /// The lock owner a request authorized by `grant` acts as when it claims `owner`.
+    ///
+    /// With a token file this is `identity:owner`, so owners claimed under different
+    /// identities never match. With the shared secrets every client is the same
+    /// principal, and `owner` is taken as given.
+    pub fn owner(&self, grant: &Grant, owner: &str) -> String {
+        match self.path {
+            Some(_) => format!("{}:{}", grant.identity, owner),
+            None => owner.to_string(),
+        }
+    }
+
+    /// The id of a session opened under `grant`, given a fresh `id`. As with
+    /// [`TokenStore::owner`], with a token file this is `identity:id`.
+    pub fn session(&self, grant: &Grant, id: &str) -> String {
+        self.owner(grant, id)
+    }
+
+    /// Whether a request authorized by `grant` may heartbeat or close `session`, or bind
+    /// locks to it. With a token file only the identity that opened the session may.
+    pub fn may_use_session(&self, grant: &Grant, session: &str) -> bool {
+        match self.path {
+            Some(_) => session
+                .split_once(':')
+                .is_some_and(|(identity, _)| identity == grant.identity),
+            None => true,
+        }
+    }
+
+    /// Whether the tokens come from a token file rather than the shared secrets.
+    pub fn is_from_file(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// Number of valid tokens.
+    pub fn len(&self) -> usize {
+        self.tokens.read().unwrap().len()
+    }
+
+    /// Whether no token is valid.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
@@ -1,9 +1,19 @@
+use crate::{LockError, LockKind, LockPage, LockStatus};
 use dotenvy::dotenv;
+use reqwest::Method;
 use reqwest::StatusCode;
-use reqwest::blocking::Client as HttpClient;
-use serde::Serialize;
+use reqwest::Url;
+use reqwest::blocking::{Client as HttpClient, Response};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
 use std::env;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
 /// # lockserver_client
 ///
 /// A Rust client library for interacting with a lockserver HTTP instance.
@@ -30,10 +40,112 @@ use std::io;
 /// A client for connecting to a lockserver instance.
 ///
 /// Use this to acquire and release distributed locks.
+#[derive(Debug, Clone)]
 pub struct LockserverClient {
     addr: String,
     owner: String,
     secret: String,
+    quorum: Vec<String>, // independent servers for quorum locking; empty = just `addr`
+    session: Option<String>, // session that acquired locks are bound to
+    reentrant: bool,     // nest holds on locks this owner already holds
+    apis: Arc<Mutex<HashMap<String, Api>>>, // API negotiated with each server, shared by clones
+}
+
+/// The HTTP API a server speaks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Api {
+    /// Resource-oriented routes under `/v1`.
+    V1,
+    /// The unversioned routes of servers that predate `/v1`.
+    Legacy,
+}
+
+/// Seconds a blocking acquire waits server-side before re-polling.
+///
+/// Kept below reqwest's default 30 second request timeout. Every poll of one acquire
+/// carries the same ticket, so the acquire keeps its place in the server's queue.
+const LONG_POLL_SECS: u64 = 20;
+
+/// Per-server request timeout when locking across a quorum, so one slow server cannot
+/// eat the whole validity window.
+const QUORUM_REQUEST_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// First wait before retrying a failed renewal or heartbeat; doubled on each failure.
+const KEEP_ALIVE_RETRY: Duration = Duration::from_millis(50);
+
+/// A lock held on a majority of independent servers.
+///
+/// Returned by [`LockserverClient::acquire_quorum`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QuorumGrant {
+    /// The highest fencing token among the granting servers. Each server numbers its
+    /// tokens independently, so tokens are only comparable between grants that came
+    /// from the same set of servers.
+    pub token: u64,
+    /// How long the lock is still safe to rely on: the requested expiration minus the
+    /// time spent acquiring it and an allowance for clock drift.
+    pub validity: Duration,
+}
+
+/// Errors returned by [`LockserverClient`].
+///
+/// Errors reported by the server are told apart by the `code` in its JSON error body.
+#[derive(Debug, thiserror::Error)]
+pub enum ClientError {
+    /// The lock, or enough semaphore permits, is held by someone else.
+    #[error("Resource is locked")]
+    Locked,
+    /// The resource is locked, but not by this client's owner.
+    #[error("Resource is held by another owner")]
+    NotOwner,
+    /// The resource is not locked.
+    #[error("Resource not found")]
+    NotFound,
+    /// Waiting for the lock would deadlock.
+    #[error("Waiting for the lock would deadlock")]
+    Deadlock,
+    /// The client's session has expired or was closed.
+    #[error("Session not found or expired")]
+    SessionNotFound,
+    /// The request was rejected as malformed.
+    #[error("{0}")]
+    InvalidRequest(String),
+    /// The server rejected the secret.
+    #[error("{0}")]
+    Unauthorized(String),
+    /// The secret is valid, but does not allow the operation on this resource.
+    #[error("{0}")]
+    Forbidden(String),
+    /// Any other error reported by the server, e.g. an internal error or a cluster
+    /// without a leader.
+    #[error("Server error ({status}): {message}")]
+    Server {
+        status: u16,
+        code: String,
+        message: String,
+    },
+    /// The server's response could not be understood.
+    #[error("Invalid response: {0}")]
+    InvalidResponse(String),
+    /// The request could not be sent, or no response arrived.
+    #[error("Request error: {0}")]
+    Request(#[from] reqwest::Error),
+}
+
+impl From<ClientError> for io::Error {
+    fn from(e: ClientError) -> Self {
+        let kind = match &e {
+            ClientError::Locked => io::ErrorKind::WouldBlock,
+            ClientError::Deadlock => io::ErrorKind::Deadlock,
+            ClientError::NotOwner | ClientError::Unauthorized(_) | ClientError::Forbidden(_) => {
+                io::ErrorKind::PermissionDenied
+            }
+            ClientError::NotFound | ClientError::SessionNotFound => io::ErrorKind::NotFound,
+            ClientError::InvalidRequest(_) => io::ErrorKind::InvalidInput,
+            _ => io::ErrorKind::Other,
+        };
+        io::Error::new(kind, e)
+    }
 }
 
 /// Lock acquisition mode: blocking or non-blocking.
@@ -77,6 +189,10 @@ impl LockserverClient {
             addr,
             owner,
             secret,
+            quorum: Vec::new(),
+            session: None,
+            reentrant: false,
+            apis: Arc::default(),
         }
     }
 
@@ -94,93 +210,655 @@ impl LockserverClient {
             addr: addr.into(),
             owner: owner.into(),
             secret: secret.into(),
+            quorum: Vec::new(),
+            session: None,
+            reentrant: false,
+            apis: Arc::default(),
         }
     }
 
-    /// Try to acquire a lock. If mode is Blocking, will retry every 200ms until success.
+    /// Create a client that locks across several independent servers (Redlock).
+    ///
+    /// A lock is held only once a majority of `addrs` have granted it within its
+    /// expiration, so it stays safe while a minority of servers is down. Acquiring
+    /// requires an expiration; [`LockserverClient::acquire_quorum`] reports how much of
+    /// it is left. `release` and `renew` go to every server, while semaphore calls use
+    /// only the first one.
+    pub fn new_quorum(
+        addrs: impl IntoIterator<Item = impl Into<String>>,
+        owner: impl Into<String>,
+        secret: impl Into<String>,
+    ) -> Self {
+        let quorum: Vec<String> = addrs.into_iter().map(|a| a.into()).collect();
+        Self {
+            addr: quorum.first().cloned().unwrap_or_default(),
+            owner: owner.into(),
+            secret: secret.into(),
+            quorum,
+            session: None,
+            reentrant: false,
+            apis: Arc::default(),
+        }
+    }
+
+    /// Make acquires reentrant: acquiring a lock this owner already holds takes
+    /// another nested hold instead of failing or blocking, so nested `lock_scope!`s
+    /// on the same resource work.
+    ///
+    /// Each hold is released separately, e.g. by its own [`LockGuard`]; the lock is
+    /// freed when the outermost one is released.
+    pub fn with_reentrant(mut self, reentrant: bool) -> Self {
+        self.reentrant = reentrant;
+        self
+    }
+
     /// Acquire a lock on a resource. Blocks until the lock is acquired.
-    pub fn acquire(&self, resource: &str) -> io::Result<()> {
+    ///
+    /// Blocking acquires wait in the server's FIFO queue for the resource, so waiters
+    /// are granted the lock in arrival order, however long they wait.
+    ///
+    /// Returns the fencing token issued by the server for this grant.
+    pub fn acquire(&self, resource: &str) -> Result<u64, ClientError> {
         self.acquire_with_mode_and_expire(resource, LockMode::Blocking, None)
     }
 
     /// Acquire a lock on a resource, with blocking or non-blocking mode.
     ///
-    /// Returns an error if the lock cannot be acquired in non-blocking mode.
-    pub fn acquire_with_mode(&self, resource: &str, mode: LockMode) -> io::Result<()> {
+    /// Returns [`ClientError::Locked`] if the lock is held and `mode` is non-blocking.
+    pub fn acquire_with_mode(&self, resource: &str, mode: LockMode) -> Result<u64, ClientError> {
         self.acquire_with_mode_and_expire(resource, mode, None)
     }
 
     /// Acquire a lock with mode and optional expiration (in seconds)
+    ///
+    /// Returns the fencing token issued by the server. Pass it along with writes to
+    /// downstream storage so that writes from a stale holder can be rejected.
+    ///
+    /// On a client created with [`LockserverClient::new_quorum`] this takes the lock on
+    /// a majority of servers, and `expire` is required.
     pub fn acquire_with_mode_and_expire(
         &self,
         resource: &str,
         mode: LockMode,
         expire: Option<u64>,
-    ) -> io::Result<()> {
+    ) -> Result<u64, ClientError> {
+        self.acquire_with_kind(resource, LockKind::Exclusive, mode, expire)
+    }
+
+    /// Acquire a shared (read) or exclusive (write) lock with mode and optional expiration.
+    ///
+    /// Shared locks may be held by several owners at once; a blocked exclusive request
+    /// keeps new shared requests from jumping ahead of it.
+    pub fn acquire_with_kind(
+        &self,
+        resource: &str,
+        kind: LockKind,
+        mode: LockMode,
+        expire: Option<u64>,
+    ) -> Result<u64, ClientError> {
+        self.acquire_with_ttl(resource, kind, mode, expire.map(Duration::from_secs))
+    }
+
+    /// Acquire a lock with the expiration given as a [`Duration`], for expirations
+    /// shorter than a second or not a whole number of seconds.
+    ///
+    /// The server releases an expired lock within a few milliseconds of its deadline.
+    pub fn acquire_with_ttl(
+        &self,
+        resource: &str,
+        kind: LockKind,
+        mode: LockMode,
+        ttl: Option<Duration>,
+    ) -> Result<u64, ClientError> {
+        if !self.quorum.is_empty() {
+            let ttl = ttl.ok_or_else(|| {
+                ClientError::InvalidRequest("Quorum locks require an expiration".to_string())
+            })?;
+            return self
+                .acquire_quorum_for(resource, kind, mode, ttl)
+                .map(|grant| grant.token);
+        }
+        self.acquire_on(&HttpClient::new(), &self.addr, resource, kind, mode, ttl)
+    }
+
+    /// Acquire an exclusive lock on a majority of the servers passed to
+    /// [`LockserverClient::new_quorum`], expiring after `expire` seconds.
+    ///
+    /// Each attempt asks every server once, without waiting in its queue. If a majority
+    /// grants the lock and time is left in the expiration, returns the grant with its
+    /// remaining validity; otherwise the partial grants are released and, in blocking
+    /// mode, the attempt is retried after a random delay.
+    pub fn acquire_quorum(
+        &self,
+        resource: &str,
+        mode: LockMode,
+        expire: u64,
+    ) -> Result<QuorumGrant, ClientError> {
+        self.acquire_quorum_with_kind(resource, LockKind::Exclusive, mode, expire)
+    }
+
+    /// Acquire a shared or exclusive lock on a majority of servers. See
+    /// [`LockserverClient::acquire_quorum`].
+    pub fn acquire_quorum_with_kind(
+        &self,
+        resource: &str,
+        kind: LockKind,
+        mode: LockMode,
+        expire: u64,
+    ) -> Result<QuorumGrant, ClientError> {
+        self.acquire_quorum_for(resource, kind, mode, Duration::from_secs(expire))
+    }
+
+    /// Internal: [`LockserverClient::acquire_quorum_with_kind`] expiring after `ttl`.
+    fn acquire_quorum_for(
+        &self,
+        resource: &str,
+        kind: LockKind,
+        mode: LockMode,
+        ttl: Duration,
+    ) -> Result<QuorumGrant, ClientError> {
+        let servers = self.servers();
+        let client = HttpClient::builder()
+            .timeout(QUORUM_REQUEST_TIMEOUT)
+            .build()?;
+        // Allow for clocks running at slightly different rates on each server.
+        let drift = ttl / 100 + Duration::from_millis(2);
+        loop {
+            let start = Instant::now();
+            let tokens: Vec<u64> = servers
+                .iter()
+                .filter_map(|addr| {
+                    self.acquire_on(
+                        &client,
+                        addr,
+                        resource,
+                        kind,
+                        LockMode::NonBlocking,
+                        Some(ttl),
+                    )
+                    .ok()
+                })
+                .collect();
+            let elapsed = start.elapsed();
+            if tokens.len() > servers.len() / 2
+                && let Some(validity) = ttl.checked_sub(elapsed + drift)
+                && !validity.is_zero()
+            {
+                return Ok(QuorumGrant {
+                    token: tokens.into_iter().max().unwrap_or_default(),
+                    validity,
+                });
+            }
+            // No quorum: undo whatever was granted so others can try.
+            for addr in servers {
+                let _ = self.release_on(&client, addr, resource);
+            }
+            if mode == LockMode::NonBlocking {
+                return Err(ClientError::Locked);
+            }
+            // Random backoff so competing clients don't keep splitting the vote.
+            let jitter = RandomState::new().build_hasher().finish() % 200;
+            thread::sleep(Duration::from_millis(50 + jitter));
+        }
+    }
+
+    /// Internal: acquire a lock on the single server at `addr`.
+    fn acquire_on(
+        &self,
+        client: &HttpClient,
+        addr: &str,
+        resource: &str,
+        kind: LockKind,
+        mode: LockMode,
+        ttl: Option<Duration>,
+    ) -> Result<u64, ClientError> {
         #[derive(Serialize)]
         struct LockRequest<'a> {
-            resource: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            resource: Option<&'a str>, // only for the unversioned route
             owner: &'a str,
-            expire: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            expire: Option<u64>, // legacy servers only take whole seconds
+            #[serde(skip_serializing_if = "Option::is_none")]
+            expire_ms: Option<u64>,
+            wait_timeout: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            ticket: Option<String>,
+            kind: LockKind,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            session: Option<&'a str>,
+            reentrant: bool,
         }
-        let client = HttpClient::new();
-        let url = format!("http://{}/acquire", self.addr);
+        let api = self.api(client, addr)?;
+        let (method, url, resource) = match api {
+            Api::V1 => (Method::PUT, self.lock_url(client, addr, resource)?, None),
+            Api::Legacy => (
+                Method::POST,
+                self.url(client, addr, "/acquire")?,
+                Some(resource),
+            ),
+        };
+        let (expire, expire_ms) = expiration(api, ttl);
         let req = LockRequest {
             resource,
             owner: &self.owner,
             expire,
+            expire_ms,
+            wait_timeout: match mode {
+                LockMode::Blocking => Some(LONG_POLL_SECS),
+                LockMode::NonBlocking => None,
+            },
+            ticket: (mode == LockMode::Blocking).then(new_ticket),
+            kind,
+            session: self.session.as_deref(),
+            reentrant: self.reentrant,
         };
         loop {
             let resp = client
-                .post(&url)
+                .request(method.clone(), url.clone())
                 .header("X-LOCKSERVER-SECRET", &self.secret)
                 .json(&req)
-                .send();
-            match resp {
-                Ok(r) if r.status() == StatusCode::OK => return Ok(()),
-                Ok(r) if r.status() == StatusCode::CONFLICT => {
-                    if mode == LockMode::NonBlocking {
-                        return Err(io::Error::new(
-                            io::ErrorKind::WouldBlock,
-                            "Resource is locked",
-                        ));
-                    } else {
-                        std::thread::sleep(std::time::Duration::from_millis(200));
+                .send()?;
+            match read_token(resp) {
+                Ok(token) => return Ok(token),
+                // Long-poll timed out; wait again. Servers that predate long polling
+                // answer straight away, so don't spin on them.
+                Err(ClientError::Locked) if mode == LockMode::Blocking => {
+                    if api == Api::Legacy {
+                        thread::sleep(Duration::from_millis(200));
                     }
                 }
-                Ok(r) => {
-                    return Err(io::Error::other(format!("HTTP error: {}", r.status())));
-                }
-                Err(e) => {
-                    return Err(io::Error::other(format!("Request error: {}", e)));
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Acquire exclusive locks on all of `resources` at once, or on none of them.
+    ///
+    /// The locks share one optional expiration (in seconds). In blocking mode, retries
+    /// every 200ms until every resource is free. Returns one fencing token per
+    /// resource, in order. Not supported by quorum clients.
+    pub fn acquire_many(
+        &self,
+        resources: &[&str],
+        mode: LockMode,
+        expire: Option<u64>,
+    ) -> Result<Vec<u64>, ClientError> {
+        #[derive(Serialize)]
+        struct BatchRequest<'a> {
+            resources: &'a [&'a str],
+            owner: &'a str,
+            expire: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            session: Option<&'a str>,
+        }
+        #[derive(Deserialize)]
+        struct BatchResponse {
+            tokens: Vec<u64>,
+        }
+        if !self.quorum.is_empty() {
+            return Err(ClientError::InvalidRequest(
+                "Batch acquire is not supported by quorum clients".to_string(),
+            ));
+        }
+        let client = HttpClient::new();
+        let url = self.url(&client, &self.addr, "/acquire-batch")?;
+        let req = BatchRequest {
+            resources,
+            owner: &self.owner,
+            expire,
+            session: self.session.as_deref(),
+        };
+        loop {
+            let resp = client
+                .post(url.clone())
+                .header("X-LOCKSERVER-SECRET", &self.secret)
+                .json(&req)
+                .send()?;
+            match read::<BatchResponse>(resp) {
+                Ok(body) => return Ok(body.tokens),
+                Err(ClientError::Locked) if mode == LockMode::Blocking => {
+                    thread::sleep(Duration::from_millis(200));
                 }
+                Err(e) => return Err(e),
             }
         }
     }
 
     /// Release a lock on a resource.
-    pub fn release(&self, resource: &str) -> io::Result<()> {
+    ///
+    /// A quorum client releases on every server and succeeds if a majority released it.
+    pub fn release(&self, resource: &str) -> Result<(), ClientError> {
+        let client = HttpClient::new();
+        self.on_majority(|addr| self.release_on(&client, addr, resource))
+    }
+
+    /// Internal: release a lock on the single server at `addr`.
+    fn release_on(
+        &self,
+        client: &HttpClient,
+        addr: &str,
+        resource: &str,
+    ) -> Result<(), ClientError> {
         #[derive(Serialize)]
         struct LockRequest<'a> {
             resource: &'a str,
             owner: &'a str,
         }
+        let request = match self.api(client, addr)? {
+            Api::V1 => client
+                .delete(self.lock_url(client, addr, resource)?)
+                .query(&[("owner", &self.owner)]),
+            Api::Legacy => client
+                .post(self.url(client, addr, "/release")?)
+                .json(&LockRequest {
+                    resource,
+                    owner: &self.owner,
+                }),
+        };
+        let resp = request.header("X-LOCKSERVER-SECRET", &self.secret).send()?;
+        check(resp)
+    }
+
+    /// Extend the expiration of a held lock to `expire` seconds from now.
+    ///
+    /// A quorum client renews on every server and succeeds if a majority renewed it.
+    pub fn renew(&self, resource: &str, expire: u64) -> Result<(), ClientError> {
+        self.renew_with_ttl(resource, Duration::from_secs(expire))
+    }
+
+    /// Extend the expiration of a held lock to `ttl` from now.
+    pub fn renew_with_ttl(&self, resource: &str, ttl: Duration) -> Result<(), ClientError> {
+        #[derive(Serialize)]
+        struct RenewRequest<'a> {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            resource: Option<&'a str>, // only for the unversioned route
+            owner: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            expire: Option<u64>, // legacy servers only take whole seconds
+            #[serde(skip_serializing_if = "Option::is_none")]
+            expire_ms: Option<u64>,
+        }
         let client = HttpClient::new();
-        let url = format!("http://{}/release", self.addr);
-        let req = LockRequest {
+        self.on_majority(|addr| {
+            let api = self.api(&client, addr)?;
+            let (expire, expire_ms) = expiration(api, Some(ttl));
+            let (request, resource) = match api {
+                Api::V1 => (client.patch(self.lock_url(&client, addr, resource)?), None),
+                Api::Legacy => (
+                    client.post(self.url(&client, addr, "/renew")?),
+                    Some(resource),
+                ),
+            };
+            let resp = request
+                .header("X-LOCKSERVER-SECRET", &self.secret)
+                .json(&RenewRequest {
+                    resource,
+                    owner: &self.owner,
+                    expire,
+                    expire_ms,
+                })
+                .send()?;
+            check(resp)
+        })
+    }
+
+    /// Internal: the API the server at `addr` speaks, asking it on first use.
+    ///
+    /// Servers that predate `/v1` answer the probe with 404.
+    fn api(&self, client: &HttpClient, addr: &str) -> Result<Api, ClientError> {
+        if let Some(api) = self.apis.lock().unwrap().get(addr) {
+            return Ok(*api);
+        }
+        let resp = client
+            .get(format!("http://{}/v1", addr))
+            .header("X-LOCKSERVER-SECRET", &self.secret)
+            .send()?;
+        let api = if resp.status() == StatusCode::NOT_FOUND {
+            Api::Legacy
+        } else {
+            check(resp)?;
+            Api::V1
+        };
+        self.apis.lock().unwrap().insert(addr.to_string(), api);
+        Ok(api)
+    }
+
+    /// Internal: the URL of the endpoint at `path` on `addr`, in the API it speaks.
+    fn url(&self, client: &HttpClient, addr: &str, path: &str) -> Result<Url, ClientError> {
+        let prefix = match self.api(client, addr)? {
+            Api::V1 => "/v1",
+            Api::Legacy => "",
+        };
+        Url::parse(&format!("http://{}{}{}", addr, prefix, path))
+            .map_err(|e| ClientError::InvalidRequest(e.to_string()))
+    }
+
+    /// Internal: the URL of the lock on `resource` at `addr`.
+    fn lock_url(
+        &self,
+        client: &HttpClient,
+        addr: &str,
+        resource: &str,
+    ) -> Result<Url, ClientError> {
+        let mut url = self.url(client, addr, "/locks")?;
+        url.path_segments_mut()
+            .map_err(|_| ClientError::InvalidRequest("Invalid server address".to_string()))?
+            .push(resource);
+        Ok(url)
+    }
+
+    /// Internal: the servers a lock is taken on.
+    fn servers(&self) -> &[String] {
+        if self.quorum.is_empty() {
+            std::slice::from_ref(&self.addr)
+        } else {
+            &self.quorum
+        }
+    }
+
+    /// Internal: run `op` against every server, succeeding if a majority succeeded.
+    ///
+    /// Otherwise returns the last error.
+    fn on_majority(
+        &self,
+        mut op: impl FnMut(&str) -> Result<(), ClientError>,
+    ) -> Result<(), ClientError> {
+        let servers = self.servers();
+        let mut ok = 0;
+        let mut last_err = None;
+        for addr in servers {
+            match op(addr) {
+                Ok(()) => ok += 1,
+                Err(e) => last_err = Some(e),
+            }
+        }
+        match last_err {
+            Some(e) if ok <= servers.len() / 2 => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    /// Open a session on the server that lives `ttl` seconds past each heartbeat.
+    ///
+    /// Locks acquired through [`Session::client`] are bound to the session, and are
+    /// released by the server if this process stops sending heartbeats, e.g. because
+    /// it crashed. Heartbeats are sent in the background every third of `ttl` until
+    /// the session is dropped, which closes it and releases its locks. A failed
+    /// heartbeat is retried until `ttl` has passed since the last one that succeeded;
+    /// the heartbeat stops early only once the server reports the session gone.
+    pub fn open_session(&self, ttl: u64) -> Result<Session, ClientError> {
+        #[derive(Serialize)]
+        struct CreateSessionRequest {
+            ttl: u64,
+        }
+        #[derive(Deserialize)]
+        struct SessionResponse {
+            session: String,
+        }
+        let client = HttpClient::new();
+        let resp = client
+            .post(self.url(&client, &self.addr, "/session")?)
+            .header("X-LOCKSERVER-SECRET", &self.secret)
+            .json(&CreateSessionRequest { ttl })
+            .send()?;
+        let id = read::<SessionResponse>(resp)?.session;
+        let mut client = self.clone();
+        client.session = Some(id);
+        let heartbeat = client.clone();
+        let ttl = Duration::from_secs(ttl);
+        let interval = (ttl / 3).max(Duration::from_millis(100));
+        let heartbeat = KeepAlive::spawn(
+            interval,
+            ttl,
+            move || heartbeat.session_request("/session/heartbeat"),
+            |e| matches!(e, ClientError::SessionNotFound),
+        );
+        Ok(Session {
+            client,
+            heartbeat: Some(heartbeat),
+        })
+    }
+
+    /// Internal: POST this client's session id to `path`.
+    fn session_request(&self, path: &str) -> Result<(), ClientError> {
+        #[derive(Serialize)]
+        struct SessionRequest<'a> {
+            session: &'a str,
+        }
+        let session = self
+            .session
+            .as_deref()
+            .ok_or_else(|| ClientError::InvalidRequest("Client has no session".to_string()))?;
+        let client = HttpClient::new();
+        let resp = client
+            .post(self.url(&client, &self.addr, path)?)
+            .header("X-LOCKSERVER-SECRET", &self.secret)
+            .json(&SessionRequest { session })
+            .send()?;
+        check(resp)
+    }
+
+    /// Look up who holds `resource`. Returns `None` if it is not locked.
+    pub fn inspect(&self, resource: &str) -> Result<Option<LockStatus>, ClientError> {
+        let client = HttpClient::new();
+        let resp = client
+            .get(self.lock_url(&client, &self.addr, resource)?)
+            .header("X-LOCKSERVER-SECRET", &self.secret)
+            .send()?;
+        match read(resp) {
+            Ok(status) => Ok(Some(status)),
+            Err(ClientError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// List locked resources whose names start with `prefix`, in name order.
+    ///
+    /// Returns up to `limit` locks (server default if `None`) after the resource named
+    /// `after`; pass the returned page's `next` as `after` to continue.
+    pub fn list_locks(
+        &self,
+        prefix: &str,
+        after: Option<&str>,
+        limit: Option<usize>,
+    ) -> Result<LockPage, ClientError> {
+        #[derive(Serialize)]
+        struct ListQuery<'a> {
+            prefix: &'a str,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            after: Option<&'a str>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            limit: Option<usize>,
+        }
+        let client = HttpClient::new();
+        let resp = client
+            .get(self.url(&client, &self.addr, "/locks")?)
+            .header("X-LOCKSERVER-SECRET", &self.secret)
+            .query(&ListQuery {
+                prefix,
+                after,
+                limit,
+            })
+            .send()?;
+        read(resp)
+    }
+
+    /// Take `permits` permits from the counting semaphore `resource`, which allows at
+    /// most `limit` permits to be held at once.
+    ///
+    /// In blocking mode, retries every 200ms until enough permits are free. Returns a
+    /// guard that gives the permits back when dropped.
+    pub fn acquire_permits<'a>(
+        &'a self,
+        resource: &'a str,
+        permits: u32,
+        limit: u32,
+        mode: LockMode,
+        expire: Option<u64>,
+    ) -> Result<SemaphoreGuard<'a>, ClientError> {
+        #[derive(Serialize)]
+        struct SemaphoreRequest<'a> {
+            resource: &'a str,
+            owner: &'a str,
+            permits: u32,
+            limit: u32,
+            expire: Option<u64>,
+        }
+        #[derive(Deserialize)]
+        struct AcquireResponse {
+            token: u64,
+        }
+        let client = HttpClient::new();
+        let url = self.url(&client, &self.addr, "/semaphore/acquire")?;
+        let req = SemaphoreRequest {
+            resource,
+            owner: &self.owner,
+            permits,
+            limit,
+            expire,
+        };
+        loop {
+            let resp = client
+                .post(url.clone())
+                .header("X-LOCKSERVER-SECRET", &self.secret)
+                .json(&req)
+                .send()?;
+            match read::<AcquireResponse>(resp) {
+                Ok(body) => {
+                    return Ok(SemaphoreGuard {
+                        client: self,
+                        resource,
+                        token: body.token,
+                    });
+                }
+                Err(ClientError::Locked) if mode == LockMode::Blocking => {
+                    thread::sleep(Duration::from_millis(200));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Return every permit this client's owner holds on the semaphore `resource`.
+    pub fn release_permits(&self, resource: &str) -> Result<(), ClientError> {
+        #[derive(Serialize)]
+        struct SemaphoreRequest<'a> {
+            resource: &'a str,
+            owner: &'a str,
+        }
+        let client = HttpClient::new();
+        let url = self.url(&client, &self.addr, "/semaphore/release")?;
+        let req = SemaphoreRequest {
             resource,
             owner: &self.owner,
         };
         let resp = client
-            .post(&url)
+            .post(url)
             .header("X-LOCKSERVER-SECRET", &self.secret)
             .json(&req)
-            .send();
-        match resp {
-            Ok(r) if r.status() == StatusCode::OK => Ok(()),
-            Ok(r) => Err(io::Error::other(format!("HTTP error: {}", r.status()))),
-            Err(e) => Err(io::Error::other(format!("Request error: {}", e))),
-        }
+            .send()?;
+        check(resp)
     }
 }
 
@@ -207,19 +885,104 @@ impl LockserverClient {
 /// ```
 #[macro_export]
 macro_rules! lock_scope {
+    // Several resources at once, all or none: `lock_scope!(&client, many: &["a", "b"], { .. })`
+    ($client:expr, many: $resources:expr, $block:block) => {{
+        let resources: &[&str] = $resources;
+        let tokens = $client
+            .acquire_many(resources, $crate::client::LockMode::Blocking, None)
+            .expect("Failed to acquire locks");
+        let _guard = $crate::MultiLockGuard::new($client, resources, tokens);
+        let result = (|| $block)();
+        result
+    }};
+    ($client:expr, many: $resources:expr, non_blocking, $block:block) => {{
+        let resources: &[&str] = $resources;
+        let tokens = $client
+            .acquire_many(resources, $crate::client::LockMode::NonBlocking, None)
+            .expect("Failed to acquire locks (non-blocking)");
+        let _guard = $crate::MultiLockGuard::new($client, resources, tokens);
+        let result = (|| $block)();
+        result
+    }};
     // Default: blocking
     ($client:expr, $resource:expr, $block:block) => {{
-        $client.acquire($resource).expect("Failed to acquire lock");
-        let _guard = $crate::LockGuard::new($client, $resource);
+        let token = $client.acquire($resource).expect("Failed to acquire lock");
+        let _guard = $crate::LockGuard::new($client, $resource, token);
         let result = (|| $block)();
         result
     }};
     // Non-blocking mode
     ($client:expr, $resource:expr, non_blocking, $block:block) => {{
-        $client
+        let token = $client
             .acquire_with_mode($resource, lockserver::client::LockMode::NonBlocking)
             .expect("Failed to acquire lock (non-blocking)");
-        let _guard = $crate::LockGuard::new($client, $resource);
+        let _guard = $crate::LockGuard::new($client, $resource, token);
+        let result = (|| $block)();
+        result
+    }};
+}
+
+/// Macro to hold a shared (read) lock for a code block.
+///
+/// Takes the same forms as [`lock_scope!`]; any number of readers may hold the lock at
+/// once, but not while a writer holds or is waiting for it.
+///
+/// See `tests/lock_scope_macro.rs` for a working example as a regular test.
+#[macro_export]
+macro_rules! read_scope {
+    ($client:expr, $resource:expr, $block:block) => {
+        $crate::__kind_scope!(
+            $client,
+            $resource,
+            $crate::LockKind::Shared,
+            $crate::client::LockMode::Blocking,
+            $block
+        )
+    };
+    ($client:expr, $resource:expr, non_blocking, $block:block) => {
+        $crate::__kind_scope!(
+            $client,
+            $resource,
+            $crate::LockKind::Shared,
+            $crate::client::LockMode::NonBlocking,
+            $block
+        )
+    };
+}
+
+/// Macro to hold an exclusive (write) lock for a code block.
+///
+/// Equivalent to [`lock_scope!`], named to pair with [`read_scope!`].
+#[macro_export]
+macro_rules! write_scope {
+    ($client:expr, $resource:expr, $block:block) => {
+        $crate::__kind_scope!(
+            $client,
+            $resource,
+            $crate::LockKind::Exclusive,
+            $crate::client::LockMode::Blocking,
+            $block
+        )
+    };
+    ($client:expr, $resource:expr, non_blocking, $block:block) => {
+        $crate::__kind_scope!(
+            $client,
+            $resource,
+            $crate::LockKind::Exclusive,
+            $crate::client::LockMode::NonBlocking,
+            $block
+        )
+    };
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __kind_scope {
+    ($client:expr, $resource:expr, $kind:expr, $mode:expr, $block:block) => {{
+        let token = $client
+            .acquire_with_kind($resource, $kind, $mode, None)
+            .expect("Failed to acquire lock");
+        let _guard = $crate::LockGuard::new($client, $resource, token);
         let result = (|| $block)();
         result
     }};
@@ -229,18 +992,315 @@ macro_rules! lock_scope {
 pub struct LockGuard<'a> {
     client: &'a LockserverClient,
     resource: &'a str,
+    token: u64,
+    keep_alive: Option<KeepAlive>,
+}
+
+/// Background renewal thread owned by a [`LockGuard`] or [`Session`].
+struct KeepAlive {
+    stop: mpsc::Sender<()>,
+    handle: thread::JoinHandle<()>,
+}
+
+impl KeepAlive {
+    /// Call `keep` every `interval` in a background thread until stopped.
+    ///
+    /// A failed call is retried with backoff for as long as what it keeps alive may
+    /// still be alive on the server: `ttl` from the start of the last call that
+    /// succeeded. The thread gives up early only on an error `gone` says is final.
+    fn spawn(
+        interval: Duration,
+        ttl: Duration,
+        mut keep: impl FnMut() -> Result<(), ClientError> + Send + 'static,
+        gone: fn(&ClientError) -> bool,
+    ) -> Self {
+        let (stop, rx) = mpsc::channel::<()>();
+        let handle = thread::spawn(move || {
+            let mut alive_until = Instant::now() + ttl;
+            let mut wait = interval;
+            let mut retry = KEEP_ALIVE_RETRY;
+            while let Err(mpsc::RecvTimeoutError::Timeout) = rx.recv_timeout(wait) {
+                let started = Instant::now();
+                match keep() {
+                    Ok(()) => {
+                        alive_until = started + ttl;
+                        wait = interval;
+                        retry = KEEP_ALIVE_RETRY;
+                    }
+                    Err(e) if gone(&e) => break,
+                    Err(_) => {
+                        let left = alive_until.saturating_duration_since(Instant::now());
+                        if left.is_zero() {
+                            break;
+                        }
+                        wait = retry.min(left);
+                        retry = (retry * 2).min(interval);
+                    }
+                }
+            }
+        });
+        Self { stop, handle }
+    }
 }
 
 impl<'a> LockGuard<'a> {
     /// Create a new lock guard. Usually not called directly; use the macro.
-    pub fn new(client: &'a LockserverClient, resource: &'a str) -> Self {
-        Self { client, resource }
+    ///
+    /// `token` is the fencing token returned when the lock was acquired.
+    pub fn new(client: &'a LockserverClient, resource: &'a str, token: u64) -> Self {
+        Self {
+            client,
+            resource,
+            token,
+            keep_alive: None,
+        }
+    }
+
+    /// The fencing token issued for the lock held by this guard.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+
+    /// Keep the lock alive in the background while the guard is held.
+    ///
+    /// Renews the lock to `expire` seconds every third of that interval until the guard
+    /// is dropped. A failed renewal is retried until the lock may have expired; renewal
+    /// stops early only once the server reports the lock lost (not found or not owned).
+    pub fn keep_alive(self, expire: u64) -> Self {
+        self.keep_alive_with_ttl(Duration::from_secs(expire))
+    }
+
+    /// [`LockGuard::keep_alive`] with the expiration given as a [`Duration`].
+    pub fn keep_alive_with_ttl(mut self, ttl: Duration) -> Self {
+        self.stop_keep_alive();
+        let client = self.client.clone();
+        let resource = self.resource.to_string();
+        let interval = (ttl / 3).max(Duration::from_millis(10));
+        self.keep_alive = Some(KeepAlive::spawn(
+            interval,
+            ttl,
+            move || client.renew_with_ttl(&resource, ttl),
+            |e| matches!(e, ClientError::NotOwner | ClientError::NotFound),
+        ));
+        self
+    }
+
+    fn stop_keep_alive(&mut self) {
+        if let Some(keep_alive) = self.keep_alive.take() {
+            let _ = keep_alive.stop.send(());
+            let _ = keep_alive.handle.join();
+        }
     }
 }
 
 impl<'a> Drop for LockGuard<'a> {
     /// Releases the lock when the guard is dropped.
     fn drop(&mut self) {
+        self.stop_keep_alive();
         let _ = self.client.release(self.resource);
     }
 }
+
+/// RAII guard for releasing a set of locks taken together when dropped.
+///
+/// Pairs with [`LockserverClient::acquire_many`].
+pub struct MultiLockGuard<'a> {
+    client: &'a LockserverClient,
+    resources: Vec<&'a str>,
+    tokens: Vec<u64>,
+}
+
+impl<'a> MultiLockGuard<'a> {
+    /// Create a guard for `resources`, holding the `tokens` returned by `acquire_many`.
+    pub fn new(client: &'a LockserverClient, resources: &[&'a str], tokens: Vec<u64>) -> Self {
+        Self {
+            client,
+            resources: resources.to_vec(),
+            tokens,
+        }
+    }
+
+    /// The fencing tokens for the held locks, in the order of the resources.
+    pub fn tokens(&self) -> &[u64] {
+        &self.tokens
+    }
+}
+
+impl<'a> Drop for MultiLockGuard<'a> {
+    /// Releases every lock when the guard is dropped.
+    fn drop(&mut self) {
+        for resource in &self.resources {
+            let _ = self.client.release(resource);
+        }
+    }
+}
+
+/// RAII guard for returning semaphore permits when dropped.
+///
+/// Returned by [`LockserverClient::acquire_permits`].
+pub struct SemaphoreGuard<'a> {
+    client: &'a LockserverClient,
+    resource: &'a str,
+    token: u64,
+}
+
+impl<'a> SemaphoreGuard<'a> {
+    /// The fencing token issued for the permits held by this guard.
+    pub fn token(&self) -> u64 {
+        self.token
+    }
+}
+
+impl<'a> Drop for SemaphoreGuard<'a> {
+    /// Returns the permits when the guard is dropped.
+    fn drop(&mut self) {
+        let _ = self.client.release_permits(self.resource);
+    }
+}
+
+/// A server-side session kept alive by a background heartbeat.
+///
+/// Returned by [`LockserverClient::open_session`]. Dropping it stops the heartbeat and
+/// closes the session, releasing every lock acquired through it.
+pub struct Session {
+    client: LockserverClient,
+    heartbeat: Option<KeepAlive>,
+}
+
+impl Session {
+    /// The session id assigned by the server.
+    pub fn id(&self) -> &str {
+        self.client.session.as_deref().unwrap_or_default()
+    }
+
+    /// A client whose locks are bound to this session.
+    pub fn client(&self) -> &LockserverClient {
+        &self.client
+    }
+}
+
+impl Drop for Session {
+    /// Stops the heartbeat and closes the session.
+    fn drop(&mut self) {
+        if let Some(heartbeat) = self.heartbeat.take() {
+            let _ = heartbeat.stop.send(());
+            let _ = heartbeat.handle.join();
+        }
+        let _ = self.client.session_request("/session/close");
+    }
+}
+
+/// Internal: the body of a successful response, or the error the server reported.
+fn read<T: DeserializeOwned>(resp: Response) -> Result<T, ClientError> {
+    check_status(resp)?
+        .json()
+        .map_err(|e| ClientError::InvalidResponse(e.to_string()))
+}
+
+/// Internal: succeed if `resp` reports success, ignoring its body.
+fn check(resp: Response) -> Result<(), ClientError> {
+    check_status(resp).map(drop)
+}
+
+/// Internal: the fencing token in a successful acquire response.
+///
+/// Servers too old to issue fencing tokens answer a plain `OK`, read as token 0.
+fn read_token(resp: Response) -> Result<u64, ClientError> {
+    #[derive(Deserialize)]
+    struct AcquireResponse {
+        token: u64,
+    }
+    let body = check_status(resp)?.text()?;
+    if body.trim() == "OK" {
+        return Ok(0);
+    }
+    serde_json::from_str::<AcquireResponse>(&body)
+        .map(|body| body.token)
+        .map_err(|e| ClientError::InvalidResponse(e.to_string()))
+}
+
+/// Internal: `resp` if it reports success, otherwise the error in its body.
+fn check_status(resp: Response) -> Result<Response, ClientError> {
+    #[derive(Deserialize)]
+    struct ErrorResponse {
+        code: String,
+        message: String,
+    }
+    let status = resp.status();
+    if status.is_success() {
+        return Ok(resp);
+    }
+    let text = resp.text()?;
+    let Ok(body) = serde_json::from_str::<ErrorResponse>(&text) else {
+        return Err(legacy_error(status, &text));
+    };
+    Err(match body.code.as_str() {
+        // A timed out wait means the lock is still held.
+        "already_locked" | "timeout" => ClientError::Locked,
+        "not_owner" => ClientError::NotOwner,
+        "not_found" => ClientError::NotFound,
+        "deadlock" => ClientError::Deadlock,
+        "session_not_found" => ClientError::SessionNotFound,
+        "invalid_request" => ClientError::InvalidRequest(body.message),
+        "unauthorized" => ClientError::Unauthorized(body.message),
+        "forbidden" => ClientError::Forbidden(body.message),
+        _ => ClientError::Server {
+            status: status.as_u16(),
+            code: body.code,
+            message: body.message,
+        },
+    })
+}
+
+/// Internal: the error in a plain-text body, as sent by servers that predate `/v1`:
+/// `ERR` followed by the message of a [`LockError`], or just a message for a bad secret.
+fn legacy_error(status: StatusCode, body: &str) -> ClientError {
+    let message = body.strip_prefix("ERR ").unwrap_or(body).trim();
+    let is = |e: LockError| message == e.to_string();
+    if message.is_empty() {
+        ClientError::InvalidResponse(format!("HTTP error: {}", status))
+    } else if status == StatusCode::UNAUTHORIZED {
+        ClientError::Unauthorized(message.to_string())
+    } else if is(LockError::AlreadyLocked) || is(LockError::Timeout) {
+        ClientError::Locked
+    } else if is(LockError::NotOwner) {
+        ClientError::NotOwner
+    } else if is(LockError::NotFound) {
+        ClientError::NotFound
+    } else {
+        ClientError::Server {
+            status: status.as_u16(),
+            code: String::new(),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// Internal: a fresh random ticket, identifying one blocking acquire across its polls.
+fn new_ticket() -> String {
+    format!("{:016x}", RandomState::new().build_hasher().finish())
+}
+
+/// `duration` in whole milliseconds, rounded up so a short expiration never becomes zero.
+///
+/// Durations too long to represent saturate; the server rejects them as invalid.
+fn duration_ms(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos().div_ceil(1_000_000)).unwrap_or(u64::MAX)
+}
+
+/// Internal: `ttl` as the `(expire, expire_ms)` pair `api` understands.
+///
+/// Servers that predate `/v1` only take `expire` in seconds, so the ttl is rounded up.
+fn expiration(api: Api, ttl: Option<Duration>) -> (Option<u64>, Option<u64>) {
+    match (api, ttl) {
+        (_, None) => (None, None),
+        (Api::V1, Some(ttl)) => (None, Some(duration_ms(ttl))),
+        (Api::Legacy, Some(ttl)) => (
+            Some(
+                ttl.as_secs()
+                    .saturating_add(u64::from(ttl.subsec_nanos() > 0)),
+            ),
+            None,
+        ),
+    }
+}
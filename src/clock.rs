@@ -0,0 +1,86 @@
+//! # clock
+//!
+//! Time sources for lock expiry.
+//!
+//! Lease deadlines are measured on a [`Clock`]'s monotonic time, so stepping the
+//! system clock (e.g. by NTP) neither keeps locks alive nor releases them early. Its
+//! wall-clock time is only used to report and persist deadlines.
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// A source of monotonic and wall-clock time.
+pub trait Clock: Send + Sync + fmt::Debug {
+    /// Monotonic time. Lease deadlines are measured against this.
+    fn now(&self) -> Instant;
+
+    /// Wall-clock time, used to report and persist deadlines.
+    fn wall(&self) -> SystemTime;
+}
+
+/// The operating system's clocks.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that only moves when told to, for testing expiry without waiting.
+///
+/// Pair it with [`LockManager::with_manual_expiry`](crate::LockManager::with_manual_expiry)
+/// and call [`LockManager::run_expiry`](crate::LockManager::run_expiry) after each
+/// [`ManualClock::advance`].
+#[derive(Debug)]
+pub struct ManualClock {
+    start: Instant,
+    wall_start: Mutex<SystemTime>, // wall-clock time when `elapsed` was zero
+    elapsed: Mutex<Duration>,
+}
+
+impl ManualClock {
+    /// A clock stopped at the current time.
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            wall_start: Mutex::new(SystemTime::now()),
+            elapsed: Mutex::new(Duration::ZERO),
+        }
+    }
+
+    /// Move both the monotonic and the wall-clock time forward by `by`.
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    /// Step the wall-clock time to `wall` without moving the monotonic time, as NTP
+    /// might.
+    pub fn set_wall(&self, wall: SystemTime) {
+        let elapsed = *self.elapsed.lock().unwrap();
+        *self.wall_start.lock().unwrap() = wall - elapsed;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> Instant {
+        self.start + *self.elapsed.lock().unwrap()
+    }
+
+    fn wall(&self) -> SystemTime {
+        let elapsed = *self.elapsed.lock().unwrap();
+        *self.wall_start.lock().unwrap() + elapsed
+    }
+}
@@ -7,6 +7,11 @@
 //! - HTTP API only (no TCP service)
 //! - Client library with ergonomic macros (`lock_scope!`)
 //! - Blocking and non-blocking lock acquisition
+//! - Shared (read) and exclusive (write) locks
+//! - Counting semaphores with a configurable permit limit
+//! - Lock expiry on a monotonic, injectable [`Clock`]
+//! - Optional Raft-replicated cluster mode ([`raft`])
+//! - Per-client API tokens limited to resource prefixes and operations ([`auth`])
 //!
 //! ## Example
 //! ```rust
@@ -18,9 +23,19 @@
 //! });
 //! ```
 
+pub mod audit;
+pub mod auth;
+pub mod clock;
 mod lock_manager;
+mod persistence;
+pub mod raft;
 
 pub mod client;
-pub use client::{LockGuard, LockserverClient};
+pub use client::{
+    ClientError, LockGuard, LockserverClient, MultiLockGuard, QuorumGrant, SemaphoreGuard, Session,
+};
 
-pub use crate::lock_manager::{LockError, LockManager};
+pub use crate::clock::{Clock, ManualClock, SystemClock};
+pub use crate::lock_manager::{
+    HolderStatus, LockError, LockKind, LockManager, LockPage, LockStatus, LongPoll,
+};
@@ -4,10 +4,21 @@
 //!
 //! This module provides the in-memory lock manager used by the server.
 
-use std::collections::{HashMap, HashSet};
-use std::sync::{Arc, Mutex};
+use crate::clock::{Clock, SystemClock};
+use crate::persistence::{Event, Snapshot, Wal};
+use crate::raft::Command;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
 use std::thread;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Notify;
+use utoipa::ToSchema;
 
 /// Errors returned by the lock manager.
 #[derive(Debug, thiserror::Error)]
@@ -16,133 +27,2540 @@ pub enum LockError {
     AlreadyLocked,
     #[error("Resource not found")]
     NotFound,
+    #[error("Resource is held by another owner")]
+    NotOwner,
+    #[error("Timed out waiting for lock")]
+    Timeout,
+    #[error("Waiting for the lock would deadlock")]
+    Deadlock,
+    #[error("Session not found or expired")]
+    SessionNotFound,
+    #[error("Invalid request: {0}")]
+    InvalidRequest(String),
     #[error("Internal error: {0}")]
     Internal(String),
 }
 
+impl LockError {
+    /// A stable, machine-readable name for the error, such as `"not_owner"`.
+    ///
+    /// The HTTP API reports errors under these names.
+    pub fn code(&self) -> &'static str {
+        match self {
+            LockError::AlreadyLocked => "already_locked",
+            LockError::NotFound => "not_found",
+            LockError::NotOwner => "not_owner",
+            LockError::Timeout => "timeout",
+            LockError::Deadlock => "deadlock",
+            LockError::SessionNotFound => "session_not_found",
+            LockError::InvalidRequest(_) => "invalid_request",
+            LockError::Internal(_) => "internal",
+        }
+    }
+}
+
+/// Kind of hold taken on a resource.
+///
+/// Any number of owners may hold a resource `Shared` at once; an `Exclusive` hold
+/// excludes every other holder.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LockKind {
+    /// Read lock, compatible with other shared holds.
+    Shared,
+    /// Write lock, held by a single owner.
+    #[default]
+    Exclusive,
+}
+
+/// A snapshot of who holds a lock, returned by [`LockManager::inspect`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct LockStatus {
+    pub resource: String,
+    pub kind: LockKind,
+    /// Current holders, ordered by owner. An exclusive lock has exactly one.
+    pub holders: Vec<HolderStatus>,
+    /// Number of acquirers queued for the resource.
+    pub waiters: usize,
+}
+
+/// One owner's hold on a lock. Times are unix timestamps in seconds.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct HolderStatus {
+    pub owner: String,
+    pub acquired_at: u64,
+    /// How many nested holds the owner has taken; 1 unless acquired reentrantly.
+    pub holds: u32,
+    /// When the hold expires, if it has an expiration.
+    pub expires_at: Option<u64>,
+    /// Seconds until the hold expires, rounded up, if it has an expiration.
+    pub ttl: Option<u64>,
+    /// Milliseconds until the hold expires, if it has an expiration.
+    pub ttl_ms: Option<u64>,
+}
+
+/// One page of locks, returned by [`LockManager::list`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+pub struct LockPage {
+    pub locks: Vec<LockStatus>,
+    /// Pass as `after` to fetch the next page; `None` on the last page.
+    pub next: Option<String>,
+}
+
 /// In-memory lock manager for distributed locks.
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct LockInfo {
+    kind: LockKind,
+    holders: HashMap<String, Holder>, // owner -> Holder
+}
+
+/// One owner's hold on a lock.
+///
+/// `acquired_at` is a unix timestamp in milliseconds, kept for reporting. Expiration
+/// times here and in the other state below are on the manager's [`Timeline`], except
+/// in a [`Snapshot`], where they are unix timestamps in milliseconds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct Holder {
+    acquired_at: u64,
+    expire_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session: Option<String>, // released when this session expires
+    #[serde(default = "one_hold")]
+    count: u32, // nested holds; the lock is freed when this drops to zero
+}
+
+fn one_hold() -> u32 {
+    1
+}
+
+/// A client session kept alive by heartbeats.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SessionInfo {
+    ttl: u64, // seconds each heartbeat extends the session by
+    expire_at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SemaphoreInfo {
+    limit: u32,
+    holders: HashMap<String, (u32, Option<u64>)>, // owner -> (permits, expire_at)
+}
+
+/// A hold tracked in the expiry timeslots.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Hold {
+    Lock {
+        resource: String,
+        owner: String,
+    },
+    Permits {
+        resource: String,
+        owner: String,
+    },
+    Session {
+        session: String,
+    },
+    /// A waiter parked between long-polls, see [`LockManager::acquire_long_poll`].
+    Waiter {
+        resource: String,
+        id: u64,
+    },
+}
+
+impl Hold {
+    fn lock(resource: &str, owner: &str) -> Self {
+        Hold::Lock {
+            resource: resource.to_string(),
+            owner: owner.to_string(),
+        }
+    }
+
+    fn permits(resource: &str, owner: &str) -> Self {
+        Hold::Permits {
+            resource: resource.to_string(),
+            owner: owner.to_string(),
+        }
+    }
+
+    fn session(session: &str) -> Self {
+        Hold::Session {
+            session: session.to_string(),
+        }
+    }
+
+    fn waiter(resource: &str, id: u64) -> Self {
+        Hold::Waiter {
+            resource: resource.to_string(),
+            id,
+        }
+    }
+}
+
+/// An acquirer queued for a resource.
 #[derive(Debug)]
-struct LockInfo {
+struct Waiter {
+    id: u64,
     owner: String,
-    expire_at: Option<u64>, // unix timestamp in seconds
+    kind: LockKind,
+    ticket: Option<String>, // presented again by each of the waiter's long-polls
+    parked_until: Option<u64>, // between long-polls: when the waiter gives up its place
+}
+
+impl Waiter {
+    /// Whether this waiter has to wait for `ahead`, queued in front of it.
+    fn waits_for(&self, ahead: &Waiter) -> bool {
+        !(self.kind == LockKind::Shared && ahead.kind == LockKind::Shared)
+    }
+}
+
+/// Expiry buckets: expire_at -> set of holds due then, earliest first.
+type Timeslots = BTreeMap<u64, HashSet<Hold>>;
+
+/// How long a long-polling waiter keeps its place in the queue between polls, in
+/// milliseconds.
+const TICKET_GRACE_MS: u64 = 10_000;
+
+/// Number of shards in the lock table. Each resource belongs to one shard, picked by
+/// hashing its name, and operations on resources in different shards never contend.
+const SHARDS: usize = 64;
+
+/// Internal: one shard of the lock table.
+#[derive(Debug, Default)]
+struct Shard {
+    state: Mutex<ShardState>,
+    released: Condvar, // signalled (with `state`) whenever a lock in this shard is freed
+    freed: Notify,     // notified alongside `released`, for async waiters
+}
+
+impl Shard {
+    /// Wake everyone waiting, blocking or async, for a lock in this shard to be freed.
+    fn wake(&self) {
+        self.released.notify_all();
+        self.freed.notify_waiters();
+    }
+}
+
+/// Internal: the locks, wait queues and semaphores of the resources in one shard, and
+/// the expiry timeslots of their holds.
+#[derive(Debug, Default)]
+struct ShardState {
+    locks: HashMap<String, LockInfo>,           // resource -> LockInfo
+    waiters: HashMap<String, VecDeque<Waiter>>, // resource -> FIFO of waiters
+    semaphores: HashMap<String, SemaphoreInfo>, // resource -> SemaphoreInfo
+    timeslots: Timeslots,                       // expire_at -> lock and permit holds
 }
 
+/// Internal: open client sessions and their expiry timeslots.
 #[derive(Debug, Default)]
+struct Sessions {
+    info: HashMap<String, SessionInfo>, // session id -> SessionInfo
+    timeslots: Timeslots,               // expire_at -> session holds
+}
+
+/// Internal: wakes the expiry worker when a deadline earlier than the one it sleeps
+/// until is scheduled.
+///
+/// `wake_at` is read without taking `lock`, so scheduling a deadline the worker will
+/// wake up for anyway costs no shared lock. The worker sets it to `u64::MAX` while it
+/// scans the shards, so any deadline scheduled meanwhile is reported to it.
+#[derive(Debug, Default)]
+struct Timer {
+    wake_at: AtomicU64, // when the worker next wakes up, on the manager's timeline
+    lock: Mutex<()>,
+    wakeup: Condvar, // signalled (with `lock`) when `wake_at` is brought forward
+    notify: Notify,  // notified alongside `wakeup`, for an expiry task
+}
+
+impl Timer {
+    /// Make sure the expiry worker wakes up no later than `at`.
+    fn wake_by(&self, at: u64) {
+        if at < self.wake_at.load(Ordering::SeqCst) {
+            let _lock = self.lock.lock().unwrap();
+            self.wake_at.fetch_min(at, Ordering::SeqCst);
+            self.wakeup.notify_all();
+            self.notify.notify_waiters();
+        }
+    }
+}
+
+/// Internal: the background job that releases holds as they expire, run either on a
+/// dedicated thread or as a tokio task.
+#[derive(Debug)]
+enum Worker {
+    Thread(thread::JoinHandle<()>),
+    Task(tokio::task::JoinHandle<()>),
+}
+
+/// Internal: the state the expiry worker shares with its manager.
+struct Expiry {
+    shards: Arc<[Shard]>,
+    sessions: Arc<Mutex<Sessions>>,
+    timer: Arc<Timer>,
+    next_token: Arc<AtomicU64>,
+    wal: Option<Arc<Mutex<Wal>>>,
+    timeline: Timeline,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Expiry {
+    /// Release every hold that is due, and write a snapshot if one is due.
+    fn run(&self) {
+        // Until the next wakeup is planned, have every new deadline reported.
+        self.timer.wake_at.store(u64::MAX, Ordering::SeqCst);
+        let now = self.timeline.now();
+        LockManager::expire_due(&self.shards, &self.sessions, self.wal.as_deref(), now);
+        if let Some(wal) = &self.wal
+            && wal.lock().unwrap().needs_snapshot()
+        {
+            let snapshot = LockManager::snapshot_of(
+                &self.timeline,
+                &self.next_token,
+                &lock_all(&self.shards),
+                &self.sessions.lock().unwrap(),
+            );
+            // On failure the log keeps growing and we try again next tick.
+            let _ = wal.lock().unwrap().write_snapshot(snapshot);
+        }
+    }
+
+    /// Plan the next wakeup: at the earliest deadline, or within a second so snapshots
+    /// are checked for. Returns how long to sleep, or `None` to run again straight
+    /// away. Call with `timer.lock` held.
+    fn plan(&self, next_deadline: Option<u64>) -> Option<Duration> {
+        let now = self.timeline.now();
+        let wake_at = next_deadline
+            .unwrap_or(u64::MAX)
+            .min(now + 1000)
+            .min(self.timer.wake_at.load(Ordering::SeqCst));
+        self.timer.wake_at.store(wake_at, Ordering::SeqCst);
+        (wake_at > now && !self.stopping.load(Ordering::SeqCst))
+            .then(|| Duration::from_millis(wake_at - now))
+    }
+}
+
+/// Internal: the outcome of asking for a lock that may have to be waited for.
+enum Admission {
+    Granted(u64), // fencing token
+    Queued(u64),  // waiter id
+}
+
+/// Internal: a waiter's place in a resource's queue, given up when dropped unless the
+/// waiter was granted the lock. Lets an async acquire be cancelled.
+///
+/// A waiter with a ticket is parked instead, keeping its place for the next long-poll.
+struct Queued<'a> {
+    manager: &'a LockManager,
+    resource: &'a str,
+    id: u64,
+    granted: bool,
+    ticketed: bool,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        if !self.granted
+            && let Ok(mut state) = self.manager.lock(self.resource)
+        {
+            if self.ticketed {
+                self.manager.park(&mut state, self.resource, self.id);
+                return;
+            }
+            state.dequeue(self.resource, self.id);
+            drop(state);
+            // We may have been at the head of the queue; let the next waiter look.
+            self.manager.shard(self.resource).wake();
+        }
+    }
+}
+
+/// One call of a long-polling async acquire, see [`LockManager::acquire_long_poll`].
+#[derive(Debug, Clone, Copy)]
+pub struct LongPoll<'a> {
+    /// Chosen by the caller and presented by each of its polls for the lock.
+    pub ticket: &'a str,
+    /// How long this poll waits.
+    pub timeout: Duration,
+}
+
+/// Internal: the manager's clock. Deadlines are kept as milliseconds on the clock's
+/// monotonic time since `epoch`, and converted to wall-clock time only to be reported
+/// or persisted.
+///
+/// A cluster node's timeline follows the leader's, see [`Timeline::follow`], and never
+/// falls behind the entries it applies, see [`Timeline::catch_up`].
+#[derive(Debug, Clone)]
+struct Timeline {
+    clock: Arc<dyn Clock>,
+    epoch: Instant,
+    offset: Arc<AtomicI64>, // milliseconds added to the clock's time; negative once moved back
+}
+
+impl Default for Timeline {
+    fn default() -> Self {
+        Self::new(Arc::new(SystemClock))
+    }
+}
+
+impl Timeline {
+    fn new(clock: Arc<dyn Clock>) -> Self {
+        let epoch = clock.now();
+        Self {
+            clock,
+            epoch,
+            offset: Arc::new(AtomicI64::new(0)),
+        }
+    }
+
+    /// Milliseconds since `epoch`, plus however far the timeline was moved.
+    fn now(&self) -> u64 {
+        self.elapsed()
+            .saturating_add_signed(self.offset.load(Ordering::SeqCst))
+    }
+
+    /// Internal: milliseconds the clock has measured since `epoch`.
+    fn elapsed(&self) -> u64 {
+        self.clock
+            .now()
+            .saturating_duration_since(self.epoch)
+            .as_millis() as u64
+    }
+
+    /// Move the timeline forward so that it reads at least `at`. It never moves back.
+    fn catch_up(&self, at: u64) {
+        self.offset.fetch_max(self.offset_to(at), Ordering::SeqCst);
+    }
+
+    /// Move the timeline so that it reads `at`, back if it is ahead.
+    fn follow(&self, at: u64) {
+        self.offset.store(self.offset_to(at), Ordering::SeqCst);
+    }
+
+    /// Internal: the offset at which the timeline reads `at`.
+    fn offset_to(&self, at: u64) -> i64 {
+        let at = i64::try_from(at).unwrap_or(i64::MAX);
+        at.saturating_sub(i64::try_from(self.elapsed()).unwrap_or(i64::MAX))
+    }
+
+    /// The clock's wall-clock time as a unix timestamp in milliseconds.
+    fn wall_now(&self) -> u64 {
+        unix_ms(self.clock.wall())
+    }
+
+    /// The unix timestamp in milliseconds of time `at`.
+    fn to_wall(&self, at: u64) -> u64 {
+        self.wall_now()
+            .saturating_add(at)
+            .saturating_sub(self.now())
+    }
+
+    /// The time of the unix timestamp `wall`, in milliseconds. Times before `epoch`
+    /// become `epoch`, which has passed.
+    fn time_of_wall(&self, wall: u64) -> u64 {
+        self.now()
+            .saturating_add(wall)
+            .saturating_sub(self.wall_now())
+    }
+}
+
+/// In-memory lock manager for distributed locks.
+///
+/// The lock table is split into shards, each behind its own mutex, so requests for
+/// unrelated resources proceed in parallel. Only operations that span resources, such
+/// as [`LockManager::acquire_many`], listing, and the deadlock check made before
+/// queueing for a held lock, take more than one shard.
+#[derive(Debug)]
 pub struct LockManager {
-    locks: Arc<Mutex<HashMap<String, LockInfo>>>, // resource -> LockInfo
-    timeslots: Arc<Mutex<HashMap<u64, HashSet<String>>>>, // expire_at -> set of resources
+    shards: Arc<[Shard]>, // the lock table; a resource's shard is picked by `hasher`
+    hasher: RandomState,
+    sessions: Arc<Mutex<Sessions>>,
+    timer: Arc<Timer>,
+    next_token: Arc<AtomicU64>, // last fencing token handed out
+    // Lock order: shards in index order before `sessions` before `wal` before `timer`.
+    next_waiter: AtomicU64,
+    wal: Option<Arc<Mutex<Wal>>>, // write-ahead log, if state is persisted
+    timeline: Timeline,
+    worker: Mutex<Option<Worker>>, // the expiry worker, until shut down
+    stopping: Arc<AtomicBool>,     // tells the expiry worker to exit
+}
+
+impl Default for LockManager {
+    fn default() -> Self {
+        Self::empty()
+    }
 }
 
 impl LockManager {
     /// Create a new lock manager.
     pub fn new() -> Self {
-        let manager = Self {
-            locks: Arc::new(Mutex::new(HashMap::new())),
-            timeslots: Arc::new(Mutex::new(HashMap::new())),
-        };
+        Self::with_clock(Arc::new(SystemClock))
+    }
+
+    /// Create a lock manager that measures time with `clock`.
+    pub fn with_clock(clock: Arc<dyn Clock>) -> Self {
+        let manager = Self::with_manual_expiry(clock);
         manager.spawn_expiry_worker();
         manager
     }
 
+    /// Create a lock manager whose expiry runs as a task on the current tokio runtime,
+    /// rather than on a thread of its own.
+    ///
+    /// Must be called from within a tokio runtime.
+    pub fn with_async_expiry() -> Self {
+        let manager = Self::empty();
+        manager.spawn_expiry_task();
+        manager
+    }
+
+    /// Create a lock manager that measures time with `clock` and has no background
+    /// expiry worker: expired holds are only released by [`Self::run_expiry`].
+    ///
+    /// With a [`ManualClock`](crate::ManualClock) this makes expiry fully
+    /// deterministic, for tests.
+    pub fn with_manual_expiry(clock: Arc<dyn Clock>) -> Self {
+        let mut manager = Self::empty();
+        manager.timeline = Timeline::new(clock);
+        manager
+    }
+
+    /// Create a lock manager whose state is persisted in `dir`.
+    ///
+    /// Any state saved there by a previous run is restored first. Expiration times are
+    /// saved as wall-clock timestamps, so locks that expired while the server was down
+    /// are dropped and the rest keep their original deadlines.
+    ///
+    /// Every change is appended to a single log, so changes to a persisted manager are
+    /// written one at a time even when they touch different shards.
+    pub fn with_data_dir(dir: impl AsRef<Path>) -> io::Result<Self> {
+        let (mut wal, snapshot, events) = Wal::open(dir.as_ref())?;
+        let mut manager = Self::empty();
+        manager.restore(snapshot, events);
+        // Compact right away so the log only holds changes made from now on.
+        wal.write_snapshot(manager.snapshot())?;
+        manager.wal = Some(Arc::new(Mutex::new(wal)));
+        manager.spawn_expiry_worker();
+        Ok(manager)
+    }
+
+    /// Internal: a manager for a cluster node, whose state only changes through
+    /// [`LockManager::apply_at`]. Expiry is driven by the leader, so no worker is spawned.
+    pub(crate) fn replica(clock: Arc<dyn Clock>) -> Self {
+        Self::with_manual_expiry(clock)
+    }
+
+    /// Internal: a manager with no state and no expiry worker.
+    fn empty() -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Shard::default()).collect(),
+            hasher: RandomState::new(),
+            sessions: Arc::new(Mutex::new(Sessions::default())),
+            timer: Arc::new(Timer::default()),
+            next_token: Arc::new(AtomicU64::new(0)),
+            next_waiter: AtomicU64::new(0),
+            wal: None,
+            timeline: Timeline::default(),
+            worker: Mutex::new(None),
+            stopping: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
     /// Try to acquire a lock for a resource and owner, with optional expiration in seconds.
     /// expire_secs: None = no expiration, Some(n) = expire after n seconds
+    ///
+    /// On success returns a fencing token. Tokens are strictly increasing across all
+    /// grants, so storage guarded by the lock can reject writes carrying a token lower
+    /// than the highest one it has already seen.
     pub fn acquire(
         &self,
         resource: &str,
         owner: &str,
         expire_secs: Option<u64>,
-    ) -> Result<(), LockError> {
-        let mut locks = self
-            .locks
-            .lock()
-            .map_err(|e| LockError::Internal(e.to_string()))?;
-        if locks.contains_key(resource) {
+    ) -> Result<u64, LockError> {
+        self.acquire_with_kind(resource, owner, LockKind::Exclusive, expire_secs)
+    }
+
+    /// [`Self::acquire`] with the expiration given as a [`Duration`], for expirations
+    /// shorter than a second or not a whole number of seconds.
+    ///
+    /// Expired locks are released within a few milliseconds of their deadline.
+    pub fn acquire_with_ttl(
+        &self,
+        resource: &str,
+        owner: &str,
+        ttl: Option<Duration>,
+    ) -> Result<u64, LockError> {
+        self.acquire_with_session(resource, owner, LockKind::Exclusive, ttl, None)
+    }
+
+    /// Try to acquire a shared or exclusive lock for a resource and owner.
+    ///
+    /// A shared lock is granted alongside other shared holders, but not while anyone
+    /// is queued for the resource, so a waiting writer is not starved by new readers.
+    pub fn acquire_with_kind(
+        &self,
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+        expire_secs: Option<u64>,
+    ) -> Result<u64, LockError> {
+        let ttl = expire_secs.map(Duration::from_secs);
+        self.acquire_with_session(resource, owner, kind, ttl, None)
+    }
+
+    /// Try to acquire a lock bound to `session`, if given, expiring after `ttl` if set.
+    ///
+    /// A lock bound to a session is released when the session is closed or stops
+    /// sending heartbeats, in addition to its own expiration. Returns
+    /// `LockError::SessionNotFound` if the session does not exist.
+    pub fn acquire_with_session(
+        &self,
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+        ttl: Option<Duration>,
+        session: Option<&str>,
+    ) -> Result<u64, LockError> {
+        let mut state = self.lock(resource)?;
+        // Queued waiters go first; a new arrival must not overtake them.
+        if !state.is_compatible(resource, owner, kind) || state.has_waiters(resource) {
             return Err(LockError::AlreadyLocked);
         }
-        let expire_at = expire_secs.map(|secs| {
-            let now = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .unwrap()
-                .as_secs();
-            now + secs
-        });
-        locks.insert(
-            resource.to_string(),
-            LockInfo {
-                owner: owner.to_string(),
-                expire_at,
-            },
-        );
-        drop(locks);
-        if let Some(expire_at) = expire_at {
-            let mut slots = self.timeslots.lock().unwrap();
-            slots
-                .entry(expire_at)
-                .or_default()
-                .insert(resource.to_string());
+        self.grant(&mut state, resource, owner, kind, ttl, session)
+    }
+
+    /// Like [`Self::acquire_with_session`], but if `owner` already holds `resource`
+    /// this takes another nested hold instead of failing with `AlreadyLocked`.
+    ///
+    /// Each hold needs its own [`Self::release`]; the lock is freed once the last one
+    /// is released. A nested hold keeps the original expiration and session, and a
+    /// shared holder cannot nest an exclusive hold. Returns a new fencing token.
+    pub fn acquire_reentrant(
+        &self,
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+        ttl: Option<Duration>,
+        session: Option<&str>,
+    ) -> Result<u64, LockError> {
+        let mut state = self.lock(resource)?;
+        if let Some(result) = self.reenter(&mut state, resource, owner, kind) {
+            return result;
         }
-        Ok(())
+        if !state.is_compatible(resource, owner, kind) || state.has_waiters(resource) {
+            return Err(LockError::AlreadyLocked);
+        }
+        self.grant(&mut state, resource, owner, kind, ttl, session)
     }
 
-    /// Release a lock for a resource and owner.
-    pub fn release(&self, resource: &str, owner: &str) -> Result<(), LockError> {
-        let mut locks = self
-            .locks
-            .lock()
+    /// Acquire exclusive locks on all of `resources` at once, or on none of them.
+    ///
+    /// Every lock shares the same expiration. Taking a set of locks in one step avoids
+    /// the deadlocks and partial holds of acquiring them one at a time. Returns one
+    /// fencing token per resource, in order; the tokens are consecutive.
+    pub fn acquire_many(
+        &self,
+        resources: &[&str],
+        owner: &str,
+        expire_secs: Option<u64>,
+    ) -> Result<Vec<u64>, LockError> {
+        let ttl = expire_secs.map(Duration::from_secs);
+        self.acquire_many_with_session(resources, owner, ttl, None)
+    }
+
+    /// [`Self::acquire_many`] with the locks bound to `session`, if given, and
+    /// expiring after `ttl` if set.
+    pub fn acquire_many_with_session(
+        &self,
+        resources: &[&str],
+        owner: &str,
+        ttl: Option<Duration>,
+        session: Option<&str>,
+    ) -> Result<Vec<u64>, LockError> {
+        let holder = Holder {
+            acquired_at: self.timeline.wall_now(),
+            expire_at: ttl
+                .map(|ttl| deadline(self.timeline.now(), ttl))
+                .transpose()?,
+            session: session.map(str::to_string),
+            count: 1,
+        };
+        let first = self.acquire_many_at(resources, owner, holder)?;
+        Ok((first..first + resources.len() as u64).collect())
+    }
+
+    /// Internal: [`Self::acquire_many`] with the holds' timestamps given. Returns the
+    /// first of the consecutive tokens issued.
+    fn acquire_many_at<S: AsRef<str>>(
+        &self,
+        resources: &[S],
+        owner: &str,
+        holder: Holder,
+    ) -> Result<u64, LockError> {
+        let names: HashSet<&str> = resources.iter().map(|r| r.as_ref()).collect();
+        if resources.is_empty() || names.len() != resources.len() {
+            return Err(LockError::InvalidRequest(
+                "resources must be non-empty and distinct".to_string(),
+            ));
+        }
+        let mut indices: Vec<usize> = names.iter().map(|r| self.shard_of(r)).collect();
+        indices.sort();
+        indices.dedup();
+        // Taken in index order, like every other multi-shard operation.
+        let mut states = indices
+            .iter()
+            .map(|&i| self.shards[i].state.lock())
+            .collect::<Result<Vec<_>, _>>()
             .map_err(|e| LockError::Internal(e.to_string()))?;
-        match locks.get(resource) {
-            Some(info) if info.owner == owner => {
-                // Remove from timeslot if present
-                if let Some(expire_at) = info.expire_at {
-                    let mut slots = self.timeslots.lock().unwrap();
-                    if let Some(set) = slots.get_mut(&expire_at) {
-                        set.remove(resource);
-                        if set.is_empty() {
-                            slots.remove(&expire_at);
-                        }
-                    }
+        let slot = |resource: &str| indices.binary_search(&self.shard_of(resource)).unwrap();
+        let free = resources.iter().all(|r| {
+            let state = &states[slot(r.as_ref())];
+            state.is_compatible(r.as_ref(), owner, LockKind::Exclusive)
+                && !state.has_waiters(r.as_ref())
+        });
+        if !free {
+            return Err(LockError::AlreadyLocked);
+        }
+        if let Some(session) = &holder.session
+            && !self.sessions.lock().unwrap().info.contains_key(session)
+        {
+            return Err(LockError::SessionNotFound);
+        }
+        let n = resources.len() as u64;
+        let first = self.next_token.fetch_add(n, Ordering::SeqCst) + 1;
+        // One record for the whole batch, so a crash cannot leave it half applied.
+        self.log(Event::AcquireMany {
+            resources: resources.iter().map(|r| r.as_ref().to_string()).collect(),
+            owner: owner.to_string(),
+            acquired_at: holder.acquired_at,
+            expire_at: holder.expire_at.map(|at| self.timeline.to_wall(at)),
+            session: holder.session.clone(),
+            first_token: first,
+        })?;
+        for resource in resources {
+            let resource = resource.as_ref();
+            let state = &mut states[slot(resource)];
+            self.insert_holder(state, resource, owner, LockKind::Exclusive, holder.clone());
+        }
+        Ok(first)
+    }
+
+    /// Acquire a lock, waiting up to `timeout` for it to become free.
+    ///
+    /// Waiters are queued per resource and granted the lock in arrival order. Returns
+    /// `LockError::Timeout` (and leaves the queue) if the lock was not granted in time.
+    ///
+    /// Returns `LockError::Deadlock` straight away if waiting would close a cycle of
+    /// owners each waiting for a lock another one holds, e.g. because `owner` already
+    /// holds a lock that someone ahead of it is waiting for.
+    pub fn acquire_wait(
+        &self,
+        resource: &str,
+        owner: &str,
+        expire_secs: Option<u64>,
+        timeout: Duration,
+    ) -> Result<u64, LockError> {
+        self.acquire_wait_with_kind(resource, owner, LockKind::Exclusive, expire_secs, timeout)
+    }
+
+    /// [`Self::acquire_wait`] with the expiration given as a [`Duration`].
+    pub fn acquire_wait_with_ttl(
+        &self,
+        resource: &str,
+        owner: &str,
+        ttl: Option<Duration>,
+        timeout: Duration,
+    ) -> Result<u64, LockError> {
+        let kind = LockKind::Exclusive;
+        self.acquire_wait_with_session(resource, owner, kind, ttl, None, timeout)
+    }
+
+    /// Acquire a shared or exclusive lock, waiting up to `timeout` for it.
+    ///
+    /// Consecutive shared waiters at the head of the queue are granted together.
+    pub fn acquire_wait_with_kind(
+        &self,
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+        expire_secs: Option<u64>,
+        timeout: Duration,
+    ) -> Result<u64, LockError> {
+        let ttl = expire_secs.map(Duration::from_secs);
+        self.acquire_wait_with_session(resource, owner, kind, ttl, None, timeout)
+    }
+
+    /// Acquire a lock bound to `session`, if given, and expiring after `ttl` if set,
+    /// waiting up to `timeout` for it.
+    pub fn acquire_wait_with_session(
+        &self,
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+        ttl: Option<Duration>,
+        session: Option<&str>,
+        timeout: Duration,
+    ) -> Result<u64, LockError> {
+        let deadline = wait_deadline(timeout)?;
+        let id = match self.admit(resource, owner, kind, ttl, session, None)? {
+            Admission::Granted(token) => return Ok(token),
+            Admission::Queued(id) => id,
+        };
+        let shard = self.shard(resource);
+        let mut state = self.lock(resource)?;
+        loop {
+            if state.is_compatible(resource, owner, kind) && state.is_next_waiter(resource, id) {
+                state.dequeue(resource, id);
+                let result = self.grant(&mut state, resource, owner, kind, ttl, session);
+                if result.is_err() {
+                    // Not granted after all; let the next waiter try.
+                    drop(state);
+                    shard.wake();
                 }
-                locks.remove(resource);
-                Ok(())
+                return result;
             }
-            Some(_) => Err(LockError::AlreadyLocked),
-            None => Err(LockError::NotFound),
+            let now = Instant::now();
+            if now >= deadline {
+                state.dequeue(resource, id);
+                drop(state);
+                // We may have been at the head of the queue; let the next waiter look.
+                shard.wake();
+                return Err(LockError::Timeout);
+            }
+            state = match shard.released.wait_timeout(state, deadline - now) {
+                Ok((guard, _)) => guard,
+                Err(e) => {
+                    e.into_inner().0.dequeue(resource, id);
+                    return Err(LockError::Internal("lock table poisoned".to_string()));
+                }
+            };
         }
     }
 
-    /// Check if a resource is currently locked.
-    pub fn is_locked(&self, resource: &str) -> bool {
-        let locks = self.locks.lock().unwrap();
-        locks.contains_key(resource)
+    /// [`Self::acquire_wait_with_session`] as a future, for async callers.
+    ///
+    /// The caller parks as a future instead of blocking a thread while it waits. It
+    /// shares the resource's FIFO queue with blocking waiters, and gives up its place
+    /// if the future is dropped before the lock is granted.
+    pub async fn acquire_async(
+        &self,
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+        ttl: Option<Duration>,
+        session: Option<&str>,
+        timeout: Duration,
+    ) -> Result<u64, LockError> {
+        let deadline = tokio::time::Instant::from_std(wait_deadline(timeout)?);
+        let id = match self.admit(resource, owner, kind, ttl, session, None)? {
+            Admission::Granted(token) => return Ok(token),
+            Admission::Queued(id) => id,
+        };
+        let queued = Queued {
+            manager: self,
+            resource,
+            id,
+            granted: false,
+            ticketed: false,
+        };
+        self.wait_queued(queued, owner, kind, ttl, session, deadline)
+            .await
     }
 
-    /// Internal: spawn a background thread to check and release expired locks every second.
-    fn spawn_expiry_worker(&self) {
-        let locks = self.locks.clone();
-        let timeslots = self.timeslots.clone();
-        thread::spawn(move || {
-            loop {
-                let now = SystemTime::now()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-                let expired: Vec<u64> = {
-                    let slots = timeslots.lock().unwrap();
-                    slots.keys().filter(|&&ts| ts <= now).cloned().collect()
-                };
-                for ts in expired {
-                    let resources = {
-                        let mut slots = timeslots.lock().unwrap();
-                        slots.remove(&ts).unwrap_or_default()
-                    };
-                    let mut l = locks.lock().unwrap();
-                    for resource in resources {
-                        l.remove(&resource);
+    /// [`Self::acquire_async`] for a client that long-polls: each call waits up to
+    /// `poll.timeout`, and a call that presents the same `poll.ticket` as an earlier
+    /// one by `owner` resumes its wait instead of rejoining the queue at the back.
+    ///
+    /// When a call times out or is dropped, the waiter is parked and keeps its place
+    /// for ten seconds. A parked waiter at the head of the queue holds up those behind
+    /// it until it polls again or its time runs out.
+    pub async fn acquire_long_poll(
+        &self,
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+        ttl: Option<Duration>,
+        session: Option<&str>,
+        poll: LongPoll<'_>,
+    ) -> Result<u64, LockError> {
+        let deadline = tokio::time::Instant::from_std(wait_deadline(poll.timeout)?);
+        let id = match self.admit(resource, owner, kind, ttl, session, Some(poll.ticket))? {
+            Admission::Granted(token) => return Ok(token),
+            Admission::Queued(id) => id,
+        };
+        let queued = Queued {
+            manager: self,
+            resource,
+            id,
+            granted: false,
+            ticketed: true,
+        };
+        self.wait_queued(queued, owner, kind, ttl, session, deadline)
+            .await
+    }
+
+    /// Internal: wait until `queued` is next in line and the lock is free, then grant it.
+    async fn wait_queued(
+        &self,
+        mut queued: Queued<'_>,
+        owner: &str,
+        kind: LockKind,
+        ttl: Option<Duration>,
+        session: Option<&str>,
+        deadline: tokio::time::Instant,
+    ) -> Result<u64, LockError> {
+        let (resource, id) = (queued.resource, queued.id);
+        let shard = self.shard(resource);
+        loop {
+            // Registered before checking, so a release in between is not missed.
+            let freed = shard.freed.notified();
+            tokio::pin!(freed);
+            freed.as_mut().enable();
+            {
+                let mut state = self.lock(resource)?;
+                if state.is_compatible(resource, owner, kind) && state.is_next_waiter(resource, id)
+                {
+                    state.dequeue(resource, id);
+                    queued.granted = true;
+                    let result = self.grant(&mut state, resource, owner, kind, ttl, session);
+                    if result.is_err() {
+                        drop(state);
+                        shard.wake();
                     }
+                    return result;
                 }
-                thread::sleep(Duration::from_secs(1));
             }
-        });
+            if tokio::time::timeout_at(deadline, freed).await.is_err() {
+                return Err(LockError::Timeout);
+            }
+        }
+    }
+
+    /// Wait up to `timeout` until nobody holds `resource`.
+    ///
+    /// Resolves straight away if `resource` is not locked. The lock may be taken again
+    /// by the time the caller acts on it; to take it, use [`Self::acquire_async`].
+    pub async fn wait_for_release(
+        &self,
+        resource: &str,
+        timeout: Duration,
+    ) -> Result<(), LockError> {
+        let deadline = tokio::time::Instant::from_std(wait_deadline(timeout)?);
+        let shard = self.shard(resource);
+        loop {
+            let freed = shard.freed.notified();
+            tokio::pin!(freed);
+            freed.as_mut().enable();
+            if !self.lock(resource)?.locks.contains_key(resource) {
+                return Ok(());
+            }
+            if tokio::time::timeout_at(deadline, freed).await.is_err() {
+                return Err(LockError::Timeout);
+            }
+        }
+    }
+
+    /// Internal: grant the lock straight away if it is free and nobody is queued for
+    /// it, or else queue `owner` for it. A waiter already queued under `ticket` is
+    /// unparked and keeps its place.
+    ///
+    /// Returns `LockError::Deadlock` instead of queueing if waiting would deadlock.
+    fn admit(
+        &self,
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+        ttl: Option<Duration>,
+        session: Option<&str>,
+        ticket: Option<&str>,
+    ) -> Result<Admission, LockError> {
+        {
+            let mut state = self.lock(resource)?;
+            if let Some(ticket) = ticket
+                && let Some(id) = state.unpark(resource, owner, kind, ticket)
+            {
+                return Ok(Admission::Queued(id));
+            }
+            if state.is_compatible(resource, owner, kind) && !state.has_waiters(resource) {
+                let token = self.grant(&mut state, resource, owner, kind, ttl, session)?;
+                return Ok(Admission::Granted(token));
+            }
+        }
+        // Checking for a deadlock needs the whole wait-for graph, so every shard is
+        // locked until the waiter is queued. The lock may have been freed meanwhile.
+        let index = self.shard_of(resource);
+        let mut states = lock_all(&self.shards);
+        let state = &mut states[index];
+        if state.is_compatible(resource, owner, kind) && !state.has_waiters(resource) {
+            let token = self.grant(state, resource, owner, kind, ttl, session)?;
+            return Ok(Admission::Granted(token));
+        }
+        let id = self.next_waiter.fetch_add(1, Ordering::SeqCst);
+        let waiter = Waiter {
+            id,
+            owner: owner.to_string(),
+            kind,
+            ticket: ticket.map(str::to_string),
+            parked_until: None,
+        };
+        if self.would_deadlock(&states, resource, &waiter) {
+            return Err(LockError::Deadlock);
+        }
+        states[index]
+            .waiters
+            .entry(resource.to_string())
+            .or_default()
+            .push_back(waiter);
+        Ok(Admission::Queued(id))
+    }
+
+    /// Release a lock for a resource and owner.
+    ///
+    /// For a shared lock this drops only `owner`'s hold; the resource stays locked
+    /// until the last holder releases it. A lock acquired reentrantly stays held until
+    /// each nested hold has been released.
+    ///
+    /// Returns `LockError::NotOwner` if only other owners hold `resource`, and
+    /// `LockError::NotFound` if nobody does.
+    pub fn release(&self, resource: &str, owner: &str) -> Result<(), LockError> {
+        let mut state = self.lock(resource)?;
+        match state
+            .locks
+            .get_mut(resource)
+            .map(|info| info.holders.get_mut(owner))
+        {
+            Some(Some(holder)) => {
+                self.log(Event::Release {
+                    resource: resource.to_string(),
+                    owner: owner.to_string(),
+                })?;
+                if holder.count > 1 {
+                    holder.count -= 1;
+                    return Ok(());
+                }
+                state.remove_holder(resource, owner);
+                drop(state);
+                self.shard(resource).wake();
+                Ok(())
+            }
+            Some(None) => Err(LockError::NotOwner),
+            None => Err(LockError::NotFound),
+        }
+    }
+
+    /// Release every hold on `resource`, whoever owns it.
+    ///
+    /// For recovering locks left behind by a crashed owner. Returns the owners whose
+    /// holds were dropped.
+    pub fn force_release(&self, resource: &str) -> Result<Vec<String>, LockError> {
+        let mut state = self.lock(resource)?;
+        let Some(info) = state.locks.get(resource) else {
+            return Err(LockError::NotFound);
+        };
+        let mut owners: Vec<String> = info.holders.keys().cloned().collect();
+        owners.sort();
+        for owner in &owners {
+            self.log(Event::ReleaseAll {
+                resource: resource.to_string(),
+                owner: owner.clone(),
+            })?;
+            state.remove_holder(resource, owner);
+        }
+        drop(state);
+        self.shard(resource).wake();
+        Ok(owners)
+    }
+
+    /// Release every lock held by `owner`. Returns the released resources, in name order.
+    ///
+    /// The shards are visited one at a time, so locks `owner` takes meanwhile may be
+    /// kept.
+    pub fn release_all_for_owner(&self, owner: &str) -> Result<Vec<String>, LockError> {
+        let mut released = Vec::new();
+        for shard in self.shards.iter() {
+            let mut state = shard
+                .state
+                .lock()
+                .map_err(|e| LockError::Internal(e.to_string()))?;
+            let resources: Vec<String> = state
+                .locks
+                .iter()
+                .filter(|(_, info)| info.holders.contains_key(owner))
+                .map(|(resource, _)| resource.clone())
+                .collect();
+            for resource in &resources {
+                self.log(Event::ReleaseAll {
+                    resource: resource.clone(),
+                    owner: owner.to_string(),
+                })?;
+                state.remove_holder(resource, owner);
+            }
+            drop(state);
+            if !resources.is_empty() {
+                shard.wake();
+            }
+            released.extend(resources);
+        }
+        released.sort();
+        Ok(released)
+    }
+
+    /// Release every lock, semaphore permit and session. Returns the number of locks
+    /// released.
+    pub fn clear(&self) -> Result<usize, LockError> {
+        let mut states = lock_all(&self.shards);
+        let mut sessions = self
+            .sessions
+            .lock()
+            .map_err(|e| LockError::Internal(e.to_string()))?;
+        let mut released = 0;
+        for state in states.iter_mut() {
+            released += state.locks.len();
+            state.locks.clear();
+            state.semaphores.clear();
+            state.timeslots.clear();
+        }
+        *sessions = Sessions::default();
+        if let Some(wal) = &self.wal {
+            let snapshot = Self::snapshot_of(&self.timeline, &self.next_token, &states, &sessions);
+            wal.lock()
+                .unwrap()
+                .write_snapshot(snapshot)
+                .map_err(|e| LockError::Internal(format!("write-ahead log: {}", e)))?;
+        }
+        drop((states, sessions));
+        for shard in self.shards.iter() {
+            shard.wake();
+        }
+        Ok(released)
+    }
+
+    /// Extend the expiration of a lock held by `owner` to `expire_secs` seconds from now.
+    ///
+    /// Works for locks acquired with or without an expiration; either way the lock will
+    /// expire `expire_secs` seconds after this call unless renewed again.
+    pub fn renew(&self, resource: &str, owner: &str, expire_secs: u64) -> Result<(), LockError> {
+        self.renew_with_ttl(resource, owner, Duration::from_secs(expire_secs))
+    }
+
+    /// [`Self::renew`] with the new expiration given as a [`Duration`] from now.
+    pub fn renew_with_ttl(
+        &self,
+        resource: &str,
+        owner: &str,
+        ttl: Duration,
+    ) -> Result<(), LockError> {
+        self.renew_at(resource, owner, deadline(self.timeline.now(), ttl)?)
+    }
+
+    /// Internal: [`Self::renew`] with the new expiration time given.
+    fn renew_at(&self, resource: &str, owner: &str, expire_at: u64) -> Result<(), LockError> {
+        let mut guard = self.lock(resource)?;
+        let state = &mut *guard;
+        match state
+            .locks
+            .get_mut(resource)
+            .map(|info| info.holders.get_mut(owner))
+        {
+            Some(Some(holder)) => {
+                self.log(Event::Renew {
+                    resource: resource.to_string(),
+                    owner: owner.to_string(),
+                    expire_at: self.timeline.to_wall(expire_at),
+                })?;
+                if let Some(old) = holder.expire_at {
+                    unschedule(&mut state.timeslots, &Hold::lock(resource, owner), old);
+                }
+                holder.expire_at = Some(expire_at);
+                self.schedule(&mut state.timeslots, Hold::lock(resource, owner), expire_at);
+                Ok(())
+            }
+            Some(None) => Err(LockError::NotOwner),
+            None => Err(LockError::NotFound),
+        }
+    }
+
+    /// Open a session that stays alive for `ttl_secs` seconds after each heartbeat.
+    ///
+    /// Locks acquired with the returned session id are released together once the
+    /// session is closed or misses its heartbeats, so a crashed client cannot hold
+    /// them forever.
+    pub fn create_session(&self, ttl_secs: u64) -> Result<String, LockError> {
+        let session = new_session_id();
+        let expire_at = deadline(self.timeline.now(), Duration::from_secs(ttl_secs))?;
+        self.create_session_at(&session, ttl_secs, expire_at)?;
+        Ok(session)
+    }
+
+    /// Internal: [`Self::create_session`] with the id and expiration time given.
+    fn create_session_at(&self, session: &str, ttl: u64, expire_at: u64) -> Result<(), LockError> {
+        if ttl == 0 {
+            return Err(LockError::InvalidRequest(
+                "session ttl must be at least 1 second".to_string(),
+            ));
+        }
+        let mut sessions = self
+            .sessions
+            .lock()
+            .map_err(|e| LockError::Internal(e.to_string()))?;
+        self.log(Event::CreateSession {
+            session: session.to_string(),
+            ttl,
+            expire_at: self.timeline.to_wall(expire_at),
+        })?;
+        sessions
+            .info
+            .insert(session.to_string(), SessionInfo { ttl, expire_at });
+        self.schedule(&mut sessions.timeslots, Hold::session(session), expire_at);
+        Ok(())
+    }
+
+    /// Keep `session` alive for another ttl from now.
+    pub fn heartbeat(&self, session: &str) -> Result<(), LockError> {
+        self.heartbeat_at(session, self.timeline.now())
+    }
+
+    /// Internal: [`Self::heartbeat`] as of `now`.
+    fn heartbeat_at(&self, session: &str, now: u64) -> Result<(), LockError> {
+        let mut guard = self
+            .sessions
+            .lock()
+            .map_err(|e| LockError::Internal(e.to_string()))?;
+        let sessions = &mut *guard;
+        let Some(info) = sessions.info.get_mut(session) else {
+            return Err(LockError::SessionNotFound);
+        };
+        let expire_at = deadline(now, Duration::from_secs(info.ttl))?;
+        self.log(Event::Heartbeat {
+            session: session.to_string(),
+            expire_at: self.timeline.to_wall(expire_at),
+        })?;
+        let old = std::mem::replace(&mut info.expire_at, expire_at);
+        unschedule(&mut sessions.timeslots, &Hold::session(session), old);
+        self.schedule(&mut sessions.timeslots, Hold::session(session), expire_at);
+        Ok(())
+    }
+
+    /// Close `session`, releasing every lock bound to it. Returns the released
+    /// resources, in name order.
+    pub fn close_session(&self, session: &str) -> Result<Vec<String>, LockError> {
+        {
+            let mut sessions = self
+                .sessions
+                .lock()
+                .map_err(|e| LockError::Internal(e.to_string()))?;
+            let Some(info) = sessions.info.get(session) else {
+                return Err(LockError::SessionNotFound);
+            };
+            let expire_at = info.expire_at;
+            self.log(Event::CloseSession {
+                session: session.to_string(),
+            })?;
+            sessions.info.remove(session);
+            unschedule(&mut sessions.timeslots, &Hold::session(session), expire_at);
+        }
+        // With the session gone no new lock can be bound to it, so one pass over the
+        // shards finds every lock it holds.
+        let mut released = drop_session_holds(&self.shards, session);
+        released.sort();
+        released.dedup();
+        Ok(released)
+    }
+
+    /// Take `permits` permits from the counting semaphore `resource`, which allows at
+    /// most `limit` permits to be held at once.
+    ///
+    /// The semaphore is created on first use with the given `limit` and dropped once
+    /// no permits are held; while it exists every caller must pass the same `limit`.
+    /// Each owner holds at most one grant per semaphore, expiring after `expire_secs`
+    /// if set. Returns a fencing token, like [`LockManager::acquire`].
+    pub fn acquire_permits(
+        &self,
+        resource: &str,
+        owner: &str,
+        permits: u32,
+        limit: u32,
+        expire_secs: Option<u64>,
+    ) -> Result<u64, LockError> {
+        let expire_at = expire_secs
+            .map(|secs| deadline(self.timeline.now(), Duration::from_secs(secs)))
+            .transpose()?;
+        self.acquire_permits_at(resource, owner, permits, limit, expire_at)
+    }
+
+    /// Internal: [`Self::acquire_permits`] with the expiration time given.
+    fn acquire_permits_at(
+        &self,
+        resource: &str,
+        owner: &str,
+        permits: u32,
+        limit: u32,
+        expire_at: Option<u64>,
+    ) -> Result<u64, LockError> {
+        if permits == 0 || permits > limit {
+            return Err(LockError::InvalidRequest(format!(
+                "permits must be between 1 and {}",
+                limit
+            )));
+        }
+        let mut guard = self.lock(resource)?;
+        let state = &mut *guard;
+        if let Some(info) = state.semaphores.get(resource) {
+            if info.limit != limit {
+                return Err(LockError::InvalidRequest(format!(
+                    "semaphore is held with limit {}",
+                    info.limit
+                )));
+            }
+            let in_use: u32 = info.holders.values().map(|&(n, _)| n).sum();
+            if info.holders.contains_key(owner) || in_use + permits > limit {
+                return Err(LockError::AlreadyLocked);
+            }
+        }
+        let token = self.next_token.fetch_add(1, Ordering::SeqCst) + 1;
+        self.log(Event::AcquirePermits {
+            resource: resource.to_string(),
+            owner: owner.to_string(),
+            permits,
+            limit,
+            expire_at: expire_at.map(|at| self.timeline.to_wall(at)),
+            token,
+        })?;
+        state
+            .semaphores
+            .entry(resource.to_string())
+            .or_insert_with(|| SemaphoreInfo {
+                limit,
+                holders: HashMap::new(),
+            })
+            .holders
+            .insert(owner.to_string(), (permits, expire_at));
+        if let Some(expire_at) = expire_at {
+            self.schedule(
+                &mut state.timeslots,
+                Hold::permits(resource, owner),
+                expire_at,
+            );
+        }
+        Ok(token)
+    }
+
+    /// Return every permit `owner` holds on the semaphore `resource`.
+    pub fn release_permits(&self, resource: &str, owner: &str) -> Result<(), LockError> {
+        let mut guard = self.lock(resource)?;
+        let state = &mut *guard;
+        let Some(info) = state.semaphores.get_mut(resource) else {
+            return Err(LockError::NotFound);
+        };
+        if !info.holders.contains_key(owner) {
+            return Err(LockError::NotOwner);
+        }
+        self.log(Event::ReleasePermits {
+            resource: resource.to_string(),
+            owner: owner.to_string(),
+        })?;
+        let Some((_, expire_at)) = info.holders.remove(owner) else {
+            return Err(LockError::NotOwner);
+        };
+        if let Some(expire_at) = expire_at {
+            unschedule(
+                &mut state.timeslots,
+                &Hold::permits(resource, owner),
+                expire_at,
+            );
+        }
+        if info.holders.is_empty() {
+            state.semaphores.remove(resource);
+        }
+        Ok(())
+    }
+
+    /// Number of permits currently held on the semaphore `resource`.
+    pub fn permits_in_use(&self, resource: &str) -> u32 {
+        let state = self.shard(resource).state.lock().unwrap();
+        state
+            .semaphores
+            .get(resource)
+            .map(|info| info.holders.values().map(|&(n, _)| n).sum())
+            .unwrap_or(0)
+    }
+
+    /// Check if a resource is currently locked.
+    pub fn is_locked(&self, resource: &str) -> bool {
+        let state = self.shard(resource).state.lock().unwrap();
+        state.locks.contains_key(resource)
+    }
+
+    /// Describe the current holders of `resource`, or `None` if it is not locked.
+    pub fn inspect(&self, resource: &str) -> Option<LockStatus> {
+        let state = self.shard(resource).state.lock().unwrap();
+        self.status(&state, resource, self.timeline.now())
+    }
+
+    /// List locked resources whose names start with `prefix`, in name order.
+    ///
+    /// Returns at most `limit` locks, starting after the resource named `after` if given.
+    /// The shards are read one at a time, so the page is not a snapshot of a single
+    /// instant.
+    pub fn list(&self, prefix: &str, after: Option<&str>, limit: usize) -> LockPage {
+        let mut names: Vec<String> = Vec::new();
+        for shard in self.shards.iter() {
+            let state = shard.state.lock().unwrap();
+            names.extend(
+                state
+                    .locks
+                    .keys()
+                    .filter(|name| name.starts_with(prefix))
+                    .filter(|name| after.is_none_or(|after| name.as_str() > after))
+                    .cloned(),
+            );
+        }
+        names.sort();
+        let next = (names.len() > limit && limit > 0).then(|| names[limit - 1].clone());
+        let now = self.timeline.now();
+        let locks = names
+            .iter()
+            .take(limit)
+            .filter_map(|name| {
+                let state = self.shard(name).state.lock().unwrap();
+                self.status(&state, name, now)
+            })
+            .collect();
+        LockPage { locks, next }
+    }
+
+    /// Internal: describe `resource`'s lock in `state` as of `now`.
+    fn status(&self, state: &ShardState, resource: &str, now: u64) -> Option<LockStatus> {
+        let info = state.locks.get(resource)?;
+        let mut holders: Vec<HolderStatus> = info
+            .holders
+            .iter()
+            .map(|(owner, holder)| HolderStatus {
+                owner: owner.clone(),
+                acquired_at: holder.acquired_at / 1000,
+                holds: holder.count,
+                expires_at: holder.expire_at.map(|at| self.timeline.to_wall(at) / 1000),
+                ttl: holder
+                    .expire_at
+                    .map(|at| at.saturating_sub(now).div_ceil(1000)),
+                ttl_ms: holder.expire_at.map(|at| at.saturating_sub(now)),
+            })
+            .collect();
+        holders.sort_by(|a, b| a.owner.cmp(&b.owner));
+        Some(LockStatus {
+            resource: resource.to_string(),
+            kind: info.kind,
+            holders,
+            waiters: state.waiters.get(resource).map_or(0, |q| q.len()),
+        })
+    }
+
+    /// Apply a [`Command`] to the lock state as of now.
+    ///
+    /// Returns the fencing token for acquire commands.
+    pub fn apply(&self, command: &Command) -> Result<Option<u64>, LockError> {
+        self.apply_at(command, self.timeline.now())
+    }
+
+    /// Internal: [`Self::apply`] as of `at`, the time the leader gave the command.
+    ///
+    /// Commands carry expirations as durations from `at`, so applying the same entries
+    /// on every node yields the same state and the same fencing tokens. The manager's
+    /// clock is first moved up to `at`, so a follower never runs behind the leader it
+    /// may later replace.
+    pub(crate) fn apply_at(&self, command: &Command, at: u64) -> Result<Option<u64>, LockError> {
+        self.timeline.catch_up(at);
+        match command {
+            Command::Noop => Ok(None),
+            Command::Acquire {
+                resource,
+                owner,
+                kind,
+                acquired_at,
+                ttl_ms,
+                session,
+                reentrant,
+            } => {
+                let mut state = self.lock(resource)?;
+                if *reentrant && let Some(result) = self.reenter(&mut state, resource, owner, *kind)
+                {
+                    return result.map(Some);
+                }
+                if !state.is_compatible(resource, owner, *kind) || state.has_waiters(resource) {
+                    return Err(LockError::AlreadyLocked);
+                }
+                let holder = Holder {
+                    acquired_at: *acquired_at,
+                    expire_at: expires(at, *ttl_ms)?,
+                    session: session.clone(),
+                    count: 1,
+                };
+                self.grant_at(&mut state, resource, owner, *kind, holder)
+                    .map(Some)
+            }
+            Command::AcquireMany {
+                resources,
+                owner,
+                acquired_at,
+                ttl_ms,
+                session,
+            } => {
+                let holder = Holder {
+                    acquired_at: *acquired_at,
+                    expire_at: expires(at, *ttl_ms)?,
+                    session: session.clone(),
+                    count: 1,
+                };
+                self.acquire_many_at(resources, owner, holder).map(Some)
+            }
+            Command::Release { resource, owner } => self.release(resource, owner).map(|_| None),
+            Command::ForceRelease { resource } => self.force_release(resource).map(|_| None),
+            Command::ReleaseOwner { owner } => self.release_all_for_owner(owner).map(|_| None),
+            Command::Renew {
+                resource,
+                owner,
+                ttl_ms,
+            } => {
+                let expire_at = deadline(at, Duration::from_millis(*ttl_ms))?;
+                self.renew_at(resource, owner, expire_at).map(|_| None)
+            }
+            Command::AcquirePermits {
+                resource,
+                owner,
+                permits,
+                limit,
+                ttl_ms,
+            } => {
+                let expire_at = expires(at, *ttl_ms)?;
+                self.acquire_permits_at(resource, owner, *permits, *limit, expire_at)
+                    .map(Some)
+            }
+            Command::ReleasePermits { resource, owner } => {
+                self.release_permits(resource, owner).map(|_| None)
+            }
+            Command::CreateSession { session, ttl } => {
+                let expire_at = deadline(at, Duration::from_secs(*ttl))?;
+                self.create_session_at(session, *ttl, expire_at)
+                    .map(|_| None)
+            }
+            Command::Heartbeat { session } => self.heartbeat_at(session, at).map(|_| None),
+            Command::CloseSession { session } => self.close_session(session).map(|_| None),
+            Command::Expire => {
+                self.expire(at);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Release every hold and session that has expired by the clock's current time.
+    ///
+    /// The expiry worker does this by itself as deadlines pass; call it to drive expiry
+    /// on a manager created with [`Self::with_manual_expiry`].
+    pub fn run_expiry(&self) {
+        self.expire(self.timeline.now());
+    }
+
+    /// Stop the expiry worker and, if state is persisted, compact it into a snapshot.
+    ///
+    /// The manager stays usable, but expired holds are then only released by
+    /// [`Self::run_expiry`]. Dropping the manager also stops the worker, without
+    /// compacting.
+    pub fn shutdown(&self) -> io::Result<()> {
+        self.stop_worker()?;
+        match &self.wal {
+            Some(wal) => {
+                let snapshot = self.snapshot();
+                wal.lock().unwrap().write_snapshot(snapshot)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Internal: release every hold and session due at or before `now`, and wake
+    /// waiters for whatever was released.
+    fn expire(&self, now: u64) {
+        Self::expire_due(&self.shards, &self.sessions, self.wal.as_deref(), now);
+    }
+
+    /// Internal: the manager's current time, in milliseconds on its timeline.
+    pub(crate) fn now(&self) -> u64 {
+        self.timeline.now()
+    }
+
+    /// Internal: move the manager's clock forward to at least `at`, the time of an
+    /// entry received from the leader.
+    pub(crate) fn catch_up(&self, at: u64) {
+        self.timeline.catch_up(at);
+    }
+
+    /// Internal: set the manager's clock to `at`, the leader's time, even if that moves
+    /// it back.
+    pub(crate) fn follow(&self, at: u64) {
+        self.timeline.follow(at);
+    }
+
+    /// Internal: whether any hold or session is due to expire.
+    pub(crate) fn has_due(&self) -> bool {
+        let now = self.timeline.now();
+        next_deadline(&self.shards, &self.sessions).is_some_and(|at| at <= now)
+    }
+
+    /// Internal: whether `owner` could take a `kind` hold on `resource` right now.
+    pub(crate) fn can_acquire(&self, resource: &str, owner: &str, kind: LockKind) -> bool {
+        let state = self.shard(resource).state.lock().unwrap();
+        state.is_compatible(resource, owner, kind) && !state.has_waiters(resource)
+    }
+
+    /// Internal: the index of the shard `resource` belongs to.
+    fn shard_of(&self, resource: &str) -> usize {
+        (self.hasher.hash_one(resource) % SHARDS as u64) as usize
+    }
+
+    /// Internal: the shard `resource` belongs to.
+    fn shard(&self, resource: &str) -> &Shard {
+        &self.shards[self.shard_of(resource)]
+    }
+
+    /// Internal: lock the shard `resource` belongs to.
+    fn lock(&self, resource: &str) -> Result<MutexGuard<'_, ShardState>, LockError> {
+        self.shard(resource)
+            .state
+            .lock()
+            .map_err(|e| LockError::Internal(e.to_string()))
+    }
+
+    /// Internal: if `owner` holds `resource` in a way that covers a `kind` hold, nest
+    /// another hold and return its fencing token. Returns `None` if `owner` does not
+    /// hold `resource`.
+    fn reenter(
+        &self,
+        state: &mut ShardState,
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+    ) -> Option<Result<u64, LockError>> {
+        let info = state.locks.get_mut(resource)?;
+        let held_kind = info.kind;
+        let holder = info.holders.get_mut(owner)?;
+        if held_kind == LockKind::Shared && kind == LockKind::Exclusive {
+            return Some(Err(LockError::AlreadyLocked));
+        }
+        let token = self.next_token.fetch_add(1, Ordering::SeqCst) + 1;
+        let logged = self.log(Event::Reenter {
+            resource: resource.to_string(),
+            owner: owner.to_string(),
+            token,
+        });
+        Some(logged.map(|_| {
+            holder.count += 1;
+            token
+        }))
+    }
+
+    /// Internal: record a new holder for a resource and return its fencing token.
+    ///
+    /// The caller must have checked the hold is compatible with the current holders.
+    fn grant(
+        &self,
+        state: &mut ShardState,
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+        ttl: Option<Duration>,
+        session: Option<&str>,
+    ) -> Result<u64, LockError> {
+        let holder = Holder {
+            acquired_at: self.timeline.wall_now(),
+            expire_at: ttl
+                .map(|ttl| deadline(self.timeline.now(), ttl))
+                .transpose()?,
+            session: session.map(str::to_string),
+            count: 1,
+        };
+        self.grant_at(state, resource, owner, kind, holder)
+    }
+
+    /// Internal: [`Self::grant`] with the new hold's timestamps given.
+    fn grant_at(
+        &self,
+        state: &mut ShardState,
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+        holder: Holder,
+    ) -> Result<u64, LockError> {
+        if let Some(session) = &holder.session
+            && !self.sessions.lock().unwrap().info.contains_key(session)
+        {
+            return Err(LockError::SessionNotFound);
+        }
+        // Issued while the shard is held so token order matches grant order.
+        let token = self.next_token.fetch_add(1, Ordering::SeqCst) + 1;
+        self.log(Event::Acquire {
+            resource: resource.to_string(),
+            owner: owner.to_string(),
+            kind,
+            acquired_at: holder.acquired_at,
+            expire_at: holder.expire_at.map(|at| self.timeline.to_wall(at)),
+            session: holder.session.clone(),
+            token,
+        })?;
+        self.insert_holder(state, resource, owner, kind, holder);
+        Ok(token)
+    }
+
+    /// Internal: add `holder` to `resource` and schedule its expiry. Does not log.
+    fn insert_holder(
+        &self,
+        state: &mut ShardState,
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+        holder: Holder,
+    ) {
+        let expire_at = holder.expire_at;
+        state
+            .locks
+            .entry(resource.to_string())
+            .or_insert_with(|| LockInfo {
+                kind,
+                holders: HashMap::new(),
+            })
+            .holders
+            .insert(owner.to_string(), holder);
+        if let Some(expire_at) = expire_at {
+            self.schedule(&mut state.timeslots, Hold::lock(resource, owner), expire_at);
+        }
+    }
+
+    /// Internal: park waiter `id` for `resource` between long-polls, giving up its
+    /// place if it does not poll again in time.
+    fn park(&self, state: &mut ShardState, resource: &str, id: u64) {
+        let Some(waiter) = state
+            .waiters
+            .get_mut(resource)
+            .and_then(|q| q.iter_mut().find(|w| w.id == id))
+        else {
+            return;
+        };
+        let until = self.timeline.now() + TICKET_GRACE_MS;
+        waiter.parked_until = Some(until);
+        self.schedule(&mut state.timeslots, Hold::waiter(resource, id), until);
+    }
+
+    /// Internal: schedule `hold` to expire at `expire_at`, waking the expiry worker if
+    /// that is sooner than it would otherwise wake up.
+    fn schedule(&self, slots: &mut Timeslots, hold: Hold, expire_at: u64) {
+        slots.entry(expire_at).or_default().insert(hold);
+        self.timer.wake_by(expire_at);
+    }
+
+    /// Internal: append `event` to the write-ahead log, if state is persisted.
+    ///
+    /// Called with the lock guarding the affected state held, before changing it.
+    fn log(&self, event: Event) -> Result<(), LockError> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+        let mut wal = wal.lock().map_err(|e| LockError::Internal(e.to_string()))?;
+        wal.append(event)
+            .map_err(|e| LockError::Internal(format!("write-ahead log: {}", e)))
+    }
+
+    /// Internal: a copy of the current state, for writing a snapshot.
+    fn snapshot(&self) -> Snapshot {
+        Self::snapshot_of(
+            &self.timeline,
+            &self.next_token,
+            &lock_all(&self.shards),
+            &self.sessions.lock().unwrap(),
+        )
+    }
+
+    /// Internal: a snapshot of the given state, with expiration times converted to
+    /// wall-clock time.
+    fn snapshot_of(
+        timeline: &Timeline,
+        next_token: &AtomicU64,
+        states: &[MutexGuard<'_, ShardState>],
+        sessions: &Sessions,
+    ) -> Snapshot {
+        let mut snapshot = Self::state_of(next_token, states, sessions);
+        map_deadlines(&mut snapshot, &mut [], |at| timeline.to_wall(at));
+        snapshot
+    }
+
+    /// Internal: a replica's state for a Raft snapshot. Expiration times stay on the
+    /// manager's timeline, which the nodes of a cluster share.
+    pub(crate) fn replica_snapshot(&self) -> Snapshot {
+        Self::state_of(
+            &self.next_token,
+            &lock_all(&self.shards),
+            &self.sessions.lock().unwrap(),
+        )
+    }
+
+    /// Internal: replace a replica's state with `snapshot`, taken by
+    /// [`Self::replica_snapshot`]. Nothing is expired: that is up to the leader.
+    pub(crate) fn restore_replica(&self, snapshot: Snapshot) {
+        self.next_token.store(snapshot.next_token, Ordering::SeqCst);
+        let restored = ShardState {
+            locks: snapshot.locks,
+            semaphores: snapshot.semaphores,
+            ..ShardState::default()
+        };
+        self.install(restored, snapshot.sessions, None);
+    }
+
+    /// Internal: a snapshot of the given state, with expiration times as they are.
+    fn state_of(
+        next_token: &AtomicU64,
+        states: &[MutexGuard<'_, ShardState>],
+        sessions: &Sessions,
+    ) -> Snapshot {
+        let mut snapshot = Snapshot {
+            seq: 0,
+            millis: true,
+            next_token: next_token.load(Ordering::SeqCst),
+            locks: HashMap::new(),
+            semaphores: HashMap::new(),
+            sessions: sessions.info.clone(),
+        };
+        for state in states {
+            snapshot.locks.extend(state.locks.clone());
+            snapshot.semaphores.extend(state.semaphores.clone());
+        }
+        snapshot
+    }
+
+    /// Internal: load `snapshot` and replay `events` into an empty manager, then drop
+    /// holds that expired in the meantime and schedule expiry for the rest.
+    fn restore(&self, snapshot: Snapshot, events: Vec<Event>) {
+        let (mut snapshot, mut events) = if snapshot.millis {
+            (snapshot, events)
+        } else {
+            into_millis(snapshot, events)
+        };
+        map_deadlines(&mut snapshot, &mut events, |at| {
+            self.timeline.time_of_wall(at)
+        });
+        let mut next_token = snapshot.next_token;
+        // Replayed as if into a single shard, then spread over the real ones.
+        let mut restored = ShardState {
+            locks: snapshot.locks,
+            semaphores: snapshot.semaphores,
+            ..ShardState::default()
+        };
+        let mut sessions = snapshot.sessions;
+        for event in events {
+            match event {
+                Event::Acquire {
+                    resource,
+                    owner,
+                    kind,
+                    acquired_at,
+                    expire_at,
+                    session,
+                    token,
+                } => {
+                    next_token = next_token.max(token);
+                    restored
+                        .locks
+                        .entry(resource)
+                        .or_insert_with(|| LockInfo {
+                            kind,
+                            holders: HashMap::new(),
+                        })
+                        .holders
+                        .insert(
+                            owner,
+                            Holder {
+                                acquired_at,
+                                expire_at,
+                                session,
+                                count: 1,
+                            },
+                        );
+                }
+                Event::AcquireMany {
+                    resources,
+                    owner,
+                    acquired_at,
+                    expire_at,
+                    session,
+                    first_token,
+                } => {
+                    next_token = next_token.max(first_token + resources.len() as u64 - 1);
+                    for resource in resources {
+                        restored
+                            .locks
+                            .entry(resource)
+                            .or_insert_with(|| LockInfo {
+                                kind: LockKind::Exclusive,
+                                holders: HashMap::new(),
+                            })
+                            .holders
+                            .insert(
+                                owner.clone(),
+                                Holder {
+                                    acquired_at,
+                                    expire_at,
+                                    session: session.clone(),
+                                    count: 1,
+                                },
+                            );
+                    }
+                }
+                Event::Reenter {
+                    resource,
+                    owner,
+                    token,
+                } => {
+                    next_token = next_token.max(token);
+                    if let Some(holder) = restored
+                        .locks
+                        .get_mut(&resource)
+                        .and_then(|info| info.holders.get_mut(&owner))
+                    {
+                        holder.count += 1;
+                    }
+                }
+                Event::Release { resource, owner } => {
+                    if let Some(holder) = restored
+                        .locks
+                        .get_mut(&resource)
+                        .and_then(|info| info.holders.get_mut(&owner))
+                        && holder.count > 1
+                    {
+                        holder.count -= 1;
+                        continue;
+                    }
+                    restored.remove_holder(&resource, &owner);
+                }
+                Event::ReleaseAll { resource, owner } | Event::Expire { resource, owner } => {
+                    restored.remove_holder(&resource, &owner);
+                }
+                Event::Renew {
+                    resource,
+                    owner,
+                    expire_at,
+                } => {
+                    if let Some(holder) = restored
+                        .locks
+                        .get_mut(&resource)
+                        .and_then(|info| info.holders.get_mut(&owner))
+                    {
+                        holder.expire_at = Some(expire_at);
+                    }
+                }
+                Event::AcquirePermits {
+                    resource,
+                    owner,
+                    permits,
+                    limit,
+                    expire_at,
+                    token,
+                } => {
+                    next_token = next_token.max(token);
+                    restored
+                        .semaphores
+                        .entry(resource)
+                        .or_insert_with(|| SemaphoreInfo {
+                            limit,
+                            holders: HashMap::new(),
+                        })
+                        .holders
+                        .insert(owner, (permits, expire_at));
+                }
+                Event::ReleasePermits { resource, owner }
+                | Event::ExpirePermits { resource, owner } => {
+                    if let Some(info) = restored.semaphores.get_mut(&resource) {
+                        info.holders.remove(&owner);
+                        if info.holders.is_empty() {
+                            restored.semaphores.remove(&resource);
+                        }
+                    }
+                }
+                Event::CreateSession {
+                    session,
+                    ttl,
+                    expire_at,
+                } => {
+                    sessions.insert(session, SessionInfo { ttl, expire_at });
+                }
+                Event::Heartbeat { session, expire_at } => {
+                    if let Some(info) = sessions.get_mut(&session) {
+                        info.expire_at = expire_at;
+                    }
+                }
+                Event::CloseSession { session } | Event::ExpireSession { session } => {
+                    sessions.remove(&session);
+                    restored.drop_session_holds(&session);
+                }
+            }
+        }
+        self.next_token.store(next_token, Ordering::SeqCst);
+        self.install(restored, sessions, Some(self.timeline.now()));
+    }
+
+    /// Internal: replace the lock state with the locks and semaphores of `restored`
+    /// and with `sessions`, and schedule their expiry. Holds and sessions due by
+    /// `now`, if given, are dropped instead.
+    fn install(
+        &self,
+        mut restored: ShardState,
+        mut sessions: HashMap<String, SessionInfo>,
+        now: Option<u64>,
+    ) {
+        for shard in self.shards.iter() {
+            *shard.state.lock().unwrap() = ShardState::default();
+        }
+        let due = |at: u64| now.is_some_and(|now| at <= now);
+        let dead: Vec<String> = sessions
+            .iter()
+            .filter(|(_, info)| due(info.expire_at))
+            .map(|(session, _)| session.clone())
+            .collect();
+        for session in dead {
+            sessions.remove(&session);
+            restored.drop_session_holds(&session);
+        }
+        for (resource, mut info) in restored.locks {
+            info.holders
+                .retain(|_, holder| !holder.expire_at.is_some_and(due));
+            if info.holders.is_empty() {
+                continue;
+            }
+            let mut state = self.shard(&resource).state.lock().unwrap();
+            for (owner, holder) in &info.holders {
+                if let Some(at) = holder.expire_at {
+                    self.schedule(&mut state.timeslots, Hold::lock(&resource, owner), at);
+                }
+            }
+            state.locks.insert(resource, info);
+        }
+        for (resource, mut info) in restored.semaphores {
+            info.holders
+                .retain(|_, &mut (_, expire_at)| !expire_at.is_some_and(due));
+            if info.holders.is_empty() {
+                continue;
+            }
+            let mut state = self.shard(&resource).state.lock().unwrap();
+            for (owner, &(_, expire_at)) in &info.holders {
+                if let Some(at) = expire_at {
+                    self.schedule(&mut state.timeslots, Hold::permits(&resource, owner), at);
+                }
+            }
+            state.semaphores.insert(resource, info);
+        }
+        let mut guard = self.sessions.lock().unwrap();
+        let restored_sessions = &mut *guard;
+        restored_sessions.timeslots.clear();
+        for (session, info) in &sessions {
+            self.schedule(
+                &mut restored_sessions.timeslots,
+                Hold::session(session),
+                info.expire_at,
+            );
+        }
+        restored_sessions.info = sessions;
+    }
+
+    /// Internal: whether queueing `waiter` for `resource` would deadlock. Call with
+    /// every shard locked, in index order.
+    ///
+    /// Walks the wait-for graph, where a waiter waits for the holders of its resource
+    /// and for the waiters ahead of it that it cannot be granted alongside. Queueing
+    /// deadlocks if the walk gets back to the new waiter's owner.
+    fn would_deadlock(
+        &self,
+        states: &[MutexGuard<'_, ShardState>],
+        resource: &str,
+        waiter: &Waiter,
+    ) -> bool {
+        let blockers = |resource: &str, me: &Waiter| -> Vec<String> {
+            let state = &states[self.shard_of(resource)];
+            let holders = state
+                .locks
+                .get(resource)
+                .into_iter()
+                .flat_map(|i| i.holders.keys());
+            let ahead = state
+                .waiters
+                .get(resource)
+                .into_iter()
+                .flatten()
+                .take_while(|w| w.id != me.id)
+                .filter(|w| me.waits_for(w))
+                .map(|w| &w.owner);
+            holders.chain(ahead).cloned().collect()
+        };
+        let mut stack = blockers(resource, waiter);
+        let mut seen = HashSet::new();
+        while let Some(owner) = stack.pop() {
+            if owner == waiter.owner {
+                return true;
+            }
+            if !seen.insert(owner.clone()) {
+                continue;
+            }
+            for state in states {
+                for (res, queue) in state.waiters.iter() {
+                    for w in queue.iter().filter(|w| w.owner == owner) {
+                        stack.extend(blockers(res, w));
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    /// Internal: drop every hold and session whose expiration is at or before `now`,
+    /// and wake waiters on the shards where locks were freed.
+    fn expire_due(
+        shards: &[Shard],
+        sessions: &Mutex<Sessions>,
+        wal: Option<&Mutex<Wal>>,
+        now: u64,
+    ) {
+        for shard in shards {
+            let mut state = shard.state.lock().unwrap();
+            if state.expire_due(wal, now) {
+                drop(state);
+                shard.wake();
+            }
+        }
+        let dead = {
+            let mut guard = sessions.lock().unwrap();
+            let sessions = &mut *guard;
+            let mut dead = Vec::new();
+            while let Some(entry) = sessions.timeslots.first_entry() {
+                if *entry.key() > now {
+                    break;
+                }
+                for hold in entry.remove() {
+                    // The session may have sent a heartbeat since; only drop it if it
+                    // is still due.
+                    if let Hold::Session { session } = hold
+                        && sessions
+                            .info
+                            .get(&session)
+                            .is_some_and(|s| s.expire_at <= now)
+                    {
+                        sessions.info.remove(&session);
+                        // Best effort: replay drops expired sessions anyway.
+                        if let Some(wal) = wal {
+                            let _ = wal.lock().unwrap().append(Event::ExpireSession {
+                                session: session.clone(),
+                            });
+                        }
+                        dead.push(session);
+                    }
+                }
+            }
+            dead
+        };
+        // Sessions that missed their heartbeats take their locks with them.
+        for session in dead {
+            drop_session_holds(shards, &session);
+        }
+    }
+
+    /// Internal: what the expiry worker needs, shared with this manager.
+    fn expiry(&self) -> Expiry {
+        Expiry {
+            shards: self.shards.clone(),
+            sessions: self.sessions.clone(),
+            timer: self.timer.clone(),
+            next_token: self.next_token.clone(),
+            wal: self.wal.clone(),
+            timeline: self.timeline.clone(),
+            stopping: self.stopping.clone(),
+        }
+    }
+
+    /// Internal: spawn a background thread that releases holds as they expire.
+    ///
+    /// The thread sleeps until the earliest scheduled expiration, or until
+    /// [`Self::schedule`] sets an earlier one, and checks at least once a second
+    /// whether a snapshot is due.
+    fn spawn_expiry_worker(&self) {
+        let expiry = self.expiry();
+        let worker = thread::spawn(move || {
+            while !expiry.stopping.load(Ordering::SeqCst) {
+                expiry.run();
+                let next = next_deadline(&expiry.shards, &expiry.sessions);
+                let lock = expiry.timer.lock.lock().unwrap();
+                if let Some(wait) = expiry.plan(next) {
+                    let _ = expiry.timer.wakeup.wait_timeout(lock, wait);
+                }
+            }
+        });
+        *self.worker.lock().unwrap() = Some(Worker::Thread(worker));
+    }
+
+    /// Internal: [`Self::spawn_expiry_worker`] as a task on the current tokio runtime.
+    fn spawn_expiry_task(&self) {
+        let expiry = self.expiry();
+        let task = tokio::spawn(async move {
+            while !expiry.stopping.load(Ordering::SeqCst) {
+                expiry.run();
+                let next = next_deadline(&expiry.shards, &expiry.sessions);
+                let woken = expiry.timer.notify.notified();
+                tokio::pin!(woken);
+                woken.as_mut().enable();
+                let wait = {
+                    let _lock = expiry.timer.lock.lock().unwrap();
+                    expiry.plan(next)
+                };
+                if let Some(wait) = wait {
+                    let _ = tokio::time::timeout(wait, woken).await;
+                }
+            }
+        });
+        *self.worker.lock().unwrap() = Some(Worker::Task(task));
+    }
+
+    /// Internal: tell the expiry worker to exit and wait for it, if one is running.
+    ///
+    /// An expiry task is not waited for, as that could block the runtime it runs on;
+    /// it is aborted instead.
+    fn stop_worker(&self) -> io::Result<()> {
+        self.stopping.store(true, Ordering::SeqCst);
+        {
+            // Notify under the lock the worker waits on, so the wakeup can't be missed.
+            let _lock = self.timer.lock.lock().unwrap();
+            self.timer.wakeup.notify_all();
+            self.timer.notify.notify_waiters();
+        }
+        match self.worker.lock().unwrap().take() {
+            Some(Worker::Thread(worker)) => worker
+                .join()
+                .map_err(|_| io::Error::other("expiry worker panicked")),
+            Some(Worker::Task(task)) => {
+                task.abort();
+                Ok(())
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for LockManager {
+    fn drop(&mut self) {
+        let _ = self.stop_worker();
+    }
+}
+
+impl ShardState {
+    /// Whether `owner` could take a `kind` hold on `resource` given its current holders.
+    fn is_compatible(&self, resource: &str, owner: &str, kind: LockKind) -> bool {
+        match self.locks.get(resource) {
+            None => true,
+            Some(info) => {
+                kind == LockKind::Shared
+                    && info.kind == LockKind::Shared
+                    && !info.holders.contains_key(owner)
+            }
+        }
+    }
+
+    /// Whether anyone is queued for `resource`.
+    fn has_waiters(&self, resource: &str) -> bool {
+        self.waiters.get(resource).is_some_and(|q| !q.is_empty())
+    }
+
+    /// Whether waiter `id` is next in line for `resource`.
+    ///
+    /// An exclusive waiter must be at the head of the queue; a shared waiter only needs
+    /// every waiter ahead of it to be shared as well.
+    fn is_next_waiter(&self, resource: &str, id: u64) -> bool {
+        let Some(queue) = self.waiters.get(resource) else {
+            return false;
+        };
+        let Some(me) = queue.iter().find(|w| w.id == id) else {
+            return false;
+        };
+        queue
+            .iter()
+            .take_while(|w| w.id != id)
+            .all(|ahead| !me.waits_for(ahead))
+    }
+
+    /// Remove waiter `id` from the queue for `resource`.
+    fn dequeue(&mut self, resource: &str, id: u64) {
+        if let Some(queue) = self.waiters.get_mut(resource) {
+            queue.retain(|w| w.id != id);
+            if queue.is_empty() {
+                self.waiters.remove(resource);
+            }
+        }
+    }
+
+    /// Resume the waiter `owner` queued for `resource` under `ticket`, if it is still
+    /// queued. Returns its id.
+    fn unpark(&mut self, resource: &str, owner: &str, kind: LockKind, ticket: &str) -> Option<u64> {
+        let waiter =
+            self.waiters.get_mut(resource)?.iter_mut().find(|w| {
+                w.owner == owner && w.kind == kind && w.ticket.as_deref() == Some(ticket)
+            })?;
+        if let Some(until) = waiter.parked_until.take() {
+            unschedule(
+                &mut self.timeslots,
+                &Hold::waiter(resource, waiter.id),
+                until,
+            );
+        }
+        Some(waiter.id)
+    }
+
+    /// Drop `owner`'s hold on `resource`, freeing the resource if it was the last.
+    fn remove_holder(&mut self, resource: &str, owner: &str) {
+        let Some(info) = self.locks.get_mut(resource) else {
+            return;
+        };
+        // Remove from timeslot if present
+        if let Some(expire_at) = info.holders.remove(owner).and_then(|h| h.expire_at) {
+            unschedule(&mut self.timeslots, &Hold::lock(resource, owner), expire_at);
+        }
+        if info.holders.is_empty() {
+            self.locks.remove(resource);
+        }
+    }
+
+    /// Drop every lock hold bound to `session`. Returns the resources it held.
+    fn drop_session_holds(&mut self, session: &str) -> Vec<String> {
+        let mut released = Vec::new();
+        let slots = &mut self.timeslots;
+        self.locks.retain(|resource, info| {
+            info.holders.retain(|owner, holder| {
+                if holder.session.as_deref() != Some(session) {
+                    return true;
+                }
+                if let Some(expire_at) = holder.expire_at {
+                    unschedule(slots, &Hold::lock(resource, owner), expire_at);
+                }
+                released.push(resource.clone());
+                false
+            });
+            !info.holders.is_empty()
+        });
+        released
+    }
+
+    /// Drop every lock and permit hold whose expiration is at or before `now`, and
+    /// every parked waiter whose time is up.
+    ///
+    /// Returns whether a lock was freed or a waiter left a queue.
+    fn expire_due(&mut self, wal: Option<&Mutex<Wal>>, now: u64) -> bool {
+        let mut released = false;
+        while let Some(entry) = self.timeslots.first_entry() {
+            if *entry.key() > now {
+                break;
+            }
+            for hold in entry.remove() {
+                // The hold may have been renewed, or released and re-acquired, since
+                // it was scheduled; only drop it if it is still due.
+                match hold {
+                    Hold::Lock { resource, owner } => {
+                        let Some(info) = self.locks.get_mut(&resource) else {
+                            continue;
+                        };
+                        if info
+                            .holders
+                            .get(&owner)
+                            .and_then(|h| h.expire_at)
+                            .is_some_and(|at| at <= now)
+                        {
+                            info.holders.remove(&owner);
+                            if info.holders.is_empty() {
+                                self.locks.remove(&resource);
+                            }
+                            released = true;
+                            // Best effort: replay drops expired holds anyway.
+                            if let Some(wal) = wal {
+                                let _ = wal
+                                    .lock()
+                                    .unwrap()
+                                    .append(Event::Expire { resource, owner });
+                            }
+                        }
+                    }
+                    Hold::Permits { resource, owner } => {
+                        let Some(info) = self.semaphores.get_mut(&resource) else {
+                            continue;
+                        };
+                        if info
+                            .holders
+                            .get(&owner)
+                            .and_then(|&(_, at)| at)
+                            .is_some_and(|at| at <= now)
+                        {
+                            info.holders.remove(&owner);
+                            if info.holders.is_empty() {
+                                self.semaphores.remove(&resource);
+                            }
+                            if let Some(wal) = wal {
+                                let _ = wal
+                                    .lock()
+                                    .unwrap()
+                                    .append(Event::ExpirePermits { resource, owner });
+                            }
+                        }
+                    }
+                    Hold::Waiter { resource, id } => {
+                        let parked = self
+                            .waiters
+                            .get(&resource)
+                            .and_then(|q| q.iter().find(|w| w.id == id))
+                            .and_then(|w| w.parked_until);
+                        if parked.is_some_and(|at| at <= now) {
+                            self.dequeue(&resource, id);
+                            released = true;
+                        }
+                    }
+                    Hold::Session { .. } => {}
+                }
+            }
+        }
+        released
+    }
+}
+
+/// Internal: lock every shard, in index order.
+fn lock_all(shards: &[Shard]) -> Vec<MutexGuard<'_, ShardState>> {
+    shards.iter().map(|s| s.state.lock().unwrap()).collect()
+}
+
+/// Internal: drop every lock hold bound to `session`, one shard at a time, and wake
+/// waiters on the shards where locks were freed. Returns the resources it held.
+fn drop_session_holds(shards: &[Shard], session: &str) -> Vec<String> {
+    let mut released = Vec::new();
+    for shard in shards {
+        let mut state = shard.state.lock().unwrap();
+        let dropped = state.drop_session_holds(session);
+        drop(state);
+        if !dropped.is_empty() {
+            shard.wake();
+            released.extend(dropped);
+        }
+    }
+    released
+}
+
+/// Internal: the earliest expiration scheduled for any hold or session.
+fn next_deadline(shards: &[Shard], sessions: &Mutex<Sessions>) -> Option<u64> {
+    let first = |slots: &Timeslots| slots.first_key_value().map(|(&at, _)| at);
+    let holds = shards
+        .iter()
+        .filter_map(|s| first(&s.state.lock().unwrap().timeslots))
+        .min();
+    let sessions = first(&sessions.lock().unwrap().timeslots);
+    holds.into_iter().chain(sessions).min()
+}
+
+/// Internal: drop `hold` from the timeslot bucket for `expire_at`.
+fn unschedule(slots: &mut Timeslots, hold: &Hold, expire_at: u64) {
+    if let Some(set) = slots.get_mut(&expire_at) {
+        set.remove(hold);
+        if set.is_empty() {
+            slots.remove(&expire_at);
+        }
+    }
+}
+
+/// Internal: a fresh, unguessable session id.
+pub(crate) fn new_session_id() -> String {
+    // Each RandomState is seeded with fresh random keys.
+    let a = RandomState::new().build_hasher().finish();
+    let b = RandomState::new().build_hasher().finish();
+    format!("{:016x}{:016x}", a, b)
+}
+
+/// Current wall-clock time as a unix timestamp in milliseconds.
+pub(crate) fn unix_now_ms() -> u64 {
+    unix_ms(SystemTime::now())
+}
+
+/// `time` as a unix timestamp in milliseconds.
+fn unix_ms(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Internal: the instant `timeout` from now, for waiting until. Returns
+/// `LockError::InvalidRequest` if that is too far off to represent.
+pub(crate) fn wait_deadline(timeout: Duration) -> Result<Instant, LockError> {
+    Instant::now()
+        .checked_add(timeout)
+        .ok_or_else(|| LockError::InvalidRequest("wait timeout is too long".to_string()))
+}
+
+/// Time in milliseconds `ttl` after `now`, rounding partial milliseconds up. Returns
+/// `LockError::InvalidRequest` if that is too far off to represent.
+pub(crate) fn deadline(now: u64, ttl: Duration) -> Result<u64, LockError> {
+    u64::try_from(ttl.as_nanos().div_ceil(1_000_000))
+        .ok()
+        .and_then(|ms| now.checked_add(ms))
+        .ok_or_else(|| LockError::InvalidRequest("expiration is too long".to_string()))
+}
+
+/// Internal: the [`deadline`] `ttl_ms` milliseconds after `at`, if there is a ttl.
+fn expires(at: u64, ttl_ms: Option<u64>) -> Result<Option<u64>, LockError> {
+    ttl_ms
+        .map(|ms| deadline(at, Duration::from_millis(ms)))
+        .transpose()
+}
+
+/// Internal: apply `f` to every expiration time in `snapshot` and `events`.
+fn map_deadlines(snapshot: &mut Snapshot, events: &mut [Event], f: impl Fn(u64) -> u64) {
+    for holder in snapshot
+        .locks
+        .values_mut()
+        .flat_map(|i| i.holders.values_mut())
+    {
+        holder.expire_at = holder.expire_at.map(&f);
+    }
+    for (_, expire_at) in snapshot
+        .semaphores
+        .values_mut()
+        .flat_map(|i| i.holders.values_mut())
+    {
+        *expire_at = expire_at.map(&f);
+    }
+    for info in snapshot.sessions.values_mut() {
+        info.expire_at = f(info.expire_at);
+    }
+    for event in events {
+        match event {
+            Event::Acquire { expire_at, .. }
+            | Event::AcquireMany { expire_at, .. }
+            | Event::AcquirePermits { expire_at, .. } => *expire_at = expire_at.map(&f),
+            Event::Renew { expire_at, .. }
+            | Event::CreateSession { expire_at, .. }
+            | Event::Heartbeat { expire_at, .. } => *expire_at = f(*expire_at),
+            _ => {}
+        }
+    }
+}
+
+/// Internal: convert state saved with timestamps in seconds, before expirations had
+/// millisecond precision.
+fn into_millis(mut snapshot: Snapshot, mut events: Vec<Event>) -> (Snapshot, Vec<Event>) {
+    for holder in snapshot
+        .locks
+        .values_mut()
+        .flat_map(|i| i.holders.values_mut())
+    {
+        holder.acquired_at *= 1000;
+    }
+    for event in &mut events {
+        if let Event::Acquire { acquired_at, .. } | Event::AcquireMany { acquired_at, .. } = event {
+            *acquired_at *= 1000;
+        }
     }
+    map_deadlines(&mut snapshot, &mut events, |at| at * 1000);
+    snapshot.millis = true;
+    (snapshot, events)
 }
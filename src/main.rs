@@ -1,19 +1,316 @@
 use dotenvy::dotenv;
 
-use actix_web::{App, HttpRequest, HttpResponse, HttpServer, Responder, web};
-use lockserver::LockManager;
-use serde::Deserialize;
+use actix_web::dev::ServerHandle;
+use actix_web::http::{Method, StatusCode};
+use actix_web::{
+    App, FromRequest, Handler, HttpRequest, HttpResponse, HttpServer, Responder, Route, web,
+};
+use lockserver::audit::{AuditEntry, AuditLog};
+use lockserver::auth::{Grant, Scope, TokenStore};
+use lockserver::raft::{
+    self, AppendRequest, ClusterConfig, RaftError, RaftNode, SnapshotRequest, VoteRequest,
+};
+use lockserver::{LockError, LockKind, LockManager, LockPage, LockStatus, LongPoll};
+use serde::{Deserialize, Serialize};
 use std::env;
 use std::sync::Arc;
-use std::sync::Mutex as StdMutex;
+use std::time::Duration;
+use utoipa::openapi::path::Operation;
+use utoipa::openapi::security::{ApiKey, ApiKeyValue, Http, HttpAuthScheme, SecurityScheme};
+use utoipa::openapi::{Deprecated, OpenApi as Spec, PathItem};
+use utoipa::{IntoParams, Modify, OpenApi, ToSchema};
 //
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 
-#[derive(Deserialize)]
+/// Body of `PUT /v1/locks/{resource}`.
+#[derive(Deserialize, ToSchema)]
+struct AcquireRequest {
+    owner: String,
+    /// Seconds until the lock expires.
+    expire: Option<u64>,
+    /// Milliseconds until the lock expires; takes precedence over `expire`.
+    expire_ms: Option<u64>,
+    /// Seconds to wait in the FIFO queue if the lock is held, instead of failing.
+    wait_timeout: Option<u64>,
+    /// Chosen by the client and sent with each long-poll of one acquire. A poll that
+    /// repeats the ticket within ten seconds of the last one ending keeps its place in
+    /// the queue instead of rejoining at the back.
+    ticket: Option<String>,
+    #[serde(default)]
+    kind: LockKind,
+    /// Release the lock when this session ends.
+    session: Option<String>,
+    /// If the owner already holds the lock, nest another hold.
+    #[serde(default)]
+    reentrant: bool,
+}
+
+/// Body of the unversioned `POST /acquire` and `POST /release`, which name the
+/// resource in the body.
+#[derive(Deserialize, ToSchema)]
 struct LockRequest {
     resource: String,
+    #[serde(flatten)]
+    lock: AcquireRequest,
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct OwnerQuery {
+    owner: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct BatchRequest {
+    resources: Vec<String>,
+    owner: String,
+    /// Seconds until the locks expire, shared by every lock in the batch.
+    expire: Option<u64>,
+    /// Milliseconds until the locks expire; takes precedence over `expire`.
+    expire_ms: Option<u64>,
+    session: Option<String>,
+}
+
+/// Body of `PATCH /v1/locks/{resource}`. One of `expire` and `expire_ms` is required.
+#[derive(Deserialize, ToSchema)]
+struct RenewRequest {
+    owner: String,
+    /// Seconds from now.
+    expire: Option<u64>,
+    /// Milliseconds from now; takes precedence over `expire`.
+    expire_ms: Option<u64>,
+}
+
+/// Body of the unversioned `POST /renew`.
+#[derive(Deserialize, ToSchema)]
+struct LegacyRenewRequest {
+    resource: String,
+    #[serde(flatten)]
+    renew: RenewRequest,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct SemaphoreRequest {
+    resource: String,
+    owner: String,
+    #[serde(default = "default_permits")]
+    #[schema(default = 1)]
+    permits: u32,
+    /// Most permits that may be held at once. Required for acquire, ignored for release.
+    #[serde(default)]
+    limit: u32,
+    /// Seconds until the permits expire.
+    expire: Option<u64>,
+}
+
+fn default_permits() -> u32 {
+    1
+}
+
+/// The expiration given as `expire_ms` (milliseconds) or else `expire` (seconds).
+fn ttl(expire: Option<u64>, expire_ms: Option<u64>) -> Option<Duration> {
+    expire_ms
+        .map(Duration::from_millis)
+        .or(expire.map(Duration::from_secs))
+}
+
+#[derive(Deserialize, ToSchema)]
+struct CreateSessionRequest {
+    /// Seconds the session lives after each heartbeat.
+    ttl: u64,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct SessionRequest {
+    session: String,
+}
+
+#[derive(Serialize, ToSchema)]
+struct SessionResponse {
+    session: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ForceReleaseRequest {
+    resource: String,
+}
+
+#[derive(Deserialize, ToSchema)]
+struct ReleaseOwnerRequest {
     owner: String,
-    expire: Option<u64>, // seconds
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct ListQuery {
+    /// Only list resources whose names start with this.
+    #[serde(default)]
+    prefix: String,
+    /// Resource name to continue after, from the previous page's `next`.
+    after: Option<String>,
+    /// Page size; at most 1000.
+    limit: Option<usize>,
+}
+
+/// Page size for `GET /locks` when no limit is given, and the largest allowed.
+const DEFAULT_PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
+
+/// Largest request body accepted by the Raft RPCs, which carry log entries and whole
+/// snapshots of the lock state.
+const RAFT_BODY_LIMIT: usize = 256 * 1024 * 1024;
+
+/// `LOCKSERVER_SECRET` when it is not set. Fine for trying the server out, refused in
+/// cluster mode.
+const DEFAULT_SECRET: &str = "changeme";
+
+#[derive(Serialize, ToSchema)]
+struct AcquireResponse {
+    /// Fencing token for this grant.
+    token: u64,
+}
+
+#[derive(Serialize, ToSchema)]
+struct BatchResponse {
+    /// One fencing token per resource, in request order.
+    tokens: Vec<u64>,
+}
+
+#[derive(Serialize, ToSchema)]
+struct ApiInfo {
+    /// The newest API version served, for clients to negotiate.
+    api: &'static str,
+    version: &'static str,
+}
+
+#[derive(Serialize, ToSchema)]
+struct OkResponse {
+    /// Always `"ok"`.
+    status: &'static str,
+}
+
+/// Body of every error response.
+#[derive(Serialize, ToSchema)]
+struct ErrorResponse {
+    /// Machine-readable, e.g. `"not_owner"`.
+    code: &'static str,
+    message: String,
+}
+
+fn error(status: StatusCode, code: &'static str, message: impl ToString) -> HttpResponse {
+    HttpResponse::build(status).json(ErrorResponse {
+        code,
+        message: message.to_string(),
+    })
+}
+
+/// Reject a request whose body or query string could not be parsed.
+fn invalid(e: impl std::fmt::Display + std::fmt::Debug + 'static) -> actix_web::Error {
+    let response = lock_error(&LockError::InvalidRequest(e.to_string()));
+    actix_web::error::InternalError::from_response(e, response).into()
+}
+
+fn unauthorized(message: &str) -> HttpResponse {
+    error(StatusCode::UNAUTHORIZED, "unauthorized", message)
+}
+
+fn not_clustered() -> HttpResponse {
+    error(
+        StatusCode::NOT_FOUND,
+        "not_clustered",
+        "Not running in cluster mode",
+    )
+}
+
+/// Report a lock error with the status that fits it: 404 for something that does not
+/// exist, 403 for a lock held by someone else, 409 while a lock is busy.
+fn lock_error(e: &LockError) -> HttpResponse {
+    let status = match e {
+        LockError::NotFound | LockError::SessionNotFound => StatusCode::NOT_FOUND,
+        LockError::NotOwner => StatusCode::FORBIDDEN,
+        LockError::AlreadyLocked | LockError::Timeout => StatusCode::CONFLICT,
+        LockError::Deadlock => StatusCode::LOCKED,
+        LockError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
+        LockError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    error(status, e.code(), e)
+}
+
+/// The OpenAPI document served at `/openapi.json`, generated from the handlers' own
+/// request and response types.
+#[derive(OpenApi)]
+#[openapi(
+    paths(
+        openapi,
+        api_info,
+        put_lock,
+        patch_lock,
+        delete_lock,
+        inspect_lock,
+        list_locks,
+        acquire_batch,
+        acquire_permits,
+        release_permits,
+        create_session,
+        heartbeat,
+        close_session,
+        force_release,
+        release_owner,
+        cluster_status,
+        acquire_lock,
+        release_lock,
+        renew_lock,
+    ),
+    modifiers(&ApiDocExtras),
+    security(("secret" = []), ("token" = [])),
+)]
+struct ApiDoc;
+
+/// Parts of [`ApiDoc`] the derive cannot express.
+struct ApiDocExtras;
+
+impl Modify for ApiDocExtras {
+    fn modify(&self, spec: &mut Spec) {
+        let components = spec.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "secret",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-LOCKSERVER-SECRET"))),
+        );
+        components.add_security_scheme(
+            "admin_secret",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new(
+                "X-LOCKSERVER-ADMIN-SECRET",
+            ))),
+        );
+        components.add_security_scheme(
+            "token",
+            SecurityScheme::Http(Http::new(HttpAuthScheme::Bearer)),
+        );
+        // The unversioned routes are kept only for older clients. Most are copies of
+        // their `/v1` counterparts.
+        for served in routes().into_iter().filter(|r| r.mount == Mount::Both) {
+            let [v1, path] = &served.documented_paths()[..] else {
+                unreachable!("a shared route has two paths");
+            };
+            let Some(item) = spec.paths.paths.get_mut(v1) else {
+                continue;
+            };
+            let Some(mut op) = operation(item, &served.method).clone() else {
+                continue;
+            };
+            op.operation_id = op.operation_id.map(|id| id + "_unversioned");
+            op.deprecated = Some(Deprecated::True);
+            let item = spec.paths.paths.entry(path.clone()).or_default();
+            *operation(item, &served.method) = Some(op);
+        }
+        for path in ["/acquire", "/release", "/renew"] {
+            if let Some(item) = spec.paths.paths.get_mut(path)
+                && let Some(op) = item.post.as_mut()
+            {
+                op.deprecated = Some(Deprecated::True);
+            }
+        }
+    }
 }
 
 fn check_secret(req: &HttpRequest, expected: &str) -> bool {
@@ -23,38 +320,924 @@ fn check_secret(req: &HttpRequest, expected: &str) -> bool {
         .unwrap_or(false)
 }
 
+/// The token a request was made with: `Authorization: Bearer <token>`, or else the
+/// `X-LOCKSERVER-SECRET` header. Admin requests may also use
+/// `X-LOCKSERVER-ADMIN-SECRET`, which takes precedence. With the shared secrets they
+/// must use it or `Authorization`: the admin secret is not taken as the regular one.
+fn token<'a>(req: &'a HttpRequest, tokens: &TokenStore, scope: Scope) -> Option<&'a str> {
+    let header = |name| req.headers().get(name).and_then(|v| v.to_str().ok());
+    let admin = header("X-LOCKSERVER-ADMIN-SECRET").filter(|_| scope == Scope::Admin);
+    let bearer = header("Authorization").and_then(|v| v.strip_prefix("Bearer "));
+    let secret =
+        header("X-LOCKSERVER-SECRET").filter(|_| scope != Scope::Admin || tokens.is_from_file());
+    admin.or(bearer).or(secret)
+}
+
+/// The grant for the request's token, or a 401 with `message` if it has no valid one.
+fn authenticate(
+    req: &HttpRequest,
+    tokens: &TokenStore,
+    message: &str,
+) -> Result<Arc<Grant>, HttpResponse> {
+    token(req, tokens, Scope::Inspect)
+        .and_then(|token| tokens.authenticate(token))
+        .ok_or_else(|| unauthorized(message))
+}
+
+/// The grant for the request's token, if it allows `scope` on each of `resources`
+/// (on no resource in particular if there are none). Otherwise the response to send:
+/// 401 without a valid token, 403 if the token does not allow the request.
+fn authorize(
+    req: &HttpRequest,
+    tokens: &TokenStore,
+    scope: Scope,
+    resources: &[&str],
+) -> Result<Arc<Grant>, HttpResponse> {
+    let grant = match token(req, tokens, scope).and_then(|token| tokens.authenticate(token)) {
+        Some(grant) => grant,
+        None if scope == Scope::Admin => {
+            return Err(unauthorized("Missing or invalid admin secret"));
+        }
+        None => return Err(unauthorized("Missing or invalid secret")),
+    };
+    let denied = match resources {
+        [] => (!grant.allows(scope, None)).then_some(None),
+        _ => resources
+            .iter()
+            .find(|r| !grant.allows(scope, Some(r)))
+            .map(Some),
+    };
+    match denied {
+        None => Ok(grant),
+        Some(resource) => Err(error(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            match resource {
+                Some(resource) => format!("{} may not {} {:?}", grant.identity, scope, resource),
+                None => format!("{} may not {}", grant.identity, scope),
+            },
+        )),
+    }
+}
+
+/// A 403 unless `grant` may use `session`, if there is one; see
+/// [`TokenStore::may_use_session`].
+fn check_session(
+    tokens: &TokenStore,
+    grant: &Grant,
+    session: Option<&str>,
+) -> Result<(), HttpResponse> {
+    match session {
+        Some(session) if !tokens.may_use_session(grant, session) => Err(error(
+            StatusCode::FORBIDDEN,
+            "forbidden",
+            format!("{} may not use session {:?}", grant.identity, session),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Settings for the `/admin` endpoints.
+struct Admin {
+    audit: AuditLog,
+}
+
+/// Where lock operations run: on this process's own lock manager, or through the
+/// Raft log when the server is part of a cluster.
+enum Backend {
+    Local(Arc<LockManager>),
+    Cluster(Arc<RaftNode>),
+}
+
+impl Backend {
+    async fn execute(&self, command: raft::Command) -> Result<Option<u64>, RaftError> {
+        match self {
+            Backend::Local(manager) => Ok(manager.apply(&command)?),
+            Backend::Cluster(node) => node.propose(command).await,
+        }
+    }
+
+    /// Lock state for read-only queries. On a follower this may lag the leader slightly.
+    fn manager(&self) -> &LockManager {
+        match self {
+            Backend::Local(manager) => manager,
+            Backend::Cluster(node) => node.manager(),
+        }
+    }
+}
+
+/// Turn the result of a lock operation into a response. Followers send clients to
+/// the leader with a redirect that keeps the method and body.
+fn respond(http_req: &HttpRequest, result: Result<Option<u64>, RaftError>) -> HttpResponse {
+    match result {
+        Ok(Some(token)) => HttpResponse::Ok().json(AcquireResponse { token }),
+        Ok(None) => HttpResponse::Ok().json(OkResponse { status: "ok" }),
+        Err(RaftError::NotLeader(Some(leader))) => HttpResponse::TemporaryRedirect()
+            .insert_header(("Location", format!("http://{}{}", leader, http_req.uri())))
+            .finish(),
+        Err(e @ RaftError::NotLeader(None)) => {
+            error(StatusCode::SERVICE_UNAVAILABLE, "no_leader", e)
+        }
+        Err(RaftError::Lock(e)) => lock_error(&e),
+    }
+}
+
+#[utoipa::path(
+    get, path = "/v1", tag = "meta",
+    responses(
+        (status = 200, description = "API version, for clients to negotiate", body = ApiInfo),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+    ),
+)]
+async fn api_info(http_req: HttpRequest, tokens: web::Data<TokenStore>) -> impl Responder {
+    if let Err(response) = authenticate(&http_req, &tokens, "Missing or invalid secret") {
+        return response;
+    }
+    HttpResponse::Ok().json(ApiInfo {
+        api: "v1",
+        version: env!("CARGO_PKG_VERSION"),
+    })
+}
+
+#[utoipa::path(
+    put, path = "/v1/locks/{resource}", tag = "locks",
+    params(("resource" = String, Path, description = "Name of the lock")),
+    request_body = AcquireRequest,
+    responses(
+        (status = 200, description = "Granted", body = AcquireResponse),
+        (status = 400, description = "Malformed request", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The token does not allow this request", body = ErrorResponse),
+        (status = 409, description = "The lock is held, or was still held when `wait_timeout` ran out", body = ErrorResponse),
+        (status = 423, description = "Waiting would deadlock", body = ErrorResponse),
+    ),
+)]
+async fn put_lock(
+    backend: web::Data<Backend>,
+    resource: web::Path<String>,
+    req: web::Json<AcquireRequest>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let grant = match authorize(&http_req, &tokens, Scope::Acquire, &[&resource]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    if let Err(response) = check_session(&tokens, &grant, req.session.as_deref()) {
+        return response;
+    }
+    let owner = tokens.owner(&grant, &req.owner);
+    acquire(&backend, &resource, &owner, &req, &http_req).await
+}
+
+#[utoipa::path(
+    post, path = "/acquire", tag = "locks",
+    request_body = LockRequest,
+    responses(
+        (status = 200, description = "Granted", body = AcquireResponse),
+        (status = 400, description = "Malformed request", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The token does not allow this request", body = ErrorResponse),
+        (status = 409, description = "The lock is held, or was still held when `wait_timeout` ran out", body = ErrorResponse),
+        (status = 423, description = "Waiting would deadlock", body = ErrorResponse),
+    ),
+)]
 async fn acquire_lock(
-    data: web::Data<Arc<StdMutex<LockManager>>>,
+    backend: web::Data<Backend>,
     req: web::Json<LockRequest>,
     http_req: HttpRequest,
-    secret: web::Data<String>,
+    tokens: web::Data<TokenStore>,
 ) -> impl Responder {
-    if !check_secret(&http_req, &secret) {
-        return HttpResponse::Unauthorized().body("Missing or invalid secret");
+    let grant = match authorize(&http_req, &tokens, Scope::Acquire, &[&req.resource]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    if let Err(response) = check_session(&tokens, &grant, req.lock.session.as_deref()) {
+        return response;
+    }
+    let owner = tokens.owner(&grant, &req.lock.owner);
+    acquire(&backend, &req.resource, &owner, &req.lock, &http_req).await
+}
+
+/// Acquire `resource` for `owner`, the owner claimed in `req` as namespaced by
+/// [`TokenStore::owner`].
+async fn acquire(
+    backend: &Backend,
+    resource: &str,
+    owner: &str,
+    req: &AcquireRequest,
+    http_req: &HttpRequest,
+) -> HttpResponse {
+    // Nesting a hold never waits; the owner already has the lock.
+    let nested = req.reentrant
+        && backend.manager().inspect(resource).is_some_and(|status| {
+            status.holders.iter().any(|h| h.owner == owner)
+                && (status.kind == LockKind::Exclusive || req.kind == LockKind::Shared)
+        });
+    let wait = req.wait_timeout.filter(|_| !nested);
+    let result = match (backend, wait) {
+        // Long-poll: parks as a future, so waiting holds no worker thread.
+        (Backend::Local(manager), Some(wait)) => {
+            let ttl = ttl(req.expire, req.expire_ms);
+            let session = req.session.as_deref();
+            let timeout = Duration::from_secs(wait);
+            match &req.ticket {
+                Some(ticket) => {
+                    let poll = LongPoll { ticket, timeout };
+                    manager
+                        .acquire_long_poll(resource, owner, req.kind, ttl, session, poll)
+                        .await
+                }
+                None => {
+                    manager
+                        .acquire_async(resource, owner, req.kind, ttl, session, timeout)
+                        .await
+                }
+            }
+            .map(Some)
+            .map_err(RaftError::from)
+        }
+        (Backend::Cluster(node), Some(wait)) => {
+            node.acquire_wait(
+                resource,
+                owner,
+                req.kind,
+                ttl(req.expire, req.expire_ms),
+                req.session.as_deref(),
+                Duration::from_secs(wait),
+            )
+            .await
+        }
+        (backend, None) => {
+            let command = raft::Command::acquire(
+                resource,
+                owner,
+                req.kind,
+                ttl(req.expire, req.expire_ms),
+                req.session.as_deref(),
+                req.reentrant,
+            );
+            match command {
+                Ok(command) => backend.execute(command).await,
+                Err(e) => Err(e.into()),
+            }
+        }
+    };
+    respond(http_req, result)
+}
+
+#[utoipa::path(
+    post, path = "/v1/acquire-batch", tag = "locks",
+    request_body = BatchRequest,
+    responses(
+        (status = 200, description = "Every lock granted", body = BatchResponse),
+        (status = 400, description = "Malformed request", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The token does not allow this request", body = ErrorResponse),
+        (status = 409, description = "One of the locks is held", body = ErrorResponse),
+    ),
+)]
+async fn acquire_batch(
+    backend: web::Data<Backend>,
+    req: web::Json<BatchRequest>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let resources: Vec<&str> = req.resources.iter().map(String::as_str).collect();
+    let grant = match authorize(&http_req, &tokens, Scope::Acquire, &resources) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    if let Err(response) = check_session(&tokens, &grant, req.session.as_deref()) {
+        return response;
     }
-    let manager = data.lock().unwrap();
-    match manager.acquire(&req.resource, &req.owner, req.expire) {
-        Ok(()) => HttpResponse::Ok().body("OK"),
-        Err(e) => HttpResponse::Conflict().body(format!("ERR {}", e)),
+    let ttl = ttl(req.expire, req.expire_ms);
+    let owner = tokens.owner(&grant, &req.owner);
+    let command = match raft::Command::acquire_many(&resources, &owner, ttl, req.session.as_deref())
+    {
+        Ok(command) => command,
+        Err(e) => return lock_error(&e),
+    };
+    match backend.execute(command).await {
+        // Batch tokens are consecutive, starting from the one returned.
+        Ok(Some(first)) => HttpResponse::Ok().json(BatchResponse {
+            tokens: (first..first + resources.len() as u64).collect(),
+        }),
+        result => respond(&http_req, result),
     }
 }
 
+#[utoipa::path(
+    delete, path = "/v1/locks/{resource}", tag = "locks",
+    params(("resource" = String, Path, description = "Name of the lock"), OwnerQuery),
+    responses(
+        (status = 200, description = "Released", body = OkResponse),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The lock is held by another owner, or the token does not allow this request", body = ErrorResponse),
+        (status = 404, description = "The resource is not locked", body = ErrorResponse),
+    ),
+)]
+async fn delete_lock(
+    backend: web::Data<Backend>,
+    resource: web::Path<String>,
+    query: web::Query<OwnerQuery>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let grant = match authorize(&http_req, &tokens, Scope::Release, &[&resource]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    let command = raft::Command::release(&resource, &tokens.owner(&grant, &query.owner));
+    respond(&http_req, backend.execute(command).await)
+}
+
+#[utoipa::path(
+    post, path = "/release", tag = "locks",
+    request_body = LockRequest,
+    responses(
+        (status = 200, description = "Released", body = OkResponse),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The lock is held by another owner, or the token does not allow this request", body = ErrorResponse),
+        (status = 404, description = "The resource is not locked", body = ErrorResponse),
+    ),
+)]
 async fn release_lock(
-    data: web::Data<Arc<StdMutex<LockManager>>>,
+    backend: web::Data<Backend>,
     req: web::Json<LockRequest>,
     http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let grant = match authorize(&http_req, &tokens, Scope::Release, &[&req.resource]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    let command = raft::Command::release(&req.resource, &tokens.owner(&grant, &req.lock.owner));
+    respond(&http_req, backend.execute(command).await)
+}
+
+#[utoipa::path(
+    patch, path = "/v1/locks/{resource}", tag = "locks",
+    params(("resource" = String, Path, description = "Name of the lock")),
+    request_body = RenewRequest,
+    responses(
+        (status = 200, description = "Renewed", body = OkResponse),
+        (status = 400, description = "Malformed request", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The lock is held by another owner, or the token does not allow this request", body = ErrorResponse),
+        (status = 404, description = "The resource is not locked", body = ErrorResponse),
+    ),
+)]
+async fn patch_lock(
+    backend: web::Data<Backend>,
+    resource: web::Path<String>,
+    req: web::Json<RenewRequest>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let grant = match authorize(&http_req, &tokens, Scope::Acquire, &[&resource]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    let owner = tokens.owner(&grant, &req.owner);
+    renew(&backend, &resource, &owner, &req, &http_req).await
+}
+
+#[utoipa::path(
+    post, path = "/renew", tag = "locks",
+    request_body = LegacyRenewRequest,
+    responses(
+        (status = 200, description = "Renewed", body = OkResponse),
+        (status = 400, description = "Malformed request", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The lock is held by another owner, or the token does not allow this request", body = ErrorResponse),
+        (status = 404, description = "The resource is not locked", body = ErrorResponse),
+    ),
+)]
+async fn renew_lock(
+    backend: web::Data<Backend>,
+    req: web::Json<LegacyRenewRequest>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let grant = match authorize(&http_req, &tokens, Scope::Acquire, &[&req.resource]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    let owner = tokens.owner(&grant, &req.renew.owner);
+    renew(&backend, &req.resource, &owner, &req.renew, &http_req).await
+}
+
+async fn renew(
+    backend: &Backend,
+    resource: &str,
+    owner: &str,
+    req: &RenewRequest,
+    http_req: &HttpRequest,
+) -> HttpResponse {
+    let Some(ttl) = ttl(req.expire, req.expire_ms) else {
+        return lock_error(&LockError::InvalidRequest(
+            "expire or expire_ms is required".to_string(),
+        ));
+    };
+    match raft::Command::renew(resource, owner, ttl) {
+        Ok(command) => respond(http_req, backend.execute(command).await),
+        Err(e) => lock_error(&e),
+    }
+}
+
+#[utoipa::path(
+    post, path = "/v1/semaphore/acquire", tag = "semaphores",
+    request_body = SemaphoreRequest,
+    responses(
+        (status = 200, description = "Granted", body = AcquireResponse),
+        (status = 400, description = "Malformed request", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The token does not allow this request", body = ErrorResponse),
+        (status = 409, description = "Not enough permits are free", body = ErrorResponse),
+    ),
+)]
+async fn acquire_permits(
+    backend: web::Data<Backend>,
+    req: web::Json<SemaphoreRequest>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let grant = match authorize(&http_req, &tokens, Scope::Acquire, &[&req.resource]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    let command = raft::Command::acquire_permits(
+        &req.resource,
+        &tokens.owner(&grant, &req.owner),
+        req.permits,
+        req.limit,
+        req.expire,
+    );
+    match command {
+        Ok(command) => respond(&http_req, backend.execute(command).await),
+        Err(e) => lock_error(&e),
+    }
+}
+
+#[utoipa::path(
+    post, path = "/v1/semaphore/release", tag = "semaphores",
+    request_body = SemaphoreRequest,
+    responses(
+        (status = 200, description = "Returned", body = OkResponse),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The owner holds no permits, or the token does not allow this request", body = ErrorResponse),
+        (status = 404, description = "The semaphore is not in use", body = ErrorResponse),
+    ),
+)]
+async fn release_permits(
+    backend: web::Data<Backend>,
+    req: web::Json<SemaphoreRequest>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let grant = match authorize(&http_req, &tokens, Scope::Release, &[&req.resource]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    let command = raft::Command::release_permits(&req.resource, &tokens.owner(&grant, &req.owner));
+    respond(&http_req, backend.execute(command).await)
+}
+
+#[utoipa::path(
+    post, path = "/v1/session", tag = "sessions",
+    request_body = CreateSessionRequest,
+    responses(
+        (status = 200, description = "Opened", body = SessionResponse),
+        (status = 400, description = "The ttl is zero or too long", body = ErrorResponse),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The token does not allow this request", body = ErrorResponse),
+    ),
+)]
+async fn create_session(
+    backend: web::Data<Backend>,
+    req: web::Json<CreateSessionRequest>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let grant = match authorize(&http_req, &tokens, Scope::Acquire, &[]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    let mut command = match raft::Command::create_session(req.ttl) {
+        Ok(command) => command,
+        Err(e) => return lock_error(&e),
+    };
+    let raft::Command::CreateSession { session, .. } = &mut command else {
+        unreachable!("create_session builds a CreateSession command");
+    };
+    // Bind the session to the identity that opened it.
+    *session = tokens.session(&grant, session);
+    let session = session.clone();
+    match backend.execute(command).await {
+        Ok(_) => HttpResponse::Ok().json(SessionResponse { session }),
+        result => respond(&http_req, result),
+    }
+}
+
+#[utoipa::path(
+    post, path = "/v1/session/heartbeat", tag = "sessions",
+    request_body = SessionRequest,
+    responses(
+        (status = 200, description = "Kept alive", body = OkResponse),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The token does not allow this request, or the session was opened by another identity", body = ErrorResponse),
+        (status = 404, description = "The session has ended", body = ErrorResponse),
+    ),
+)]
+async fn heartbeat(
+    backend: web::Data<Backend>,
+    req: web::Json<SessionRequest>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let grant = match authorize(&http_req, &tokens, Scope::Acquire, &[]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    if let Err(response) = check_session(&tokens, &grant, Some(&req.session)) {
+        return response;
+    }
+    let command = raft::Command::heartbeat(&req.session);
+    respond(&http_req, backend.execute(command).await)
+}
+
+#[utoipa::path(
+    post, path = "/v1/session/close", tag = "sessions",
+    request_body = SessionRequest,
+    responses(
+        (status = 200, description = "Closed, and its locks released", body = OkResponse),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The token does not allow this request, or the session was opened by another identity", body = ErrorResponse),
+        (status = 404, description = "The session has ended", body = ErrorResponse),
+    ),
+)]
+async fn close_session(
+    backend: web::Data<Backend>,
+    req: web::Json<SessionRequest>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let grant = match authorize(&http_req, &tokens, Scope::Acquire, &[]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    if let Err(response) = check_session(&tokens, &grant, Some(&req.session)) {
+        return response;
+    }
+    let command = raft::Command::close_session(&req.session);
+    respond(&http_req, backend.execute(command).await)
+}
+
+/// Run an admin `command` and record it, with its outcome, in the audit trail.
+async fn run_admin(
+    backend: &Backend,
+    admin: &Admin,
+    http_req: &HttpRequest,
+    command: raft::Command,
+    mut entry: AuditEntry,
+) -> HttpResponse {
+    let result = backend.execute(command).await;
+    entry.outcome = match &result {
+        Ok(_) => "ok".to_string(),
+        Err(e) => format!("ERR {}", e),
+    };
+    if let Err(e) = admin.audit.record(&entry) {
+        eprintln!("Failed to write audit log: {}", e);
+    }
+    respond(http_req, result)
+}
+
+#[utoipa::path(
+    post, path = "/v1/admin/force-release", tag = "admin",
+    request_body = ForceReleaseRequest,
+    responses(
+        (status = 200, description = "Released", body = OkResponse),
+        (status = 401, description = "Missing or invalid admin secret", body = ErrorResponse),
+        (status = 403, description = "The token does not allow this request", body = ErrorResponse),
+        (status = 404, description = "The resource is not locked", body = ErrorResponse),
+    ),
+    security(("admin_secret" = []), ("token" = [])),
+)]
+async fn force_release(
+    backend: web::Data<Backend>,
+    admin: web::Data<Admin>,
+    req: web::Json<ForceReleaseRequest>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let grant = match authorize(&http_req, &tokens, Scope::Admin, &[&req.resource]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    let mut entry = AuditEntry::new("force_release", client_addr(&http_req));
+    entry.identity = Some(grant.identity.clone());
+    entry.resource = Some(req.resource.clone());
+    let command = raft::Command::force_release(&req.resource);
+    run_admin(&backend, &admin, &http_req, command, entry).await
+}
+
+#[utoipa::path(
+    post, path = "/v1/admin/release-owner", tag = "admin",
+    request_body = ReleaseOwnerRequest,
+    responses(
+        (status = 200, description = "Released", body = OkResponse),
+        (status = 401, description = "Missing or invalid admin secret", body = ErrorResponse),
+        (status = 403, description = "The token does not allow this request", body = ErrorResponse),
+    ),
+    security(("admin_secret" = []), ("token" = [])),
+)]
+async fn release_owner(
+    backend: web::Data<Backend>,
+    admin: web::Data<Admin>,
+    req: web::Json<ReleaseOwnerRequest>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let grant = match authorize(&http_req, &tokens, Scope::Admin, &[]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    let mut entry = AuditEntry::new("release_owner", client_addr(&http_req));
+    entry.identity = Some(grant.identity.clone());
+    entry.owner = Some(req.owner.clone());
+    let command = raft::Command::release_owner(&req.owner);
+    run_admin(&backend, &admin, &http_req, command, entry).await
+}
+
+fn client_addr(req: &HttpRequest) -> Option<String> {
+    req.peer_addr().map(|addr| addr.to_string())
+}
+
+#[utoipa::path(
+    get, path = "/v1/locks", tag = "locks",
+    params(ListQuery),
+    responses(
+        (status = 200, description = "One page of locks, in name order", body = LockPage),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The token does not allow this request", body = ErrorResponse),
+    ),
+)]
+async fn list_locks(
+    backend: web::Data<Backend>,
+    query: web::Query<ListQuery>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    let grant = match authorize(&http_req, &tokens, Scope::Inspect, &[]) {
+        Ok(grant) => grant,
+        Err(response) => return response,
+    };
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let mut page = backend
+        .manager()
+        .list(&query.prefix, query.after.as_deref(), limit);
+    // Pages may come back short, but `next` still continues after the last lock seen.
+    page.locks.retain(|lock| grant.covers(&lock.resource));
+    HttpResponse::Ok().json(page)
+}
+
+#[utoipa::path(
+    get, path = "/v1/locks/{resource}", tag = "locks",
+    params(("resource" = String, Path, description = "Name of the lock")),
+    responses(
+        (status = 200, description = "Who holds the lock", body = LockStatus),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The token does not allow this request", body = ErrorResponse),
+        (status = 404, description = "The resource is not locked", body = ErrorResponse),
+    ),
+)]
+async fn inspect_lock(
+    backend: web::Data<Backend>,
+    resource: web::Path<String>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    if let Err(response) = authorize(&http_req, &tokens, Scope::Inspect, &[&resource]) {
+        return response;
+    }
+    match backend.manager().inspect(&resource) {
+        Some(status) => HttpResponse::Ok().json(status),
+        None => lock_error(&LockError::NotFound),
+    }
+}
+
+#[utoipa::path(
+    get, path = "/openapi.json", tag = "meta",
+    responses(
+        (status = 200, description = "This document", content_type = "application/json"),
+    ),
+    security(()),
+)]
+async fn openapi() -> impl Responder {
+    HttpResponse::Ok().json(ApiDoc::openapi())
+}
+
+async fn raft_vote(
+    backend: web::Data<Backend>,
+    req: web::Json<VoteRequest>,
+    http_req: HttpRequest,
     secret: web::Data<String>,
 ) -> impl Responder {
     if !check_secret(&http_req, &secret) {
-        return HttpResponse::Unauthorized().body("Missing or invalid secret");
+        return unauthorized("Missing or invalid secret");
     }
-    let manager = data.lock().unwrap();
-    match manager.release(&req.resource, &req.owner) {
-        Ok(()) => HttpResponse::Ok().body("OK"),
-        Err(e) => HttpResponse::Conflict().body(format!("ERR {}", e)),
+    match backend.get_ref() {
+        Backend::Cluster(node) => HttpResponse::Ok().json(node.handle_vote(req.into_inner())),
+        Backend::Local(_) => not_clustered(),
     }
 }
 
+async fn raft_append(
+    backend: web::Data<Backend>,
+    req: web::Json<AppendRequest>,
+    http_req: HttpRequest,
+    secret: web::Data<String>,
+) -> impl Responder {
+    if !check_secret(&http_req, &secret) {
+        return unauthorized("Missing or invalid secret");
+    }
+    match backend.get_ref() {
+        Backend::Cluster(node) => HttpResponse::Ok().json(node.handle_append(req.into_inner())),
+        Backend::Local(_) => not_clustered(),
+    }
+}
+
+async fn raft_snapshot(
+    backend: web::Data<Backend>,
+    req: web::Json<SnapshotRequest>,
+    http_req: HttpRequest,
+    secret: web::Data<String>,
+) -> impl Responder {
+    if !check_secret(&http_req, &secret) {
+        return unauthorized("Missing or invalid secret");
+    }
+    match backend.get_ref() {
+        Backend::Cluster(node) => HttpResponse::Ok().json(node.handle_snapshot(req.into_inner())),
+        Backend::Local(_) => not_clustered(),
+    }
+}
+
+#[utoipa::path(
+    get, path = "/cluster/status", tag = "cluster",
+    responses(
+        (status = 200, description = "This node's view of the cluster", body = raft::Status),
+        (status = 401, description = "Missing or invalid secret", body = ErrorResponse),
+        (status = 403, description = "The token does not allow this request", body = ErrorResponse),
+        (status = 404, description = "Not running in cluster mode", body = ErrorResponse),
+    ),
+)]
+async fn cluster_status(
+    backend: web::Data<Backend>,
+    http_req: HttpRequest,
+    tokens: web::Data<TokenStore>,
+) -> impl Responder {
+    if let Err(response) = authorize(&http_req, &tokens, Scope::Inspect, &[]) {
+        return response;
+    }
+    match backend.get_ref() {
+        Backend::Cluster(node) => HttpResponse::Ok().json(node.status()),
+        Backend::Local(_) => not_clustered(),
+    }
+}
+
+/// Where a route is served: under `/v1`, without a prefix (for older clients), or both.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mount {
+    V1,
+    Root,
+    Both,
+}
+
+/// A route of the API: where it is mounted, its method and path pattern, and its handler.
+struct Served {
+    mount: Mount,
+    method: Method,
+    path: &'static str,
+    route: Route,
+}
+
+impl Served {
+    fn new<F, Args>(mount: Mount, method: Method, path: &'static str, handler: F) -> Self
+    where
+        F: Handler<Args>,
+        Args: FromRequest + 'static,
+        F::Output: Responder + 'static,
+    {
+        Self {
+            mount,
+            route: web::method(method.clone()).to(handler),
+            method,
+            path,
+        }
+    }
+
+    /// The paths the route is served at, as [`ApiDoc`] writes them.
+    fn documented_paths(&self) -> Vec<String> {
+        let path = self.path.replace(":.+", "");
+        match self.mount {
+            Mount::V1 => vec![format!("/v1{}", path)],
+            Mount::Root => vec![path],
+            Mount::Both => vec![format!("/v1{}", path), path],
+        }
+    }
+}
+
+/// Every route of the API. The app serves exactly these, besides the Raft RPCs between
+/// cluster nodes; [`ApiDoc`] documents the unversioned copies of the shared ones from
+/// this same list, and `--routes` prints it.
+fn routes() -> Vec<Served> {
+    use Mount::{Both, Root, V1};
+    vec![
+        Served::new(Root, Method::GET, "/openapi.json", openapi),
+        Served::new(V1, Method::GET, "", api_info),
+        Served::new(Both, Method::GET, "/locks", list_locks),
+        Served::new(Both, Method::GET, "/locks/{resource:.+}", inspect_lock),
+        Served::new(V1, Method::PUT, "/locks/{resource:.+}", put_lock),
+        Served::new(V1, Method::PATCH, "/locks/{resource:.+}", patch_lock),
+        Served::new(V1, Method::DELETE, "/locks/{resource:.+}", delete_lock),
+        Served::new(Both, Method::POST, "/acquire-batch", acquire_batch),
+        Served::new(Both, Method::POST, "/semaphore/acquire", acquire_permits),
+        Served::new(Both, Method::POST, "/semaphore/release", release_permits),
+        Served::new(Both, Method::POST, "/session", create_session),
+        Served::new(Both, Method::POST, "/session/heartbeat", heartbeat),
+        Served::new(Both, Method::POST, "/session/close", close_session),
+        Served::new(Both, Method::POST, "/admin/force-release", force_release),
+        Served::new(Both, Method::POST, "/admin/release-owner", release_owner),
+        Served::new(Root, Method::POST, "/acquire", acquire_lock),
+        Served::new(Root, Method::POST, "/release", release_lock),
+        Served::new(Root, Method::POST, "/renew", renew_lock),
+        Served::new(Root, Method::GET, "/cluster/status", cluster_status),
+    ]
+}
+
+fn serve_routes(cfg: &mut web::ServiceConfig) {
+    let mut v1 = web::scope("/v1");
+    for served in routes().into_iter().filter(|r| r.mount != Mount::Root) {
+        v1 = v1.route(served.path, served.route);
+    }
+    cfg.service(v1);
+    for served in routes().into_iter().filter(|r| r.mount != Mount::V1) {
+        cfg.route(served.path, served.route);
+    }
+}
+
+/// Internal: the operation `item` documents for `method`.
+fn operation<'a>(item: &'a mut PathItem, method: &Method) -> &'a mut Option<Operation> {
+    match *method {
+        Method::GET => &mut item.get,
+        Method::PUT => &mut item.put,
+        Method::POST => &mut item.post,
+        Method::PATCH => &mut item.patch,
+        Method::DELETE => &mut item.delete,
+        _ => unreachable!("no route is served for {}", method),
+    }
+}
+
+/// Re-read the token file on SIGHUP.
+async fn reload_on_signal(tokens: web::Data<TokenStore>) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let Ok(mut hangup) = signal(SignalKind::hangup()) else {
+            return;
+        };
+        while hangup.recv().await.is_some() {
+            match tokens.reload() {
+                Ok(count) => println!("Reloaded {} API token(s)", count),
+                Err(e) => eprintln!("Keeping current API tokens: {}", e),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokens;
+}
+
+/// Wait for SIGTERM or SIGINT, then stop `server` gracefully: it stops accepting
+/// connections and lets in-flight requests finish.
+async fn stop_on_signal(server: ServerHandle) {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let Ok(mut term) = signal(SignalKind::terminate()) else {
+            return;
+        };
+        tokio::select! {
+            _ = term.recv() => {}
+            _ = tokio::signal::ctrl_c() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+    println!("Shutting down: draining in-flight requests");
+    server.stop(true).await;
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     // Load .env file if present
@@ -76,15 +1259,79 @@ async fn main() -> std::io::Result<()> {
                 .value_name("PORT")
                 .help("HTTP API port (default: 8080)"),
         )
+        .arg(
+            Arg::new("data-dir")
+                .long("data-dir")
+                .value_name("DIR")
+                .help(
+                    "Persist lock state in DIR and restore it on startup (default: in-memory only; \
+                     required in cluster mode)",
+                ),
+        )
+        .arg(
+            Arg::new("audit-log")
+                .long("audit-log")
+                .value_name("FILE")
+                .help("Append admin actions to FILE as JSON lines (default: stdout only)"),
+        )
+        .arg(
+            Arg::new("tokens")
+                .long("tokens")
+                .value_name("FILE")
+                .help("Accept the API tokens listed in FILE instead of the shared secrets"),
+        )
+        .arg(
+            Arg::new("node-id")
+                .long("node-id")
+                .value_name("ID")
+                .help("This server's id in the cluster given by --peers"),
+        )
+        .arg(
+            Arg::new("peers")
+                .long("peers")
+                .value_name("ID=HOST:PORT,...")
+                .help("Every node in the cluster, including this one (default: standalone)"),
+        )
+        .arg(
+            Arg::new("routes")
+                .long("routes")
+                .action(ArgAction::SetTrue)
+                .help("Print the API's routes, one `METHOD PATH` per line, and exit"),
+        )
+        .arg(
+            Arg::new("release-on-shutdown")
+                .long("release-on-shutdown")
+                .action(ArgAction::SetTrue)
+                .help(
+                    "Release every lock on SIGTERM/SIGINT instead of keeping it for the next run",
+                ),
+        )
         .get_matches();
 
+    if matches.get_flag("routes") {
+        for served in routes() {
+            for path in served.documented_paths() {
+                println!("{} {}", served.method, path);
+            }
+        }
+        return Ok(());
+    }
+
     // Load from env first, then override with CLI args if present
     let mut bind_ip = env::var("LOCKSERVER_BIND_IP").unwrap_or_else(|_| "0.0.0.0".to_string());
     let mut http_port = env::var("LOCKSERVER_PORT")
         .ok()
         .and_then(|s| s.parse().ok())
         .unwrap_or(8080);
-    let secret = env::var("LOCKSERVER_SECRET").unwrap_or_else(|_| "changeme".to_string());
+    let secret = env::var("LOCKSERVER_SECRET").unwrap_or_else(|_| DEFAULT_SECRET.to_string());
+    let mut data_dir = env::var("LOCKSERVER_DATA_DIR").ok();
+    let admin_secret = env::var("LOCKSERVER_ADMIN_SECRET").ok();
+    let mut audit_log = env::var("LOCKSERVER_AUDIT_LOG").ok();
+    let mut tokens_file = env::var("LOCKSERVER_TOKENS").ok();
+    let mut node_id = env::var("LOCKSERVER_NODE_ID").ok();
+    let mut peers = env::var("LOCKSERVER_PEERS").ok();
+    let release_on_shutdown = matches.get_flag("release-on-shutdown")
+        || env::var("LOCKSERVER_RELEASE_ON_SHUTDOWN").is_ok_and(|v| v == "1" || v == "true");
 
     if let Some(cli_bind) = matches.get_one::<String>("bind") {
         bind_ip = cli_bind.clone();
@@ -94,22 +1341,142 @@ async fn main() -> std::io::Result<()> {
     {
         http_port = port;
     }
+    if let Some(cli_data_dir) = matches.get_one::<String>("data-dir") {
+        data_dir = Some(cli_data_dir.clone());
+    }
+    if let Some(cli_audit_log) = matches.get_one::<String>("audit-log") {
+        audit_log = Some(cli_audit_log.clone());
+    }
+    if let Some(cli_tokens) = matches.get_one::<String>("tokens") {
+        tokens_file = Some(cli_tokens.clone());
+    }
+    if let Some(cli_node_id) = matches.get_one::<String>("node-id") {
+        node_id = Some(cli_node_id.clone());
+    }
+    if let Some(cli_peers) = matches.get_one::<String>("peers") {
+        peers = Some(cli_peers.clone());
+    }
     // Optionally allow CLI arg for secret in future
 
-    let http_manager = Arc::new(StdMutex::new(LockManager::new()));
+    let backend = web::Data::new(match (peers, node_id) {
+        (Some(peers), Some(id)) => {
+            let Some(data_dir) = data_dir else {
+                return Err(std::io::Error::other(
+                    "cluster mode requires --data-dir to keep the node's Raft log in",
+                ));
+            };
+            // Nodes trust each other's Raft RPCs on this secret alone, whatever
+            // authorizes API requests.
+            if secret.is_empty() || secret == DEFAULT_SECRET {
+                return Err(std::io::Error::other(
+                    "cluster mode requires LOCKSERVER_SECRET, which the nodes authenticate \
+                     each other with, to be set to something other than the default",
+                ));
+            }
+            let nodes = ClusterConfig::parse_nodes(&peers).map_err(std::io::Error::other)?;
+            let id = id
+                .parse()
+                .map_err(|_| std::io::Error::other(format!("invalid node id {:?}", id)))?;
+            if !nodes.contains_key(&id) {
+                return Err(std::io::Error::other(format!(
+                    "node id {} is not listed in --peers",
+                    id
+                )));
+            }
+            println!("Joining cluster as node {} of {}", id, nodes.len());
+            println!("Keeping the Raft log in {}", data_dir);
+            Backend::Cluster(RaftNode::start(ClusterConfig {
+                id,
+                nodes,
+                secret: secret.clone(),
+                data_dir: Some(data_dir.into()),
+            })?)
+        }
+        (Some(_), None) | (None, Some(_)) => {
+            return Err(std::io::Error::other(
+                "--node-id and --peers must be given together",
+            ));
+        }
+        (None, None) => Backend::Local(Arc::new(match &data_dir {
+            Some(dir) => {
+                println!("Persisting lock state in {}", dir);
+                LockManager::with_data_dir(dir)?
+            }
+            None => LockManager::with_async_expiry(),
+        })),
+    });
+    let tokens = web::Data::new(match &tokens_file {
+        Some(path) => {
+            let tokens = TokenStore::from_file(path)?;
+            println!("Loaded {} API token(s) from {}", tokens.len(), path);
+            tokens
+        }
+        None => {
+            if admin_secret.is_none() {
+                println!("Admin API disabled (set LOCKSERVER_ADMIN_SECRET to enable)");
+            }
+            TokenStore::shared(&secret, admin_secret.as_deref())
+        }
+    });
+    let admin = web::Data::new(Admin {
+        audit: match &audit_log {
+            Some(path) => AuditLog::with_file(path)?,
+            None => AuditLog::stdout(),
+        },
+    });
     let http_addr = (bind_ip.as_str(), http_port);
     println!(
         "Lockserver HTTP listening on {}:{} (secret required)",
         bind_ip, http_port
     );
-    HttpServer::new(move || {
-        App::new()
-            .app_data(web::Data::new(http_manager.clone()))
-            .app_data(web::Data::new(secret.clone()))
-            .route("/acquire", web::post().to(acquire_lock))
-            .route("/release", web::post().to(release_lock))
+    let server = HttpServer::new({
+        let backend = backend.clone();
+        let tokens = tokens.clone();
+        move || {
+            let raft_body = web::JsonConfig::default()
+                .limit(RAFT_BODY_LIMIT)
+                .error_handler(|e, _| invalid(e));
+            App::new()
+                .app_data(backend.clone())
+                .app_data(admin.clone())
+                .app_data(tokens.clone())
+                .app_data(web::Data::new(secret.clone()))
+                .app_data(web::JsonConfig::default().error_handler(|e, _| invalid(e)))
+                .app_data(web::QueryConfig::default().error_handler(|e, _| invalid(e)))
+                .configure(serve_routes)
+                .route(raft::VOTE_PATH, web::post().to(raft_vote))
+                .service(
+                    web::resource(raft::APPEND_PATH)
+                        .app_data(raft_body.clone())
+                        .route(web::post().to(raft_append)),
+                )
+                .service(
+                    web::resource(raft::SNAPSHOT_PATH)
+                        .app_data(raft_body.clone())
+                        .route(web::post().to(raft_snapshot)),
+                )
+        }
     })
+    .disable_signals()
     .bind(http_addr)?
-    .run()
-    .await
+    .run();
+    actix_web::rt::spawn(stop_on_signal(server.handle()));
+    actix_web::rt::spawn(reload_on_signal(tokens));
+    server.await?;
+
+    match backend.get_ref() {
+        Backend::Local(manager) => {
+            if release_on_shutdown {
+                let released = manager.clear().map_err(std::io::Error::other)?;
+                println!("Released {} lock(s)", released);
+            }
+            manager.shutdown()
+        }
+        Backend::Cluster(_) => {
+            if release_on_shutdown {
+                println!("--release-on-shutdown is ignored in cluster mode");
+            }
+            Ok(())
+        }
+    }
 }
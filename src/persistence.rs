@@ -0,0 +1,379 @@
+//! # persistence
+//!
+//! Durable storage for lock manager state.
+//!
+//! Every state change is appended to a write-ahead log (`wal.log`) in the data directory
+//! before it takes effect. From time to time the full state is written to
+//! `snapshot.json` and the log is truncated. On startup the snapshot is loaded and the
+//! log replayed on top of it.
+//!
+//! A cluster node keeps its Raft state in the data directory instead: its term and vote
+//! (`raft_vote.json`), its log (`raft.log`), and the snapshot the log was last compacted
+//! into (`raft_snapshot.json`).
+
+use crate::lock_manager::{LockInfo, LockKind, SemaphoreInfo, SessionInfo};
+use crate::raft::{Entry, NodeId};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+const WAL_FILE: &str = "wal.log";
+const SNAPSHOT_FILE: &str = "snapshot.json";
+const VOTE_FILE: &str = "raft_vote.json";
+const RAFT_LOG_FILE: &str = "raft.log";
+const RAFT_SNAPSHOT_FILE: &str = "raft_snapshot.json";
+
+/// Number of log records after which a new snapshot is due.
+const SNAPSHOT_EVERY: u64 = 1000;
+
+/// A state change recorded in the write-ahead log.
+///
+/// Times are absolute unix timestamps in milliseconds, so they can be checked against
+/// the wall clock when the log is replayed after a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub(crate) enum Event {
+    Acquire {
+        resource: String,
+        owner: String,
+        kind: LockKind,
+        acquired_at: u64,
+        expire_at: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+        token: u64,
+    },
+    /// Exclusive holds on several resources, granted together with consecutive
+    /// tokens starting at `first_token`.
+    AcquireMany {
+        resources: Vec<String>,
+        owner: String,
+        acquired_at: u64,
+        expire_at: Option<u64>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session: Option<String>,
+        first_token: u64,
+    },
+    /// Another nested hold by an owner that already holds `resource`.
+    Reenter {
+        resource: String,
+        owner: String,
+        token: u64,
+    },
+    /// Drops one hold; the lock is freed once the owner's last nested hold is released.
+    Release {
+        resource: String,
+        owner: String,
+    },
+    /// Drops the owner's hold however many times it was entered.
+    ReleaseAll {
+        resource: String,
+        owner: String,
+    },
+    Renew {
+        resource: String,
+        owner: String,
+        expire_at: u64,
+    },
+    Expire {
+        resource: String,
+        owner: String,
+    },
+    AcquirePermits {
+        resource: String,
+        owner: String,
+        permits: u32,
+        limit: u32,
+        expire_at: Option<u64>,
+        token: u64,
+    },
+    ReleasePermits {
+        resource: String,
+        owner: String,
+    },
+    ExpirePermits {
+        resource: String,
+        owner: String,
+    },
+    CreateSession {
+        session: String,
+        ttl: u64,
+        expire_at: u64,
+    },
+    Heartbeat {
+        session: String,
+        expire_at: u64,
+    },
+    CloseSession {
+        session: String,
+    },
+    ExpireSession {
+        session: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    seq: u64,
+    event: Event,
+}
+
+/// Full lock manager state as of log record `seq`.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct Snapshot {
+    pub(crate) seq: u64,
+    /// Whether this snapshot and the log after it use millisecond timestamps. Data
+    /// written before millisecond expirations used seconds and is converted on load.
+    #[serde(default)]
+    pub(crate) millis: bool,
+    pub(crate) next_token: u64,
+    pub(crate) locks: HashMap<String, LockInfo>,
+    pub(crate) semaphores: HashMap<String, SemaphoreInfo>,
+    #[serde(default)]
+    pub(crate) sessions: HashMap<String, SessionInfo>,
+}
+
+/// Append-only log of [`Event`]s plus the latest [`Snapshot`], kept in one directory.
+#[derive(Debug)]
+pub(crate) struct Wal {
+    dir: PathBuf,
+    file: File,
+    seq: u64,            // sequence number of the last record written
+    since_snapshot: u64, // records written since the last snapshot
+}
+
+impl Wal {
+    /// Open (or create) the log in `dir`.
+    ///
+    /// Returns the log together with the saved snapshot and the events logged after it,
+    /// in order. A torn record at the end of the log, left by a crash mid-write, is
+    /// ignored.
+    pub(crate) fn open(dir: &Path) -> io::Result<(Self, Snapshot, Vec<Event>)> {
+        fs::create_dir_all(dir)?;
+        let snapshot: Snapshot = match fs::read(dir.join(SNAPSHOT_FILE)) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot {
+                millis: true,
+                ..Snapshot::default()
+            },
+            Err(e) => return Err(e),
+        };
+        let mut seq = snapshot.seq;
+        let mut events = Vec::new();
+        match File::open(dir.join(WAL_FILE)) {
+            Ok(file) => {
+                for line in BufReader::new(file).lines() {
+                    let Ok(record) = serde_json::from_str::<Record>(&line?) else {
+                        break;
+                    };
+                    // Records at or below the snapshot's seq are already part of it.
+                    if record.seq > seq {
+                        seq = record.seq;
+                        events.push(record.event);
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(WAL_FILE))?;
+        let wal = Self {
+            dir: dir.to_path_buf(),
+            file,
+            seq,
+            since_snapshot: events.len() as u64,
+        };
+        Ok((wal, snapshot, events))
+    }
+
+    /// Durably append `event` to the log.
+    pub(crate) fn append(&mut self, event: Event) -> io::Result<()> {
+        let record = Record {
+            seq: self.seq + 1,
+            event,
+        };
+        let mut line = serde_json::to_vec(&record)?;
+        line.push(b'\n');
+        self.file.write_all(&line)?;
+        self.file.sync_data()?;
+        self.seq = record.seq;
+        self.since_snapshot += 1;
+        Ok(())
+    }
+
+    /// Whether enough records have been written that a snapshot is due.
+    pub(crate) fn needs_snapshot(&self) -> bool {
+        self.since_snapshot >= SNAPSHOT_EVERY
+    }
+
+    /// Replace the snapshot with `snapshot` and truncate the log.
+    ///
+    /// `snapshot` must reflect every record written so far; its `seq` is overwritten.
+    pub(crate) fn write_snapshot(&mut self, mut snapshot: Snapshot) -> io::Result<()> {
+        snapshot.seq = self.seq;
+        replace(&self.dir, SNAPSHOT_FILE, &serde_json::to_vec(&snapshot)?)?;
+        // Safe to drop the log now: a crash before this point replays only the records
+        // above the new snapshot's seq.
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        self.since_snapshot = 0;
+        Ok(())
+    }
+}
+
+/// A cluster node's current term and the candidate it voted for in that term.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct Vote {
+    pub(crate) term: u64,
+    pub(crate) voted_for: Option<NodeId>,
+}
+
+/// Lock state as of a Raft log entry, which the entries up to it were compacted into.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub(crate) struct RaftSnapshot {
+    /// Index of the last entry the snapshot covers.
+    pub(crate) index: u64,
+    /// Term of that entry.
+    pub(crate) term: u64,
+    /// Time of that entry, see [`Entry::at`]; expiration times in `state` are on the
+    /// same timeline.
+    pub(crate) at: u64,
+    pub(crate) state: Snapshot,
+}
+
+#[derive(Serialize, Deserialize)]
+struct LogRecord {
+    index: u64,
+    entry: Entry,
+}
+
+/// Durable Raft state for a cluster node, kept in one directory.
+///
+/// Log entries are appended with their index. An entry replaces the one at its index
+/// and every entry after it, so dropping a conflicting suffix needs no rewrite.
+#[derive(Debug)]
+pub(crate) struct RaftStore {
+    dir: PathBuf,
+    log: File,
+}
+
+impl RaftStore {
+    /// Open (or create) the Raft state in `dir`.
+    ///
+    /// Returns the store together with the saved vote, the latest snapshot, and the log
+    /// entries after it, in order. A torn record at the end of the log is cut off, so
+    /// that later records are not appended to it.
+    pub(crate) fn open(dir: &Path) -> io::Result<(Self, Vote, RaftSnapshot, Vec<Entry>)> {
+        fs::create_dir_all(dir)?;
+        let vote = read_json(&dir.join(VOTE_FILE))?.unwrap_or_default();
+        let snapshot: RaftSnapshot = read_json(&dir.join(RAFT_SNAPSHOT_FILE))?.unwrap_or_default();
+        let mut entries = Vec::new();
+        let mut valid = 0; // length of the log up to the end of the last record read
+        match File::open(dir.join(RAFT_LOG_FILE)) {
+            Ok(file) => {
+                let mut reader = BufReader::new(file);
+                let mut line = Vec::new();
+                while reader.read_until(b'\n', &mut line)? > 0 && line.ends_with(b"\n") {
+                    let Ok(record) = serde_json::from_slice::<LogRecord>(&line) else {
+                        break;
+                    };
+                    // Entries at or below the snapshot's index are already part of it.
+                    if let Some(position) = record.index.checked_sub(snapshot.index + 1) {
+                        if position > entries.len() as u64 {
+                            break;
+                        }
+                        entries.truncate(position as usize);
+                        entries.push(record.entry);
+                    }
+                    valid += line.len() as u64;
+                    line.clear();
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(RAFT_LOG_FILE))?;
+        if log.metadata()?.len() > valid {
+            log.set_len(valid)?;
+            log.sync_all()?;
+        }
+        let store = Self {
+            dir: dir.to_path_buf(),
+            log,
+        };
+        Ok((store, vote, snapshot, entries))
+    }
+
+    /// Durably replace the saved term and vote.
+    pub(crate) fn save_vote(&mut self, vote: Vote) -> io::Result<()> {
+        replace(&self.dir, VOTE_FILE, &serde_json::to_vec(&vote)?)
+    }
+
+    /// Durably append `entries`, the first of which has index `first`, dropping any
+    /// saved entries from `first` on.
+    pub(crate) fn append(&mut self, first: u64, entries: &[Entry]) -> io::Result<()> {
+        self.log.write_all(&records(first, entries)?)?;
+        self.log.sync_data()
+    }
+
+    /// Replace the snapshot with `snapshot` and the log with `entries`, the ones after
+    /// it.
+    pub(crate) fn compact(&mut self, snapshot: &RaftSnapshot, entries: &[Entry]) -> io::Result<()> {
+        replace(
+            &self.dir,
+            RAFT_SNAPSHOT_FILE,
+            &serde_json::to_vec(snapshot)?,
+        )?;
+        // A crash before the log is replaced leaves entries the snapshot covers, which
+        // are skipped on load.
+        let tmp = self.dir.join(format!("{}.tmp", RAFT_LOG_FILE));
+        let mut log = File::create(&tmp)?;
+        log.write_all(&records(snapshot.index + 1, entries)?)?;
+        log.sync_all()?;
+        fs::rename(&tmp, self.dir.join(RAFT_LOG_FILE))?;
+        self.log = log;
+        Ok(())
+    }
+}
+
+/// Internal: log records for `entries`, the first of which has index `first`.
+fn records(first: u64, entries: &[Entry]) -> io::Result<Vec<u8>> {
+    let mut lines = Vec::new();
+    for (index, entry) in (first..).zip(entries) {
+        serde_json::to_writer(
+            &mut lines,
+            &LogRecord {
+                index,
+                entry: entry.clone(),
+            },
+        )?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+/// Internal: read the JSON file at `path`, if it exists.
+fn read_json<T: serde::de::DeserializeOwned>(path: &Path) -> io::Result<Option<T>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Internal: atomically replace the file `name` in `dir` with `contents`.
+fn replace(dir: &Path, name: &str, contents: &[u8]) -> io::Result<()> {
+    let tmp = dir.join(format!("{}.tmp", name));
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(name))
+}
@@ -0,0 +1,1070 @@
+//! # raft
+//!
+//! Raft replication of lock state across a cluster of lockserver nodes.
+//!
+//! Every change to lock state is proposed to the leader as a [`Command`], appended to
+//! the replicated log, and applied to each node's [`LockManager`] once a majority of
+//! nodes has stored it. Followers keep a full copy of the lock state but do not accept
+//! changes themselves; the HTTP layer sends their clients on to the leader.
+//!
+//! Each node saves its term, vote and log entries in its data directory before it
+//! answers for them, so a restarted node neither votes twice in a term nor forgets
+//! entries it acknowledged. Once enough entries are applied the log is compacted into a
+//! snapshot of the lock state, which the leader sends instead of entries to a node too
+//! far behind. The cluster stays available as long as a majority of its nodes is
+//! running.
+
+use crate::clock::{Clock, SystemClock};
+use crate::lock_manager::{
+    LockError, LockKind, LockManager, deadline, new_session_id, unix_now_ms, wait_deadline,
+};
+use crate::persistence::{RaftSnapshot, RaftStore, Vote};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{Notify, oneshot};
+use utoipa::ToSchema;
+
+/// Identifier of a node in the cluster.
+pub type NodeId = u64;
+
+/// HTTP path of the RequestVote RPC.
+pub const VOTE_PATH: &str = "/raft/vote";
+/// HTTP path of the AppendEntries RPC.
+pub const APPEND_PATH: &str = "/raft/append";
+/// HTTP path of the InstallSnapshot RPC.
+pub const SNAPSHOT_PATH: &str = "/raft/snapshot";
+
+const TICK: Duration = Duration::from_millis(20);
+const HEARTBEAT: Duration = Duration::from_millis(100);
+const ELECTION_TIMEOUT_MS: u64 = 500; // randomized up to twice this
+const RPC_TIMEOUT: Duration = Duration::from_millis(300);
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_BATCH: u64 = 256; // entries per AppendEntries request
+const COMPACT_EVERY: u64 = 1000; // applied entries kept before the log is compacted
+
+/// Errors returned when running an operation through the cluster.
+#[derive(Debug, thiserror::Error)]
+pub enum RaftError {
+    /// This node is not the leader. Carries the leader's address, if known.
+    #[error("Not the cluster leader")]
+    NotLeader(Option<String>),
+    #[error(transparent)]
+    Lock(#[from] LockError),
+}
+
+/// A replicated change to lock state.
+///
+/// Expirations are durations from the [`Entry::at`] time the leader gives the command,
+/// so every node applies exactly the same change whatever its wall clock says.
+/// `acquired_at` is a unix timestamp in milliseconds, only ever reported.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Command {
+    /// Appended by a new leader to commit entries left over from earlier terms.
+    Noop,
+    Acquire {
+        resource: String,
+        owner: String,
+        kind: LockKind,
+        acquired_at: u64,
+        ttl_ms: Option<u64>,
+        session: Option<String>,
+        /// If `owner` already holds `resource`, take another nested hold on it.
+        #[serde(default)]
+        reentrant: bool,
+    },
+    /// Exclusive locks on all of `resources`, or none of them.
+    AcquireMany {
+        resources: Vec<String>,
+        owner: String,
+        acquired_at: u64,
+        ttl_ms: Option<u64>,
+        session: Option<String>,
+    },
+    Release {
+        resource: String,
+        owner: String,
+    },
+    /// Drop every hold on `resource`, whoever owns it.
+    ForceRelease {
+        resource: String,
+    },
+    /// Drop every lock held by `owner`.
+    ReleaseOwner {
+        owner: String,
+    },
+    Renew {
+        resource: String,
+        owner: String,
+        ttl_ms: u64,
+    },
+    AcquirePermits {
+        resource: String,
+        owner: String,
+        permits: u32,
+        limit: u32,
+        ttl_ms: Option<u64>,
+    },
+    ReleasePermits {
+        resource: String,
+        owner: String,
+    },
+    /// Open `session`, alive for `ttl` seconds after each heartbeat.
+    CreateSession {
+        session: String,
+        ttl: u64,
+    },
+    /// Keep `session` alive for another ttl.
+    Heartbeat {
+        session: String,
+    },
+    CloseSession {
+        session: String,
+    },
+    /// Drop every hold and session that is due.
+    Expire,
+}
+
+impl Command {
+    /// Acquire `resource`, expiring `ttl` from now if set, and bound to `session` if
+    /// given. A `reentrant` acquire by the current holder nests another
+    /// hold, see [`LockManager::acquire_reentrant`].
+    ///
+    /// Returns `LockError::InvalidRequest` if `ttl` is too long to represent.
+    pub fn acquire(
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+        ttl: Option<Duration>,
+        session: Option<&str>,
+        reentrant: bool,
+    ) -> Result<Self, LockError> {
+        Ok(Command::Acquire {
+            resource: resource.to_string(),
+            owner: owner.to_string(),
+            kind,
+            acquired_at: unix_now_ms(),
+            ttl_ms: ttl.map(ttl_ms).transpose()?,
+            session: session.map(str::to_string),
+            reentrant,
+        })
+    }
+
+    /// Open a new session with a fresh id, alive for `ttl` seconds after each heartbeat.
+    ///
+    /// Returns `LockError::InvalidRequest` if `ttl` is too long to represent.
+    pub fn create_session(ttl: u64) -> Result<Self, LockError> {
+        ttl_ms(Duration::from_secs(ttl))?;
+        Ok(Command::CreateSession {
+            session: new_session_id(),
+            ttl,
+        })
+    }
+
+    /// Keep `session` alive for another ttl from now.
+    pub fn heartbeat(session: &str) -> Self {
+        Command::Heartbeat {
+            session: session.to_string(),
+        }
+    }
+
+    /// Close `session`, releasing every lock bound to it.
+    pub fn close_session(session: &str) -> Self {
+        Command::CloseSession {
+            session: session.to_string(),
+        }
+    }
+
+    /// Acquire exclusive locks on all of `resources` at once, with a shared expiration.
+    pub fn acquire_many(
+        resources: &[&str],
+        owner: &str,
+        ttl: Option<Duration>,
+        session: Option<&str>,
+    ) -> Result<Self, LockError> {
+        Ok(Command::AcquireMany {
+            resources: resources.iter().map(|r| r.to_string()).collect(),
+            owner: owner.to_string(),
+            acquired_at: unix_now_ms(),
+            ttl_ms: ttl.map(ttl_ms).transpose()?,
+            session: session.map(str::to_string),
+        })
+    }
+
+    /// Release `owner`'s hold on `resource`.
+    pub fn release(resource: &str, owner: &str) -> Self {
+        Command::Release {
+            resource: resource.to_string(),
+            owner: owner.to_string(),
+        }
+    }
+
+    /// Drop every hold on `resource`, whoever owns it.
+    pub fn force_release(resource: &str) -> Self {
+        Command::ForceRelease {
+            resource: resource.to_string(),
+        }
+    }
+
+    /// Drop every lock held by `owner`.
+    pub fn release_owner(owner: &str) -> Self {
+        Command::ReleaseOwner {
+            owner: owner.to_string(),
+        }
+    }
+
+    /// Extend `owner`'s hold on `resource` to `ttl` from now.
+    pub fn renew(resource: &str, owner: &str, ttl: Duration) -> Result<Self, LockError> {
+        Ok(Command::Renew {
+            resource: resource.to_string(),
+            owner: owner.to_string(),
+            ttl_ms: ttl_ms(ttl)?,
+        })
+    }
+
+    /// Take permits from the semaphore `resource`, expiring `expire_secs` seconds from now if set.
+    ///
+    /// Returns `LockError::InvalidRequest` if `expire_secs` is too long to represent.
+    pub fn acquire_permits(
+        resource: &str,
+        owner: &str,
+        permits: u32,
+        limit: u32,
+        expire_secs: Option<u64>,
+    ) -> Result<Self, LockError> {
+        Ok(Command::AcquirePermits {
+            resource: resource.to_string(),
+            owner: owner.to_string(),
+            permits,
+            limit,
+            ttl_ms: expire_secs
+                .map(|secs| ttl_ms(Duration::from_secs(secs)))
+                .transpose()?,
+        })
+    }
+
+    /// Return every permit `owner` holds on the semaphore `resource`.
+    pub fn release_permits(resource: &str, owner: &str) -> Self {
+        Command::ReleasePermits {
+            resource: resource.to_string(),
+            owner: owner.to_string(),
+        }
+    }
+}
+
+/// Internal: `ttl` in whole milliseconds, rounded up. Returns
+/// `LockError::InvalidRequest` if that is too long to represent.
+fn ttl_ms(ttl: Duration) -> Result<u64, LockError> {
+    deadline(0, ttl)
+}
+
+/// Role of a node in the current term.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Follower,
+    Candidate,
+    Leader,
+}
+
+/// An entry in the replicated log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    pub term: u64,
+    /// When the leader proposed the entry, in milliseconds on its monotonic clock.
+    /// Expirations in `command` count from here.
+    pub at: u64,
+    pub command: Command,
+}
+
+/// Body of the RequestVote RPC.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VoteRequest {
+    pub term: u64,
+    pub candidate_id: NodeId,
+    pub last_log_index: u64,
+    pub last_log_term: u64,
+}
+
+/// Reply to the RequestVote RPC.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct VoteResponse {
+    pub term: u64,
+    pub vote_granted: bool,
+}
+
+/// Body of the AppendEntries RPC.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppendRequest {
+    pub term: u64,
+    pub leader_id: NodeId,
+    pub prev_log_index: u64,
+    pub prev_log_term: u64,
+    pub entries: Vec<Entry>,
+    pub leader_commit: u64,
+    /// The leader's time when it sent the request, see [`Entry::at`]. Followers set
+    /// their clocks to it, so that whichever takes over next goes on from there.
+    pub at: u64,
+}
+
+/// Reply to the AppendEntries RPC.
+///
+/// On success `match_index` is the last index known to match the leader's log; on
+/// failure it is a hint for where the leader should retry from.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppendResponse {
+    pub term: u64,
+    pub success: bool,
+    pub match_index: u64,
+}
+
+/// Body of the InstallSnapshot RPC, sent instead of entries the leader has compacted.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotRequest {
+    pub term: u64,
+    pub leader_id: NodeId,
+    snapshot: RaftSnapshot,
+}
+
+/// Reply to the InstallSnapshot RPC.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotResponse {
+    pub term: u64,
+}
+
+/// A node's view of the cluster, as reported by [`RaftNode::status`].
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct Status {
+    #[schema(value_type = u64)]
+    pub id: NodeId,
+    pub role: Role,
+    pub term: u64,
+    #[schema(value_type = Option<u64>)]
+    pub leader: Option<NodeId>,
+    pub commit_index: u64,
+}
+
+/// Cluster membership for one node.
+#[derive(Debug, Clone)]
+pub struct ClusterConfig {
+    /// This node's id; must be a key of `nodes`.
+    pub id: NodeId,
+    /// HTTP address (`host:port`) of every node in the cluster, including this one.
+    pub nodes: HashMap<NodeId, String>,
+    /// Shared secret sent with RPCs to other nodes.
+    pub secret: String,
+    /// Directory the node keeps its term, vote and log in. Without one they are only
+    /// kept in memory, which is unsafe across restarts and meant for tests.
+    pub data_dir: Option<PathBuf>,
+}
+
+impl ClusterConfig {
+    /// Parse a node list of the form `1=10.0.0.1:8080,2=10.0.0.2:8080,3=10.0.0.3:8080`.
+    pub fn parse_nodes(spec: &str) -> Result<HashMap<NodeId, String>, String> {
+        spec.split(',')
+            .filter(|part| !part.trim().is_empty())
+            .map(|part| {
+                let (id, addr) = part
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected ID=HOST:PORT, got {:?}", part))?;
+                let id = id
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("invalid node id {:?}", id))?;
+                Ok((id, addr.trim().to_string()))
+            })
+            .collect()
+    }
+}
+
+/// Reply channel for a command proposed on this node.
+type Reply = oneshot::Sender<Result<Option<u64>, LockError>>;
+
+/// Raft state guarded by [`RaftNode::state`].
+struct State {
+    role: Role,
+    term: u64,
+    voted_for: Option<NodeId>,
+    snapshot: RaftSnapshot, // what the log was last compacted into
+    log: Vec<Entry>,        // entry at index i is log[i - snapshot.index - 1]
+    store: Option<RaftStore>,
+    commit_index: u64,
+    last_applied: u64,
+    leader: Option<NodeId>,
+    election_deadline: Instant,
+    votes: HashSet<NodeId>,
+    next_index: HashMap<NodeId, u64>,
+    match_index: HashMap<NodeId, u64>,
+    // index -> (term, reply) for commands proposed on this node
+    pending: HashMap<u64, (u64, Reply)>,
+}
+
+impl State {
+    fn last_index(&self) -> u64 {
+        self.snapshot.index + self.log.len() as u64
+    }
+
+    /// The term of the entry at `index`, unless it was compacted away.
+    fn term_at(&self, index: u64) -> Option<u64> {
+        match index.checked_sub(self.snapshot.index)? {
+            0 => Some(self.snapshot.term),
+            i => self.log.get(i as usize - 1).map(|entry| entry.term),
+        }
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index()).unwrap_or(0)
+    }
+
+    /// The entry at `index`, which must be in the log.
+    fn entry(&self, index: u64) -> &Entry {
+        &self.log[(index - self.snapshot.index - 1) as usize]
+    }
+
+    /// Become a follower, moving up to `term` if it is newer. If the new term cannot
+    /// be saved the node stays in its old one.
+    fn step_down(&mut self, term: u64) {
+        if term > self.term {
+            let _ = self.set_vote(term, None);
+        }
+        self.role = Role::Follower;
+        self.votes.clear();
+    }
+
+    /// Save `term` and `voted_for`, then adopt them.
+    fn set_vote(&mut self, term: u64, voted_for: Option<NodeId>) -> io::Result<()> {
+        if let Some(store) = &mut self.store {
+            store.save_vote(Vote { term, voted_for })?;
+        }
+        self.term = term;
+        self.voted_for = voted_for;
+        Ok(())
+    }
+
+    /// Save `entries`, then put them in the log after index `after`, dropping whatever
+    /// followed it.
+    fn append(&mut self, after: u64, entries: Vec<Entry>) -> io::Result<()> {
+        if let Some(store) = &mut self.store {
+            store.append(after + 1, &entries)?;
+        }
+        self.log.truncate((after - self.snapshot.index) as usize);
+        // Anyone waiting on a dropped entry is told it failed.
+        self.pending.retain(|&i, _| i <= after);
+        self.log.extend(entries);
+        Ok(())
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let jitter = RandomState::new().build_hasher().finish() % ELECTION_TIMEOUT_MS;
+        self.election_deadline =
+            Instant::now() + Duration::from_millis(ELECTION_TIMEOUT_MS + jitter);
+    }
+}
+
+/// A lockserver node taking part in a Raft cluster.
+pub struct RaftNode {
+    id: NodeId,
+    nodes: HashMap<NodeId, String>,
+    secret: String,
+    manager: Arc<LockManager>,
+    state: Mutex<State>,
+    applied: Notify,                   // woken whenever committed entries are applied
+    peers: Vec<(NodeId, Arc<Notify>)>, // per-peer replication wake-up
+    expiring: AtomicBool,              // an expire command is in flight
+    http: reqwest::Client,
+}
+
+impl RaftNode {
+    /// Start a node and spawn its election and replication tasks.
+    ///
+    /// The node's term, vote and log are restored from `config.data_dir` if saved there
+    /// before. Must be called from within a tokio runtime.
+    pub fn start(config: ClusterConfig) -> io::Result<Arc<Self>> {
+        Self::with_clock(config, Arc::new(SystemClock))
+    }
+
+    /// [`Self::start`] with lock expiry measured on `clock`.
+    pub fn with_clock(config: ClusterConfig, clock: Arc<dyn Clock>) -> io::Result<Arc<Self>> {
+        let (store, vote, snapshot, log) = match &config.data_dir {
+            Some(dir) => {
+                let (store, vote, snapshot, log) = RaftStore::open(dir)?;
+                (Some(store), vote, snapshot, log)
+            }
+            None => (None, Vote::default(), RaftSnapshot::default(), Vec::new()),
+        };
+        let manager = LockManager::replica(clock);
+        for at in log.iter().map(|entry| entry.at).chain([snapshot.at]) {
+            manager.catch_up(at);
+        }
+        // Entries after the snapshot are applied again once the leader says they
+        // are committed.
+        manager.restore_replica(snapshot.state.clone());
+        let peers = config
+            .nodes
+            .keys()
+            .filter(|&&id| id != config.id)
+            .map(|&id| (id, Arc::new(Notify::new())))
+            .collect();
+        let mut state = State {
+            role: Role::Follower,
+            term: vote.term,
+            voted_for: vote.voted_for,
+            commit_index: snapshot.index,
+            last_applied: snapshot.index,
+            snapshot,
+            log,
+            store,
+            leader: None,
+            election_deadline: Instant::now(),
+            votes: HashSet::new(),
+            next_index: HashMap::new(),
+            match_index: HashMap::new(),
+            pending: HashMap::new(),
+        };
+        state.reset_election_deadline();
+        let node = Arc::new(Self {
+            id: config.id,
+            nodes: config.nodes,
+            secret: config.secret,
+            manager: Arc::new(manager),
+            state: Mutex::new(state),
+            applied: Notify::new(),
+            peers,
+            expiring: AtomicBool::new(false),
+            http: reqwest::Client::builder()
+                .redirect(reqwest::redirect::Policy::none())
+                .build()
+                .expect("failed to build HTTP client"),
+        });
+        tokio::spawn(node.clone().run_ticker());
+        for (peer, wake) in &node.peers {
+            tokio::spawn(node.clone().run_replicator(*peer, wake.clone()));
+        }
+        Ok(node)
+    }
+
+    /// This node's copy of the lock state. Changes must go through [`RaftNode::propose`].
+    pub fn manager(&self) -> &Arc<LockManager> {
+        &self.manager
+    }
+
+    /// This node's current view of the cluster.
+    pub fn status(&self) -> Status {
+        let st = self.state.lock().unwrap();
+        Status {
+            id: self.id,
+            role: st.role,
+            term: st.term,
+            leader: st.leader,
+            commit_index: st.commit_index,
+        }
+    }
+
+    /// Replicate `command` and apply it once committed.
+    ///
+    /// Only the leader accepts proposals; other nodes return
+    /// [`RaftError::NotLeader`] with the leader's address if they know it. Returns the
+    /// fencing token for acquire commands.
+    pub async fn propose(&self, command: Command) -> Result<Option<u64>, RaftError> {
+        let rx = {
+            let mut st = self.state.lock().unwrap();
+            if st.role != Role::Leader {
+                return Err(RaftError::NotLeader(self.leader_addr(&st)));
+            }
+            let term = st.term;
+            let at = self.manager.now();
+            let last = st.last_index();
+            if let Err(e) = st.append(last, vec![Entry { term, at, command }]) {
+                return Err(LockError::Internal(format!("raft log: {}", e)).into());
+            }
+            let index = st.last_index();
+            let (tx, rx) = oneshot::channel();
+            st.pending.insert(index, (term, tx));
+            // A single-node cluster commits right away.
+            self.advance_commit(&mut st);
+            rx
+        };
+        self.wake_replicators();
+        match tokio::time::timeout(COMMIT_TIMEOUT, rx).await {
+            Ok(Ok(result)) => Ok(result?),
+            // The entry was overwritten by another leader's log.
+            Ok(Err(_)) => {
+                let st = self.state.lock().unwrap();
+                Err(RaftError::NotLeader(self.leader_addr(&st)))
+            }
+            Err(_) => Err(LockError::Internal("timed out waiting for commit".to_string()).into()),
+        }
+    }
+
+    /// Acquire a lock through the cluster, retrying for up to `timeout` while it is held.
+    ///
+    /// Unlike [`LockManager::acquire_wait`], waiters are not queued: whoever retries
+    /// first after a release wins.
+    pub async fn acquire_wait(
+        &self,
+        resource: &str,
+        owner: &str,
+        kind: LockKind,
+        ttl: Option<Duration>,
+        session: Option<&str>,
+        timeout: Duration,
+    ) -> Result<Option<u64>, RaftError> {
+        let deadline = wait_deadline(timeout)?;
+        loop {
+            let applied = self.applied.notified();
+            tokio::pin!(applied);
+            applied.as_mut().enable();
+            // Only propose when the lock looks free, so a held lock doesn't flood the log.
+            if self.manager.can_acquire(resource, owner, kind) {
+                match self
+                    .propose(Command::acquire(
+                        resource, owner, kind, ttl, session, false,
+                    )?)
+                    .await
+                {
+                    Err(RaftError::Lock(LockError::AlreadyLocked)) => continue,
+                    result => return result,
+                }
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(LockError::Timeout.into());
+            }
+            let _ = tokio::time::timeout(deadline - now, applied).await;
+        }
+    }
+
+    /// Handle a RequestVote RPC from a candidate.
+    pub fn handle_vote(&self, req: VoteRequest) -> VoteResponse {
+        let mut st = self.state.lock().unwrap();
+        if req.term > st.term {
+            st.step_down(req.term);
+        }
+        let up_to_date = req.last_log_term > st.last_term()
+            || (req.last_log_term == st.last_term() && req.last_log_index >= st.last_index());
+        // The vote is saved before it is given.
+        let vote_granted = req.term == st.term
+            && st.voted_for.is_none_or(|id| id == req.candidate_id)
+            && up_to_date
+            && st.set_vote(req.term, Some(req.candidate_id)).is_ok();
+        if vote_granted {
+            st.reset_election_deadline();
+        }
+        VoteResponse {
+            term: st.term,
+            vote_granted,
+        }
+    }
+
+    /// Handle an AppendEntries RPC from the leader.
+    pub fn handle_append(&self, req: AppendRequest) -> AppendResponse {
+        let mut st = self.state.lock().unwrap();
+        if req.term < st.term {
+            return AppendResponse {
+                term: st.term,
+                success: false,
+                match_index: st.last_index(),
+            };
+        }
+        if req.term > st.term || st.role != Role::Follower {
+            st.step_down(req.term);
+        }
+        if st.term != req.term {
+            // The leader's term could not be saved.
+            return AppendResponse {
+                term: st.term,
+                success: false,
+                match_index: st.last_index(),
+            };
+        }
+        st.leader = Some(req.leader_id);
+        st.reset_election_deadline();
+        self.manager.follow(req.at);
+
+        let mut entries = req.entries;
+        let mut index = req.prev_log_index;
+        if index < st.snapshot.index {
+            // Entries our snapshot covers are committed, so they match the leader's.
+            let covered = entries.len().min((st.snapshot.index - index) as usize);
+            entries.drain(..covered);
+            index += covered as u64;
+        } else if st.term_at(index) != Some(req.prev_log_term) {
+            return AppendResponse {
+                term: st.term,
+                success: false,
+                match_index: st.last_index().min(index.saturating_sub(1)),
+            };
+        }
+        // Skip the entries we already have; from the first we don't, the leader's
+        // entries replace ours.
+        let have = entries
+            .iter()
+            .zip(index + 1..)
+            .take_while(|(entry, i)| st.term_at(*i) == Some(entry.term))
+            .count();
+        entries.drain(..have);
+        index += have as u64;
+        if !entries.is_empty() {
+            // Never fall behind the entries themselves, so they stay in time order if
+            // we take over.
+            for entry in &entries {
+                self.manager.catch_up(entry.at);
+            }
+            let count = entries.len() as u64;
+            if st.append(index, entries).is_err() {
+                return AppendResponse {
+                    term: st.term,
+                    success: false,
+                    match_index: index,
+                };
+            }
+            index += count;
+        }
+        if req.leader_commit > st.commit_index {
+            st.commit_index = req.leader_commit.min(index);
+            self.apply_committed(&mut st);
+        }
+        AppendResponse {
+            term: st.term,
+            success: true,
+            match_index: index,
+        }
+    }
+
+    /// Handle an InstallSnapshot RPC from the leader.
+    pub fn handle_snapshot(&self, req: SnapshotRequest) -> SnapshotResponse {
+        let mut st = self.state.lock().unwrap();
+        if req.term < st.term {
+            return SnapshotResponse { term: st.term };
+        }
+        if req.term > st.term || st.role != Role::Follower {
+            st.step_down(req.term);
+        }
+        if st.term != req.term {
+            return SnapshotResponse { term: st.term };
+        }
+        st.leader = Some(req.leader_id);
+        st.reset_election_deadline();
+
+        let snapshot = req.snapshot;
+        if snapshot.index <= st.commit_index {
+            return SnapshotResponse { term: st.term };
+        }
+        // Entries after the snapshot are kept if our log agrees with it.
+        let rest = match st.term_at(snapshot.index) {
+            Some(term) if term == snapshot.term => {
+                st.log[(snapshot.index - st.snapshot.index) as usize..].to_vec()
+            }
+            _ => Vec::new(),
+        };
+        if let Some(store) = &mut st.store
+            && store.compact(&snapshot, &rest).is_err()
+        {
+            return SnapshotResponse { term: st.term };
+        }
+        self.manager.catch_up(snapshot.at);
+        self.manager.restore_replica(snapshot.state.clone());
+        let (first, last) = (snapshot.index, snapshot.index + rest.len() as u64);
+        st.pending.retain(|&i, _| i > first && i <= last);
+        st.commit_index = snapshot.index;
+        st.last_applied = snapshot.index;
+        st.snapshot = snapshot;
+        st.log = rest;
+        self.applied.notify_waiters();
+        SnapshotResponse { term: st.term }
+    }
+
+    /// Internal: drive elections and, on the leader, lock expiry.
+    async fn run_ticker(self: Arc<Self>) {
+        loop {
+            tokio::time::sleep(TICK).await;
+            let (election, is_leader) = {
+                let mut st = self.state.lock().unwrap();
+                let election = (st.role != Role::Leader && Instant::now() >= st.election_deadline)
+                    .then(|| self.start_election(&mut st))
+                    .flatten();
+                (election, st.role == Role::Leader)
+            };
+            if let Some(req) = election {
+                let req = Arc::new(req);
+                for &(peer, _) in &self.peers {
+                    tokio::spawn(self.clone().request_vote(peer, req.clone()));
+                }
+            }
+            if is_leader && self.manager.has_due() && !self.expiring.swap(true, Ordering::SeqCst) {
+                let node = self.clone();
+                tokio::spawn(async move {
+                    let _ = node.propose(Command::Expire).await;
+                    node.expiring.store(false, Ordering::SeqCst);
+                });
+            }
+        }
+    }
+
+    /// Internal: become a candidate for the next term and vote for ourselves. Returns
+    /// `None` if the new term could not be saved.
+    fn start_election(&self, st: &mut State) -> Option<VoteRequest> {
+        st.reset_election_deadline();
+        st.set_vote(st.term + 1, Some(self.id)).ok()?;
+        st.role = Role::Candidate;
+        st.votes = HashSet::from([self.id]);
+        st.leader = None;
+        if self.has_quorum(st.votes.len()) {
+            self.become_leader(st);
+        }
+        Some(VoteRequest {
+            term: st.term,
+            candidate_id: self.id,
+            last_log_index: st.last_index(),
+            last_log_term: st.last_term(),
+        })
+    }
+
+    /// Internal: ask `peer` for its vote and count the reply.
+    async fn request_vote(self: Arc<Self>, peer: NodeId, req: Arc<VoteRequest>) {
+        let Some(resp) = self.send::<_, VoteResponse>(peer, VOTE_PATH, &*req).await else {
+            return;
+        };
+        let mut st = self.state.lock().unwrap();
+        if resp.term > st.term {
+            st.step_down(resp.term);
+            return;
+        }
+        if st.role == Role::Candidate && st.term == req.term && resp.vote_granted {
+            st.votes.insert(peer);
+            if self.has_quorum(st.votes.len()) {
+                self.become_leader(&mut st);
+                drop(st);
+                self.wake_replicators();
+            }
+        }
+    }
+
+    /// Internal: take over as leader for the current term.
+    fn become_leader(&self, st: &mut State) {
+        st.role = Role::Leader;
+        st.leader = Some(self.id);
+        let next = st.last_index() + 1;
+        for &(peer, _) in &self.peers {
+            st.next_index.insert(peer, next);
+            st.match_index.insert(peer, 0);
+        }
+        let noop = Entry {
+            term: st.term,
+            at: self.manager.now(),
+            command: Command::Noop,
+        };
+        let last = st.last_index();
+        if st.append(last, vec![noop]).is_err() {
+            st.step_down(st.term);
+            return;
+        }
+        self.advance_commit(st);
+    }
+
+    /// Internal: keep `peer`'s log in sync with ours while we are leader.
+    async fn run_replicator(self: Arc<Self>, peer: NodeId, wake: Arc<Notify>) {
+        loop {
+            let _ = tokio::time::timeout(HEARTBEAT, wake.notified()).await;
+            loop {
+                let req = {
+                    let st = self.state.lock().unwrap();
+                    if st.role != Role::Leader {
+                        break;
+                    }
+                    let next = st.next_index.get(&peer).copied().unwrap_or(1).max(1);
+                    let prev_log_index = next - 1;
+                    match st.term_at(prev_log_index) {
+                        Some(prev_log_term) => {
+                            let start = (prev_log_index - st.snapshot.index) as usize;
+                            let end = (st.last_index() - st.snapshot.index) as usize;
+                            Ok(AppendRequest {
+                                term: st.term,
+                                leader_id: self.id,
+                                prev_log_index,
+                                prev_log_term,
+                                entries: st.log[start..end.min(start + MAX_BATCH as usize)]
+                                    .to_vec(),
+                                leader_commit: st.commit_index,
+                                at: self.manager.now(),
+                            })
+                        }
+                        // The entries the peer needs were compacted.
+                        None => Err(SnapshotRequest {
+                            term: st.term,
+                            leader_id: self.id,
+                            snapshot: st.snapshot.clone(),
+                        }),
+                    }
+                };
+                let req = match req {
+                    Ok(req) => req,
+                    Err(req) => match self.install_snapshot(peer, req).await {
+                        true => continue,
+                        false => break,
+                    },
+                };
+                let Some(resp) = self
+                    .send::<_, AppendResponse>(peer, APPEND_PATH, &req)
+                    .await
+                else {
+                    break;
+                };
+                let mut st = self.state.lock().unwrap();
+                if resp.term > st.term {
+                    st.step_down(resp.term);
+                    break;
+                }
+                if st.role != Role::Leader || st.term != req.term {
+                    break;
+                }
+                if resp.success {
+                    let matched = st.match_index.entry(peer).or_insert(0);
+                    *matched = (*matched).max(resp.match_index);
+                    let matched = *matched;
+                    st.next_index.insert(peer, matched + 1);
+                    self.advance_commit(&mut st);
+                    if matched >= st.last_index() {
+                        break;
+                    }
+                } else {
+                    // Back up and retry straight away.
+                    let next = st.next_index.get(&peer).copied().unwrap_or(1);
+                    let next = next.saturating_sub(1).min(resp.match_index + 1).max(1);
+                    st.next_index.insert(peer, next);
+                }
+            }
+        }
+    }
+
+    /// Internal: send our snapshot to `peer`. Returns whether to go on replicating.
+    async fn install_snapshot(&self, peer: NodeId, req: SnapshotRequest) -> bool {
+        let Some(resp) = self
+            .send::<_, SnapshotResponse>(peer, SNAPSHOT_PATH, &req)
+            .await
+        else {
+            return false;
+        };
+        let mut st = self.state.lock().unwrap();
+        if resp.term > st.term {
+            st.step_down(resp.term);
+            return false;
+        }
+        if st.role != Role::Leader || st.term != req.term {
+            return false;
+        }
+        let matched = st.match_index.entry(peer).or_insert(0);
+        *matched = (*matched).max(req.snapshot.index);
+        let next = *matched + 1;
+        st.next_index.insert(peer, next);
+        self.advance_commit(&mut st);
+        true
+    }
+
+    /// Internal: commit the newest entry of our term stored on a majority, then apply.
+    fn advance_commit(&self, st: &mut State) {
+        if st.role != Role::Leader {
+            return;
+        }
+        for index in (st.commit_index + 1..=st.last_index()).rev() {
+            if st.term_at(index) != Some(st.term) {
+                break;
+            }
+            let replicas = 1 + st.match_index.values().filter(|&&m| m >= index).count();
+            if self.has_quorum(replicas) {
+                st.commit_index = index;
+                break;
+            }
+        }
+        self.apply_committed(st);
+    }
+
+    /// Internal: apply committed entries to the lock manager, answering local proposals.
+    fn apply_committed(&self, st: &mut State) {
+        if st.last_applied >= st.commit_index {
+            return;
+        }
+        while st.last_applied < st.commit_index {
+            st.last_applied += 1;
+            let index = st.last_applied;
+            let entry = st.entry(index);
+            let term = entry.term;
+            let result = self.manager.apply_at(&entry.command, entry.at);
+            if let Some((proposed_term, reply)) = st.pending.remove(&index)
+                && proposed_term == term
+            {
+                let _ = reply.send(result);
+            }
+        }
+        if st.last_applied - st.snapshot.index >= COMPACT_EVERY {
+            self.compact(st);
+        }
+        self.applied.notify_waiters();
+    }
+
+    /// Internal: compact the applied entries of the log into a snapshot of the lock
+    /// state. The log is left as it is if the snapshot cannot be saved.
+    fn compact(&self, st: &mut State) {
+        let index = st.last_applied;
+        let entry = st.entry(index);
+        let snapshot = RaftSnapshot {
+            index,
+            term: entry.term,
+            at: entry.at,
+            state: self.manager.replica_snapshot(),
+        };
+        let applied = (index - st.snapshot.index) as usize;
+        if let Some(store) = &mut st.store
+            && store.compact(&snapshot, &st.log[applied..]).is_err()
+        {
+            return;
+        }
+        st.log.drain(..applied);
+        st.snapshot = snapshot;
+    }
+
+    fn has_quorum(&self, count: usize) -> bool {
+        count * 2 > self.nodes.len()
+    }
+
+    fn leader_addr(&self, st: &State) -> Option<String> {
+        st.leader.and_then(|id| self.nodes.get(&id).cloned())
+    }
+
+    fn wake_replicators(&self) {
+        for (_, wake) in &self.peers {
+            wake.notify_one();
+        }
+    }
+
+    /// Internal: POST an RPC to `peer`, returning `None` if it could not be reached.
+    async fn send<Req: Serialize, Resp: DeserializeOwned>(
+        &self,
+        peer: NodeId,
+        path: &str,
+        req: &Req,
+    ) -> Option<Resp> {
+        let addr = self.nodes.get(&peer)?;
+        let resp = self
+            .http
+            .post(format!("http://{}{}", addr, path))
+            .header("X-LOCKSERVER-SECRET", &self.secret)
+            .timeout(RPC_TIMEOUT)
+            .json(req)
+            .send()
+            .await
+            .ok()?;
+        if !resp.status().is_success() {
+            return None;
+        }
+        resp.json().await.ok()
+    }
+}
@@ -16,3 +16,143 @@ fn test_lock_scope_macro() {
         });
     });
 }
+
+#[test]
+fn test_inspect_and_list_locks() {
+    let client = LockserverClient::new_with_env(
+        Some("127.0.0.1:8080"),
+        Some("inspect_owner"),
+        None::<String>,
+    );
+    let token = client
+        .acquire_with_mode_and_expire(
+            "inspect/res",
+            lockserver::client::LockMode::Blocking,
+            Some(30),
+        )
+        .unwrap();
+    let _guard = lockserver::LockGuard::new(&client, "inspect/res", token);
+    let status = client.inspect("inspect/res").unwrap().unwrap();
+    assert_eq!(status.holders[0].owner, "inspect_owner");
+    assert!(status.holders[0].expires_at.is_some());
+    let page = client.list_locks("inspect/", None, None).unwrap();
+    assert!(page.locks.iter().any(|l| l.resource == "inspect/res"));
+    assert!(client.inspect("inspect/missing").unwrap().is_none());
+}
+
+#[test]
+fn test_session_releases_locks_on_drop() {
+    let client = LockserverClient::new_with_env(
+        Some("127.0.0.1:8080"),
+        Some("session_owner"),
+        None::<String>,
+    );
+    let session = client.open_session(3).unwrap();
+    session
+        .client()
+        .acquire_with_mode("session/res", lockserver::client::LockMode::NonBlocking)
+        .unwrap();
+    assert!(client.inspect("session/res").unwrap().is_some());
+    drop(session);
+    assert!(client.inspect("session/res").unwrap().is_none());
+}
+
+#[test]
+fn test_millisecond_ttl() {
+    use std::time::Duration;
+
+    let client =
+        LockserverClient::new_with_env(Some("127.0.0.1:8080"), Some("ttl_owner"), None::<String>);
+    client
+        .acquire_with_ttl(
+            "ttl/res",
+            lockserver::LockKind::Exclusive,
+            lockserver::client::LockMode::NonBlocking,
+            Some(Duration::from_millis(300)),
+        )
+        .unwrap();
+    let status = client.inspect("ttl/res").unwrap().unwrap();
+    assert!(status.holders[0].ttl_ms.unwrap() <= 300);
+    std::thread::sleep(Duration::from_millis(400));
+    assert!(client.inspect("ttl/res").unwrap().is_none());
+}
+
+#[test]
+fn test_typed_errors() {
+    use lockserver::ClientError;
+    use lockserver::client::LockMode;
+
+    let holder = LockserverClient::new_with_env(
+        Some("127.0.0.1:8080"),
+        Some("errors_holder"),
+        None::<String>,
+    );
+    let other = LockserverClient::new_with_env(
+        Some("127.0.0.1:8080"),
+        Some("errors_other"),
+        None::<String>,
+    );
+    holder
+        .acquire_with_mode("errors/res", LockMode::NonBlocking)
+        .unwrap();
+    assert!(matches!(
+        other.acquire_with_mode("errors/res", LockMode::NonBlocking),
+        Err(ClientError::Locked)
+    ));
+    assert!(matches!(
+        other.release("errors/res"),
+        Err(ClientError::NotOwner)
+    ));
+    holder.release("errors/res").unwrap();
+    assert!(matches!(
+        holder.release("errors/res"),
+        Err(ClientError::NotFound)
+    ));
+
+    let wrong_secret = LockserverClient::new("127.0.0.1:8080", "errors_holder", "wrong");
+    assert!(matches!(
+        wrong_secret.acquire_with_mode("errors/res", LockMode::NonBlocking),
+        Err(ClientError::Unauthorized(_))
+    ));
+}
+
+#[test]
+fn test_v1_routes() {
+    use reqwest::StatusCode;
+    use reqwest::blocking::Client;
+    use serde_json::{Value, json};
+
+    let http = Client::new();
+    let secret = std::env::var("LOCKSERVER_SECRET").unwrap_or_else(|_| "changeme".to_string());
+    let url = "http://127.0.0.1:8080/v1/locks/v1/jobs/42";
+    let send = |req: reqwest::blocking::RequestBuilder| {
+        req.header("X-LOCKSERVER-SECRET", &secret).send().unwrap()
+    };
+
+    let resp = send(
+        http.put(url)
+            .json(&json!({ "owner": "v1_owner", "expire": 10 })),
+    );
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.json::<Value>().unwrap()["token"].is_u64());
+    let resp = send(http.get(url));
+    assert_eq!(resp.json::<Value>().unwrap()["resource"], "v1/jobs/42");
+    let resp = send(
+        http.patch(url)
+            .json(&json!({ "owner": "v1_owner", "expire": 20 })),
+    );
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // The unversioned routes address the same lock.
+    let resp = send(
+        http.post("http://127.0.0.1:8080/release")
+            .json(&json!({ "resource": "v1/jobs/42", "owner": "other" })),
+    );
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    assert_eq!(resp.json::<Value>().unwrap()["code"], "not_owner");
+
+    let resp = send(http.delete(url).query(&[("owner", "v1_owner")]));
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = send(http.get(url));
+    assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+}
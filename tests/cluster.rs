@@ -0,0 +1,584 @@
+use actix_web::{App, HttpResponse, HttpServer, web};
+use lockserver::raft::{
+    self, AppendRequest, ClusterConfig, RaftError, RaftNode, Role, SnapshotRequest, VoteRequest,
+};
+use lockserver::{LockError, LockKind, ManualClock};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::process::{Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+const SECRET: &str = "cluster-test-secret";
+
+/// Three lockserver processes forming a cluster, each keeping its Raft log in its own
+/// directory; killed and cleaned up on drop.
+struct Cluster {
+    first_port: u16,
+    dir: PathBuf,
+    nodes: HashMap<u64, Child>,
+}
+
+impl Cluster {
+    fn start(first_port: u16) -> Self {
+        let dir = std::env::temp_dir().join(format!(
+            "lockserver-cluster-{}-{}",
+            first_port,
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        let mut cluster = Cluster {
+            first_port,
+            dir,
+            nodes: HashMap::new(),
+        };
+        for id in 1..=3 {
+            cluster.spawn(id);
+        }
+        cluster
+    }
+
+    /// Start node `id`, which picks up whatever it saved before.
+    fn spawn(&mut self, id: u64) {
+        let peers = (1..=3)
+            .map(|id| format!("{}={}", id, self.addr(id)))
+            .collect::<Vec<_>>()
+            .join(",");
+        let port = self.first_port + id as u16 - 1;
+        let data_dir = self.dir.join(id.to_string());
+        let child = Command::new(env!("CARGO_BIN_EXE_lockserver"))
+            .args(["--bind", "127.0.0.1", "--port", &port.to_string()])
+            .args(["--node-id", &id.to_string(), "--peers", &peers])
+            .args(["--data-dir".as_ref(), data_dir.as_os_str()])
+            .env("LOCKSERVER_SECRET", SECRET)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start lockserver");
+        self.nodes.insert(id, child);
+    }
+
+    fn kill(&mut self, id: u64) {
+        if let Some(mut child) = self.nodes.remove(&id) {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+
+    fn addr(&self, id: u64) -> String {
+        format!("127.0.0.1:{}", self.first_port + id as u16 - 1)
+    }
+
+    fn post(&self, id: u64, path: &str, body: serde_json::Value) -> reqwest::blocking::Response {
+        reqwest::blocking::Client::new()
+            .post(format!("http://{}{}", self.addr(id), path))
+            .header("X-LOCKSERVER-SECRET", SECRET)
+            .json(&body)
+            .send()
+            .expect("request failed")
+    }
+
+    /// Wait until a running node reports itself leader and return its id.
+    fn leader(&self) -> u64 {
+        let client = reqwest::blocking::Client::new();
+        let deadline = Instant::now() + Duration::from_secs(15);
+        while Instant::now() < deadline {
+            for &id in self.nodes.keys() {
+                let status = client
+                    .get(format!("http://{}/cluster/status", self.addr(id)))
+                    .header("X-LOCKSERVER-SECRET", SECRET)
+                    .send()
+                    .and_then(|resp| resp.json::<serde_json::Value>());
+                if let Ok(status) = status
+                    && status["role"] == "leader"
+                {
+                    return id;
+                }
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        panic!("no leader elected");
+    }
+}
+
+impl Drop for Cluster {
+    fn drop(&mut self) {
+        for (_, child) in self.nodes.iter_mut() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[test]
+fn test_cluster_survives_leader_failure() {
+    let mut cluster = Cluster::start(18081);
+    let leader = cluster.leader();
+    let follower = if leader == 1 { 2 } else { 1 };
+
+    // Followers redirect to the leader; the client follows the redirect.
+    let resp = cluster.post(
+        follower,
+        "/acquire",
+        serde_json::json!({"resource": "res", "owner": "a"}),
+    );
+    assert!(resp.status().is_success());
+    let token = resp.json::<serde_json::Value>().unwrap()["token"]
+        .as_u64()
+        .unwrap();
+    let resp = cluster.post(
+        leader,
+        "/acquire",
+        serde_json::json!({"resource": "res", "owner": "b"}),
+    );
+    assert_eq!(resp.status(), 409);
+
+    // The lock survives losing the leader.
+    cluster.kill(leader);
+    let new_leader = cluster.leader();
+    assert_ne!(new_leader, leader);
+    let resp = cluster.post(
+        new_leader,
+        "/acquire",
+        serde_json::json!({"resource": "res", "owner": "b"}),
+    );
+    assert_eq!(resp.status(), 409);
+
+    let resp = cluster.post(
+        new_leader,
+        "/release",
+        serde_json::json!({"resource": "res", "owner": "a"}),
+    );
+    assert!(resp.status().is_success());
+    let resp = cluster.post(
+        new_leader,
+        "/acquire",
+        serde_json::json!({"resource": "res", "owner": "b"}),
+    );
+    assert!(resp.status().is_success());
+    let next = resp.json::<serde_json::Value>().unwrap()["token"]
+        .as_u64()
+        .unwrap();
+    assert!(next > token);
+}
+
+#[test]
+fn test_cluster_keeps_committed_locks_across_restarts() {
+    let mut cluster = Cluster::start(18085);
+    let token = |resp: reqwest::blocking::Response| {
+        assert!(resp.status().is_success());
+        resp.json::<serde_json::Value>().unwrap()["token"]
+            .as_u64()
+            .unwrap()
+    };
+    let leader = cluster.leader();
+    let kept = token(cluster.post(
+        leader,
+        "/acquire",
+        serde_json::json!({"resource": "kept", "owner": "a"}),
+    ));
+
+    // A follower misses a lock while it is down, and must be caught up once it is back
+    // to form a majority with the leader.
+    let (down, other) = match leader {
+        1 => (2, 3),
+        2 => (1, 3),
+        _ => (1, 2),
+    };
+    cluster.kill(down);
+    let late = token(cluster.post(
+        leader,
+        "/acquire",
+        serde_json::json!({"resource": "late", "owner": "a"}),
+    ));
+    assert!(late > kept);
+    cluster.spawn(down);
+    cluster.kill(other);
+    let resp = cluster.post(
+        cluster.leader(),
+        "/acquire",
+        serde_json::json!({"resource": "late", "owner": "b"}),
+    );
+    assert_eq!(resp.status(), 409);
+
+    // Restarting every node loses nothing that was committed.
+    for id in 1..=3 {
+        cluster.kill(id);
+    }
+    for id in 1..=3 {
+        cluster.spawn(id);
+    }
+    let leader = cluster.leader();
+    for resource in ["kept", "late"] {
+        let resp = cluster.post(
+            leader,
+            "/acquire",
+            serde_json::json!({"resource": resource, "owner": "b"}),
+        );
+        assert_eq!(resp.status(), 409, "{} was lost", resource);
+    }
+    let next = token(cluster.post(
+        leader,
+        "/acquire",
+        serde_json::json!({"resource": "fresh", "owner": "b"}),
+    ));
+    assert!(next > late);
+}
+
+#[test]
+fn test_cluster_node_requires_secret() {
+    let dir = std::env::temp_dir().join(format!("lockserver-nosecret-{}", std::process::id()));
+    for secret in [None, Some("changeme")] {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_lockserver"));
+        command
+            .args(["--bind", "127.0.0.1", "--port", "18090"])
+            .args(["--node-id", "1", "--peers", "1=127.0.0.1:18090"])
+            .args(["--data-dir".as_ref(), dir.as_os_str()])
+            .env_remove("LOCKSERVER_SECRET")
+            .stdout(Stdio::null())
+            .stderr(Stdio::null());
+        if let Some(secret) = secret {
+            command.env("LOCKSERVER_SECRET", secret);
+        }
+        let mut child = command.spawn().expect("failed to start lockserver");
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let status = loop {
+            if let Some(status) = child.try_wait().unwrap() {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                panic!("node started with secret {:?}", secret);
+            }
+            thread::sleep(Duration::from_millis(50));
+        };
+        assert!(!status.success());
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_cluster_expiry_ignores_wall_clock_steps() {
+    let clock = Arc::new(ManualClock::new());
+    let config = ClusterConfig {
+        id: 1,
+        nodes: HashMap::from([(1, "127.0.0.1:18084".to_string())]),
+        secret: SECRET.to_string(),
+        data_dir: None,
+    };
+    let node = RaftNode::with_clock(config, clock.clone()).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while node.status().role != Role::Leader {
+        assert!(Instant::now() < deadline, "no leader elected");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let acquire = |owner: &str| {
+        raft::Command::acquire(
+            "wall_step",
+            owner,
+            LockKind::Exclusive,
+            Some(Duration::from_secs(10)),
+            None,
+            false,
+        )
+        .unwrap()
+    };
+
+    // The lock is taken while the leader's wall clock is an hour fast...
+    clock.set_wall(SystemTime::now() + Duration::from_secs(3600));
+    node.propose(acquire("a")).await.unwrap();
+    // ...and the correction back must not cut its ttl short.
+    clock.set_wall(SystemTime::now());
+    clock.advance(Duration::from_secs(9));
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(matches!(
+        node.propose(acquire("b")).await,
+        Err(RaftError::Lock(LockError::AlreadyLocked))
+    ));
+
+    // Once the ttl has passed on the monotonic clock, the leader expires it.
+    clock.advance(Duration::from_secs(2));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match node.propose(acquire("b")).await {
+            Ok(_) => break,
+            Err(RaftError::Lock(LockError::AlreadyLocked)) => {}
+            Err(e) => panic!("unexpected error: {e}"),
+        }
+        assert!(Instant::now() < deadline, "lock did not expire");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+/// Start the single node of `config`, once it has elected itself.
+async fn leader(config: ClusterConfig) -> Arc<RaftNode> {
+    let node = RaftNode::start(config).unwrap();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while node.status().role != Role::Leader {
+        assert!(Instant::now() < deadline, "no leader elected");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    node
+}
+
+#[test]
+fn test_compacted_log_is_restored() {
+    let dir = std::env::temp_dir().join(format!("lockserver-compact-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let config = ClusterConfig {
+        id: 1,
+        nodes: HashMap::from([(1, "127.0.0.1:18088".to_string())]),
+        secret: SECRET.to_string(),
+        data_dir: Some(dir.clone()),
+    };
+    let acquire = |resource: &str| {
+        raft::Command::acquire(resource, "a", LockKind::Exclusive, None, None, false).unwrap()
+    };
+
+    // Enough changes that the log is compacted into a snapshot along the way.
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let last = runtime.block_on(async {
+        let node = leader(config.clone()).await;
+        node.propose(acquire("compacted")).await.unwrap();
+        for _ in 0..600 {
+            node.propose(acquire("churn")).await.unwrap();
+            node.propose(raft::Command::release("churn", "a"))
+                .await
+                .unwrap();
+        }
+        node.propose(acquire("recent")).await.unwrap().unwrap()
+    });
+    drop(runtime);
+    assert!(dir.join("raft_snapshot.json").exists());
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let node = leader(config).await;
+        for resource in ["compacted", "recent"] {
+            assert!(matches!(
+                node.propose(acquire(resource)).await,
+                Err(RaftError::Lock(LockError::AlreadyLocked))
+            ));
+        }
+        let next = node.propose(acquire("fresh")).await.unwrap().unwrap();
+        assert!(next > last);
+    });
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_torn_log_tail_is_cut_off() {
+    let dir = std::env::temp_dir().join(format!("lockserver-torn-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let config = ClusterConfig {
+        id: 1,
+        nodes: HashMap::from([(1, "127.0.0.1:18089".to_string())]),
+        secret: SECRET.to_string(),
+        data_dir: Some(dir.clone()),
+    };
+    let acquire = |resource: &str| {
+        raft::Command::acquire(resource, "a", LockKind::Exclusive, None, None, false).unwrap()
+    };
+    let run = |resource: &'static str| {
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async {
+            let node = leader(config.clone()).await;
+            node.propose(acquire(resource)).await.unwrap();
+        });
+    };
+
+    // A crash mid-write leaves half a record at the end of the log...
+    run("before");
+    let mut log = fs::OpenOptions::new()
+        .append(true)
+        .open(dir.join("raft.log"))
+        .unwrap();
+    std::io::Write::write_all(&mut log, b"{\"index\":").unwrap();
+    drop(log);
+    // ...which must not swallow what is logged after the restart.
+    run("after");
+
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let node = leader(config.clone()).await;
+        for resource in ["before", "after"] {
+            assert!(
+                matches!(
+                    node.propose(acquire(resource)).await,
+                    Err(RaftError::Lock(LockError::AlreadyLocked))
+                ),
+                "{} was lost",
+                resource
+            );
+        }
+    });
+    let _ = fs::remove_dir_all(&dir);
+}
+
+/// An in-process node's Raft RPCs, served over HTTP. Requests to or from a node in `cut`
+/// fail, as if the network between them were down.
+struct Peer {
+    id: u64,
+    node: Arc<RaftNode>,
+    cut: Arc<Mutex<HashSet<u64>>>,
+}
+
+impl Peer {
+    fn reachable(&self, from: u64) -> bool {
+        let cut = self.cut.lock().unwrap();
+        !cut.contains(&self.id) && !cut.contains(&from)
+    }
+}
+
+async fn peer_vote(peer: web::Data<Peer>, req: web::Json<VoteRequest>) -> HttpResponse {
+    match peer.reachable(req.candidate_id) {
+        true => HttpResponse::Ok().json(peer.node.handle_vote(req.into_inner())),
+        false => HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+async fn peer_append(peer: web::Data<Peer>, req: web::Json<AppendRequest>) -> HttpResponse {
+    match peer.reachable(req.leader_id) {
+        true => HttpResponse::Ok().json(peer.node.handle_append(req.into_inner())),
+        false => HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+async fn peer_snapshot(peer: web::Data<Peer>, req: web::Json<SnapshotRequest>) -> HttpResponse {
+    match peer.reachable(req.leader_id) {
+        true => HttpResponse::Ok().json(peer.node.handle_snapshot(req.into_inner())),
+        false => HttpResponse::ServiceUnavailable().finish(),
+    }
+}
+
+/// Serve `peer` on `addr` from a background thread.
+fn serve(peer: Peer, addr: String) {
+    let peer = web::Data::new(peer);
+    thread::spawn(move || {
+        actix_web::rt::System::new().block_on(async move {
+            HttpServer::new(move || {
+                App::new()
+                    .app_data(peer.clone())
+                    .route(raft::VOTE_PATH, web::post().to(peer_vote))
+                    .route(raft::APPEND_PATH, web::post().to(peer_append))
+                    .route(raft::SNAPSHOT_PATH, web::post().to(peer_snapshot))
+            })
+            .workers(2)
+            .bind(addr)
+            .unwrap()
+            .run()
+            .await
+        })
+    });
+}
+
+/// Propose `command` on whichever of `ids` is leader, retrying until one takes it.
+/// Returns the node that did, and the outcome.
+async fn propose_on(
+    nodes: &HashMap<u64, Arc<RaftNode>>,
+    ids: &[u64],
+    command: raft::Command,
+) -> (u64, Result<Option<u64>, RaftError>) {
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        for id in ids {
+            if nodes[id].status().role != Role::Leader {
+                continue;
+            }
+            match nodes[id].propose(command.clone()).await {
+                Err(RaftError::NotLeader(_)) | Err(RaftError::Lock(LockError::Internal(_))) => {}
+                result => return (*id, result),
+            }
+        }
+        assert!(Instant::now() < deadline, "no leader among {:?}", ids);
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_failover_to_node_with_clock_ahead() {
+    let addrs: HashMap<u64, String> = (1..=3)
+        .map(|id| (id, format!("127.0.0.1:{}", 18140 + id)))
+        .collect();
+    let cut = Arc::new(Mutex::new(HashSet::from([3])));
+    let clocks: HashMap<u64, Arc<ManualClock>> = (1..=3)
+        .map(|id| (id, Arc::new(ManualClock::new())))
+        .collect();
+    let mut nodes = HashMap::new();
+    for id in 1..=3 {
+        let config = ClusterConfig {
+            id,
+            nodes: addrs.clone(),
+            secret: SECRET.to_string(),
+            data_dir: None,
+        };
+        let node = RaftNode::with_clock(config, clocks[&id].clone()).unwrap();
+        let cut = cut.clone();
+        serve(
+            Peer {
+                id,
+                node: node.clone(),
+                cut,
+            },
+            addrs[&id].clone(),
+        );
+        nodes.insert(id, node);
+    }
+    // Node 3 has been up a minute longer than the others.
+    clocks[&3].advance(Duration::from_secs(60));
+    let acquire = |owner: &str| {
+        raft::Command::acquire(
+            "failover",
+            owner,
+            LockKind::Exclusive,
+            Some(Duration::from_secs(30)),
+            None,
+            false,
+        )
+        .unwrap()
+    };
+
+    // The lock is taken on the timeline of a leader that started later...
+    let (_, result) = propose_on(&nodes, &[1, 2], acquire("a")).await;
+    result.unwrap();
+    cut.lock().unwrap().clear();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while nodes[&3].manager().inspect("failover").is_none() {
+        assert!(Instant::now() < deadline, "node 3 did not catch up");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    // ...and node 3 takes over: it alone has the latest entry once 1 is cut off.
+    *cut.lock().unwrap() = HashSet::from([2]);
+    let marker = raft::Command::acquire("marker", "a", LockKind::Exclusive, None, None, false);
+    let (_, result) = propose_on(&nodes, &[1, 3], marker.unwrap()).await;
+    result.unwrap();
+    *cut.lock().unwrap() = HashSet::from([1]);
+    let (leader, result) = propose_on(&nodes, &[2, 3], acquire("b")).await;
+    assert_eq!(leader, 3);
+    // Its minute ahead must not cut the lock's ttl short.
+    assert!(matches!(
+        result,
+        Err(RaftError::Lock(LockError::AlreadyLocked))
+    ));
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(matches!(
+        nodes[&3].propose(acquire("b")).await,
+        Err(RaftError::Lock(LockError::AlreadyLocked))
+    ));
+
+    // Once the ttl has passed, the new leader expires it.
+    clocks[&3].advance(Duration::from_secs(31));
+    let deadline = Instant::now() + Duration::from_secs(5);
+    loop {
+        match nodes[&3].propose(acquire("b")).await {
+            Ok(_) => break,
+            Err(RaftError::Lock(LockError::AlreadyLocked)) => {}
+            Err(e) => panic!("unexpected error: {e}"),
+        }
+        assert!(Instant::now() < deadline, "lock did not expire");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+}
@@ -0,0 +1,137 @@
+use actix_web::http::StatusCode;
+use actix_web::{App, HttpResponse, HttpServer, web};
+use lockserver::{LockGuard, LockserverClient};
+use serde_json::json;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What the mock server answers to keep-alive requests, and how many it has seen.
+#[derive(Default)]
+struct Script {
+    /// Error codes to answer with, in order, before answering with success.
+    failures: Vec<&'static str>,
+    /// Keep-alive requests answered with success.
+    succeeded: usize,
+}
+
+type State = web::Data<Mutex<Script>>;
+
+/// Answer a keep-alive request with the next scripted failure, if any.
+async fn keep(state: State) -> HttpResponse {
+    let mut script = state.lock().unwrap();
+    if script.failures.is_empty() {
+        script.succeeded += 1;
+        return HttpResponse::Ok().json(json!({}));
+    }
+    let code = script.failures.remove(0);
+    let status = match code {
+        "session_not_found" | "not_found" => StatusCode::NOT_FOUND,
+        "not_owner" => StatusCode::CONFLICT,
+        _ => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    HttpResponse::build(status).json(json!({"code": code, "message": code}))
+}
+
+async fn ok() -> HttpResponse {
+    HttpResponse::Ok().json(json!({}))
+}
+
+async fn create_session() -> HttpResponse {
+    HttpResponse::Ok().json(json!({"session": "mock-session"}))
+}
+
+/// Serve a `/v1` API on `port` whose keep-alive answers follow `script`.
+fn start_server(port: u16, script: Script) -> (String, State) {
+    let addr = format!("127.0.0.1:{}", port);
+    let state: State = web::Data::new(Mutex::new(script));
+    let data = state.clone();
+    thread::spawn(move || {
+        actix_web::rt::System::new().block_on(async move {
+            HttpServer::new(move || {
+                App::new().app_data(data.clone()).service(
+                    web::scope("/v1")
+                        .route("", web::get().to(ok))
+                        .route("/session", web::post().to(create_session))
+                        .route("/session/heartbeat", web::post().to(keep))
+                        .route("/session/close", web::post().to(ok))
+                        .route("/locks/{resource}", web::patch().to(keep))
+                        .route("/locks/{resource}", web::delete().to(ok)),
+                )
+            })
+            .workers(2)
+            .bind(("127.0.0.1", port))
+            .unwrap()
+            .run()
+            .await
+        })
+    });
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while std::net::TcpStream::connect(&addr).is_err() {
+        assert!(Instant::now() < deadline, "mock server did not start");
+        thread::sleep(Duration::from_millis(50));
+    }
+    (addr, state)
+}
+
+#[test]
+fn test_session_heartbeat_retries_failures() {
+    let script = Script {
+        failures: vec!["unavailable"; 3],
+        ..Script::default()
+    };
+    let (addr, state) = start_server(18151, script);
+    let client = LockserverClient::new_with_env(Some(addr), Some("owner"), None::<String>);
+    let session = client.open_session(3).unwrap();
+    thread::sleep(Duration::from_millis(3000));
+    let script = state.lock().unwrap();
+    assert!(script.failures.is_empty());
+    assert!(script.succeeded > 0, "heartbeat gave up after a failure");
+    drop(script);
+    drop(session);
+}
+
+#[test]
+fn test_session_heartbeat_stops_when_session_gone() {
+    let script = Script {
+        failures: vec!["session_not_found"; 2],
+        ..Script::default()
+    };
+    let (addr, state) = start_server(18152, script);
+    let client = LockserverClient::new_with_env(Some(addr), Some("owner"), None::<String>);
+    let session = client.open_session(3).unwrap();
+    thread::sleep(Duration::from_millis(2500));
+    assert_eq!(state.lock().unwrap().failures.len(), 1);
+    drop(session);
+}
+
+#[test]
+fn test_lock_renewal_retries_failures() {
+    let script = Script {
+        failures: vec!["unavailable"; 3],
+        ..Script::default()
+    };
+    let (addr, state) = start_server(18153, script);
+    let client = LockserverClient::new_with_env(Some(addr), Some("owner"), None::<String>);
+    let guard = LockGuard::new(&client, "res", 1).keep_alive(3);
+    thread::sleep(Duration::from_millis(3000));
+    let script = state.lock().unwrap();
+    assert!(script.failures.is_empty());
+    assert!(script.succeeded > 0, "renewal gave up after a failure");
+    drop(script);
+    drop(guard);
+}
+
+#[test]
+fn test_lock_renewal_stops_when_lock_lost() {
+    let script = Script {
+        failures: vec!["not_owner", "not_found", "not_found"],
+        ..Script::default()
+    };
+    let (addr, state) = start_server(18154, script);
+    let client = LockserverClient::new_with_env(Some(addr), Some("owner"), None::<String>);
+    let guard = LockGuard::new(&client, "res", 1).keep_alive(3);
+    thread::sleep(Duration::from_millis(2500));
+    assert_eq!(state.lock().unwrap().failures.len(), 2);
+    drop(guard);
+}
@@ -0,0 +1,156 @@
+use actix_web::{App, HttpRequest, HttpResponse, HttpServer, web};
+use lockserver::client::LockMode;
+use lockserver::{ClientError, LockserverClient};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::process::{Child, Command, Stdio};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SECRET: &str = "legacy-test-secret";
+
+type Locks = web::Data<Mutex<HashMap<String, String>>>;
+
+fn authorized(req: &HttpRequest) -> bool {
+    req.headers()
+        .get("X-LOCKSERVER-SECRET")
+        .is_some_and(|v| v == SECRET)
+}
+
+/// `POST /acquire` as servers before `/v1` answered it: plain-text bodies, no tokens.
+async fn legacy_acquire(locks: Locks, body: web::Json<Value>, req: HttpRequest) -> HttpResponse {
+    if !authorized(&req) {
+        return HttpResponse::Unauthorized().body("Missing or invalid secret");
+    }
+    let resource = body["resource"].as_str().unwrap_or_default().to_string();
+    let owner = body["owner"].as_str().unwrap_or_default().to_string();
+    let mut locks = locks.lock().unwrap();
+    if locks.contains_key(&resource) {
+        return HttpResponse::Conflict().body("ERR Resource is already locked");
+    }
+    locks.insert(resource, owner);
+    HttpResponse::Ok().body("OK")
+}
+
+/// `POST /release` as servers before `/v1` answered it.
+async fn legacy_release(locks: Locks, body: web::Json<Value>, req: HttpRequest) -> HttpResponse {
+    if !authorized(&req) {
+        return HttpResponse::Unauthorized().body("Missing or invalid secret");
+    }
+    let resource = body["resource"].as_str().unwrap_or_default();
+    let owner = body["owner"].as_str().unwrap_or_default();
+    let mut locks = locks.lock().unwrap();
+    if locks.get(resource).is_none_or(|holder| holder != owner) {
+        return HttpResponse::Conflict().body("ERR Resource not found");
+    }
+    locks.remove(resource);
+    HttpResponse::Ok().body("OK")
+}
+
+/// Serve the pre-`/v1` API on `port` from a background thread.
+fn start_legacy_server(port: u16) -> String {
+    let addr = format!("127.0.0.1:{}", port);
+    thread::spawn(move || {
+        actix_web::rt::System::new().block_on(async move {
+            let locks: Locks = web::Data::new(Mutex::new(HashMap::new()));
+            HttpServer::new(move || {
+                App::new()
+                    .app_data(locks.clone())
+                    .route("/acquire", web::post().to(legacy_acquire))
+                    .route("/release", web::post().to(legacy_release))
+            })
+            .workers(2)
+            .bind(("127.0.0.1", port))
+            .unwrap()
+            .run()
+            .await
+        })
+    });
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while std::net::TcpStream::connect(&addr).is_err() {
+        assert!(Instant::now() < deadline, "legacy server did not start");
+        thread::sleep(Duration::from_millis(50));
+    }
+    addr
+}
+
+#[test]
+fn test_client_falls_back_to_legacy_server() {
+    let addr = start_legacy_server(18131);
+    let a = LockserverClient::new(&addr, "worker_a", SECRET);
+    let b = LockserverClient::new(&addr, "worker_b", SECRET);
+
+    // No fencing tokens before `/v1`.
+    assert_eq!(
+        a.acquire_with_mode("legacy/res", LockMode::NonBlocking)
+            .unwrap(),
+        0
+    );
+    assert!(matches!(
+        b.acquire_with_mode("legacy/res", LockMode::NonBlocking),
+        Err(ClientError::Locked)
+    ));
+    assert!(matches!(
+        b.release("legacy/res"),
+        Err(ClientError::NotFound)
+    ));
+
+    // A blocking acquire retries until the lock is released.
+    let waiter = thread::spawn(move || b.acquire("legacy/res"));
+    thread::sleep(Duration::from_millis(300));
+    a.release("legacy/res").unwrap();
+    assert_eq!(waiter.join().unwrap().unwrap(), 0);
+
+    let intruder = LockserverClient::new(&addr, "worker_c", "wrong-secret");
+    assert!(matches!(
+        intruder.acquire_with_mode("legacy/other", LockMode::NonBlocking),
+        Err(ClientError::Unauthorized(_))
+    ));
+}
+
+/// A lockserver process; killed on drop.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+#[test]
+fn test_unversioned_routes_take_nested_resource_names() {
+    let port = 18132;
+    let _server = Server(
+        Command::new(env!("CARGO_BIN_EXE_lockserver"))
+            .args(["--bind", "127.0.0.1", "--port", &port.to_string()])
+            .env("LOCKSERVER_SECRET", SECRET)
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start lockserver"),
+    );
+    let url = |path: &str| format!("http://127.0.0.1:{}{}", port, path);
+    let client = reqwest::blocking::Client::new();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while client.get(url("/v1")).send().is_err() {
+        assert!(Instant::now() < deadline, "lockserver did not start");
+        thread::sleep(Duration::from_millis(50));
+    }
+
+    let resp = client
+        .post(url("/acquire"))
+        .header("X-LOCKSERVER-SECRET", SECRET)
+        .json(&serde_json::json!({"resource": "jobs/nightly", "owner": "a"}))
+        .send()
+        .unwrap();
+    assert!(resp.status().is_success());
+    let resp = client
+        .get(url("/locks/jobs/nightly"))
+        .header("X-LOCKSERVER-SECRET", SECRET)
+        .send()
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let status: Value = resp.json().unwrap();
+    assert_eq!(status["resource"], "jobs/nightly");
+}
@@ -1,4 +1,19 @@
-use lockserver::LockManager;
+use lockserver::{LockError, LockKind, LockManager, ManualClock};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A lock manager whose time only moves when the returned clock is advanced.
+fn manual_manager() -> (Arc<ManualClock>, LockManager) {
+    let clock = Arc::new(ManualClock::new());
+    let manager = LockManager::with_manual_expiry(clock.clone());
+    (clock, manager)
+}
+
+/// Advance `clock` by `ms` milliseconds and run any expiry that became due.
+fn advance(clock: &ManualClock, manager: &LockManager, ms: u64) {
+    clock.advance(Duration::from_millis(ms));
+    manager.run_expiry();
+}
 
 #[test]
 fn test_acquire_and_release() {
@@ -11,16 +26,42 @@ fn test_acquire_and_release() {
 
 #[test]
 fn test_expire_lock() {
-    let manager = LockManager::new();
+    let (clock, manager) = manual_manager();
     // Acquire with 2 second expiration
     assert!(manager.acquire("res_exp", "owner_exp", Some(2)).is_ok());
     assert!(manager.is_locked("res_exp"));
-    // Wait 3 seconds
-    std::thread::sleep(std::time::Duration::from_secs(3));
-    // Should be auto-released
+    advance(&clock, &manager, 1999);
+    assert!(manager.is_locked("res_exp"));
+    // Should be auto-released once the deadline is reached
+    advance(&clock, &manager, 1);
     assert!(!manager.is_locked("res_exp"));
 }
 
+#[test]
+fn test_expiry_is_exact_to_the_millisecond() {
+    let (clock, manager) = manual_manager();
+    let ttls: Vec<u64> = (1..=50).chain([250, 999, 1000, 1001]).collect();
+    for ttl in &ttls {
+        manager
+            .acquire_with_ttl(
+                &format!("ms_{ttl}"),
+                "w1",
+                Some(Duration::from_millis(*ttl)),
+            )
+            .unwrap();
+    }
+    for now in 1..=1001 {
+        advance(&clock, &manager, 1);
+        for ttl in &ttls {
+            assert_eq!(
+                manager.is_locked(&format!("ms_{ttl}")),
+                now < *ttl,
+                "ttl {ttl}ms at {now}ms"
+            );
+        }
+    }
+}
+
 #[test]
 fn test_acquire_twice() {
     let manager = LockManager::new();
@@ -32,6 +73,811 @@ fn test_acquire_twice() {
 fn test_release_wrong_owner() {
     let manager = LockManager::new();
     assert!(manager.acquire("res1", "owner1", None).is_ok());
-    assert!(manager.release("res1", "owner2").is_err());
+    assert!(matches!(
+        manager.release("res1", "owner2"),
+        Err(LockError::NotOwner)
+    ));
     assert!(manager.is_locked("res1"));
+    assert!(matches!(
+        manager.release("res2", "owner1"),
+        Err(LockError::NotFound)
+    ));
+}
+
+#[test]
+fn test_fencing_tokens_increase() {
+    let manager = LockManager::new();
+    let first = manager.acquire("res1", "owner1", None).unwrap();
+    assert!(manager.release("res1", "owner1").is_ok());
+    let second = manager.acquire("res1", "owner2", None).unwrap();
+    let other = manager.acquire("res2", "owner1", None).unwrap();
+    assert!(first < second);
+    assert!(second < other);
+}
+
+#[test]
+fn test_renew_lock() {
+    let (clock, manager) = manual_manager();
+    assert!(manager.acquire("res_renew", "owner1", Some(1)).is_ok());
+    assert!(matches!(
+        manager.renew("res_renew", "owner2", 5),
+        Err(LockError::NotOwner)
+    ));
+    assert!(manager.renew("res_renew", "owner1", 5).is_ok());
+    // Past the original expiration, but within the renewed one
+    advance(&clock, &manager, 2000);
+    assert!(manager.is_locked("res_renew"));
+    assert!(manager.renew("missing", "owner1", 5).is_err());
+    advance(&clock, &manager, 3000);
+    assert!(!manager.is_locked("res_renew"));
+}
+
+#[test]
+fn test_wait_queue_is_fifo() {
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+    use std::time::Duration;
+
+    let manager = Arc::new(LockManager::new());
+    assert!(manager.acquire("res_fifo", "holder", None).is_ok());
+    let order = Arc::new(Mutex::new(Vec::new()));
+    let mut handles = Vec::new();
+    for name in ["first", "second"] {
+        let manager = manager.clone();
+        let order = order.clone();
+        handles.push(thread::spawn(move || {
+            manager
+                .acquire_wait("res_fifo", name, None, Duration::from_secs(5))
+                .unwrap();
+            order.lock().unwrap().push(name);
+            thread::sleep(Duration::from_millis(50));
+            manager.release("res_fifo", name).unwrap();
+        }));
+        // Make sure waiters enqueue in a known order
+        thread::sleep(Duration::from_millis(100));
+    }
+    // A new arrival must not overtake queued waiters
+    assert!(manager.acquire("res_fifo", "latecomer", None).is_err());
+    assert!(manager.release("res_fifo", "holder").is_ok());
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(*order.lock().unwrap(), vec!["first", "second"]);
+    assert!(!manager.is_locked("res_fifo"));
+}
+
+#[test]
+fn test_wait_timeout() {
+    use std::time::Duration;
+
+    let manager = LockManager::new();
+    assert!(manager.acquire("res_wait", "owner1", None).is_ok());
+    assert!(
+        manager
+            .acquire_wait("res_wait", "owner2", None, Duration::from_millis(100))
+            .is_err()
+    );
+    // The timed-out waiter must have left the queue
+    assert!(manager.release("res_wait", "owner1").is_ok());
+    assert!(manager.acquire("res_wait", "owner3", None).is_ok());
+}
+
+#[test]
+fn test_shared_and_exclusive() {
+    let manager = LockManager::new();
+    let shared = LockKind::Shared;
+    assert!(
+        manager
+            .acquire_with_kind("res_rw", "r1", shared, None)
+            .is_ok()
+    );
+    assert!(
+        manager
+            .acquire_with_kind("res_rw", "r2", shared, None)
+            .is_ok()
+    );
+    assert!(manager.acquire("res_rw", "w1", None).is_err());
+    assert!(manager.release("res_rw", "r1").is_ok());
+    // Still held by the remaining reader
+    assert!(manager.is_locked("res_rw"));
+    assert!(manager.acquire("res_rw", "w1", None).is_err());
+    assert!(manager.release("res_rw", "r2").is_ok());
+    assert!(manager.acquire("res_rw", "w1", None).is_ok());
+    assert!(
+        manager
+            .acquire_with_kind("res_rw", "r1", shared, None)
+            .is_err()
+    );
+}
+
+#[test]
+fn test_waiting_writer_blocks_new_readers() {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    let manager = Arc::new(LockManager::new());
+    let shared = LockKind::Shared;
+    assert!(
+        manager
+            .acquire_with_kind("res_pref", "r1", shared, None)
+            .is_ok()
+    );
+    let writer = {
+        let manager = manager.clone();
+        thread::spawn(move || {
+            manager
+                .acquire_wait("res_pref", "w1", None, Duration::from_secs(5))
+                .unwrap();
+        })
+    };
+    thread::sleep(Duration::from_millis(100));
+    // The queued writer keeps a new reader out even though only readers hold the lock
+    assert!(
+        manager
+            .acquire_with_kind("res_pref", "r2", shared, None)
+            .is_err()
+    );
+    assert!(manager.release("res_pref", "r1").is_ok());
+    writer.join().unwrap();
+    assert!(manager.release("res_pref", "w1").is_ok());
+    assert!(
+        manager
+            .acquire_with_kind("res_pref", "r2", shared, None)
+            .is_ok()
+    );
+}
+
+#[test]
+fn test_semaphore_permits() {
+    let manager = LockManager::new();
+    assert!(manager.acquire_permits("bucket", "w1", 2, 3, None).is_ok());
+    assert!(manager.acquire_permits("bucket", "w2", 2, 3, None).is_err());
+    assert!(manager.acquire_permits("bucket", "w2", 1, 3, None).is_ok());
+    assert_eq!(manager.permits_in_use("bucket"), 3);
+    // Limit must match while the semaphore is in use
+    assert!(manager.acquire_permits("bucket", "w3", 1, 5, None).is_err());
+    assert!(manager.release_permits("bucket", "w1").is_ok());
+    assert!(manager.release_permits("bucket", "w1").is_err());
+    assert_eq!(manager.permits_in_use("bucket"), 1);
+    assert!(manager.acquire_permits("bucket", "w3", 2, 3, None).is_ok());
+    // Semaphores and locks may share a name without interfering
+    assert!(manager.acquire("bucket", "w1", None).is_ok());
+}
+
+#[test]
+fn test_semaphore_permit_expiry() {
+    let (clock, manager) = manual_manager();
+    assert!(
+        manager
+            .acquire_permits("bucket_exp", "w1", 1, 1, Some(1))
+            .is_ok()
+    );
+    assert!(
+        manager
+            .acquire_permits("bucket_exp", "w2", 1, 1, None)
+            .is_err()
+    );
+    advance(&clock, &manager, 999);
+    assert_eq!(manager.permits_in_use("bucket_exp"), 1);
+    advance(&clock, &manager, 1);
+    assert_eq!(manager.permits_in_use("bucket_exp"), 0);
+    assert!(
+        manager
+            .acquire_permits("bucket_exp", "w2", 1, 1, None)
+            .is_ok()
+    );
+}
+
+#[test]
+fn test_inspect_and_list() {
+    let manager = LockManager::new();
+    assert!(manager.inspect("jobs/a").is_none());
+    manager.acquire("jobs/a", "w1", Some(30)).unwrap();
+    manager
+        .acquire_with_kind("jobs/b", "w1", LockKind::Shared, None)
+        .unwrap();
+    manager
+        .acquire_with_kind("jobs/b", "w2", LockKind::Shared, None)
+        .unwrap();
+    manager.acquire("other", "w3", None).unwrap();
+
+    let status = manager.inspect("jobs/a").unwrap();
+    assert_eq!(status.kind, LockKind::Exclusive);
+    assert_eq!(status.holders.len(), 1);
+    assert_eq!(status.holders[0].owner, "w1");
+    let ttl = status.holders[0].ttl.unwrap();
+    assert!(ttl > 25 && ttl <= 30);
+    assert_eq!(status.waiters, 0);
+    let owners: Vec<_> = manager
+        .inspect("jobs/b")
+        .unwrap()
+        .holders
+        .into_iter()
+        .map(|h| h.owner)
+        .collect();
+    assert_eq!(owners, ["w1", "w2"]);
+
+    let page = manager.list("jobs/", None, 1);
+    assert_eq!(page.locks[0].resource, "jobs/a");
+    assert_eq!(page.next.as_deref(), Some("jobs/a"));
+    let page = manager.list("jobs/", page.next.as_deref(), 1);
+    assert_eq!(page.locks[0].resource, "jobs/b");
+    assert_eq!(page.next, None);
+    assert_eq!(manager.list("", None, 10).locks.len(), 3);
+}
+
+#[test]
+fn test_force_release_and_release_owner() {
+    let manager = LockManager::new();
+    manager
+        .acquire_with_kind("shared_res", "w1", LockKind::Shared, None)
+        .unwrap();
+    manager
+        .acquire_with_kind("shared_res", "w2", LockKind::Shared, None)
+        .unwrap();
+    assert_eq!(manager.force_release("shared_res").unwrap(), ["w1", "w2"]);
+    assert!(!manager.is_locked("shared_res"));
+    assert!(manager.force_release("shared_res").is_err());
+
+    manager.acquire("crashed_a", "crashed", None).unwrap();
+    manager.acquire("crashed_b", "crashed", None).unwrap();
+    manager.acquire("healthy", "w1", None).unwrap();
+    assert_eq!(
+        manager.release_all_for_owner("crashed").unwrap(),
+        ["crashed_a", "crashed_b"]
+    );
+    assert!(!manager.is_locked("crashed_a"));
+    assert!(manager.is_locked("healthy"));
+    assert!(manager.release_all_for_owner("crashed").unwrap().is_empty());
+}
+
+#[test]
+fn test_session_locks() {
+    let (clock, manager) = manual_manager();
+    let session = manager.create_session(2).unwrap();
+    manager
+        .acquire_with_session("sess_a", "w1", LockKind::Exclusive, None, Some(&session))
+        .unwrap();
+    manager
+        .acquire_with_session("sess_b", "w1", LockKind::Exclusive, None, Some(&session))
+        .unwrap();
+    manager.acquire("sess_c", "w1", None).unwrap();
+    assert!(
+        manager
+            .acquire_with_session("sess_d", "w1", LockKind::Exclusive, None, Some("nope"))
+            .is_err()
+    );
+
+    // Heartbeats keep the session and its locks alive.
+    for _ in 0..3 {
+        advance(&clock, &manager, 1500);
+        manager.heartbeat(&session).unwrap();
+    }
+    assert!(manager.is_locked("sess_a"));
+
+    // Once heartbeats stop, the session's locks are released; others are kept.
+    advance(&clock, &manager, 1999);
+    assert!(manager.is_locked("sess_a"));
+    advance(&clock, &manager, 1);
+    assert!(!manager.is_locked("sess_a"));
+    assert!(!manager.is_locked("sess_b"));
+    assert!(manager.is_locked("sess_c"));
+    assert!(manager.heartbeat(&session).is_err());
+
+    let session = manager.create_session(30).unwrap();
+    manager
+        .acquire_with_session("sess_a", "w2", LockKind::Exclusive, None, Some(&session))
+        .unwrap();
+    assert_eq!(manager.close_session(&session).unwrap(), ["sess_a"]);
+    assert!(!manager.is_locked("sess_a"));
+}
+
+#[test]
+fn test_acquire_many_is_all_or_nothing() {
+    let manager = LockManager::new();
+    manager.acquire("many_b", "w2", None).unwrap();
+    assert!(
+        manager
+            .acquire_many(&["many_a", "many_b", "many_c"], "w1", None)
+            .is_err()
+    );
+    assert!(!manager.is_locked("many_a"));
+    assert!(!manager.is_locked("many_c"));
+    assert!(
+        manager
+            .acquire_many(&["many_a", "many_a"], "w1", None)
+            .is_err()
+    );
+
+    manager.release("many_b", "w2").unwrap();
+    let tokens = manager
+        .acquire_many(&["many_a", "many_b", "many_c"], "w1", Some(30))
+        .unwrap();
+    assert_eq!(tokens.len(), 3);
+    assert!(tokens.windows(2).all(|w| w[1] == w[0] + 1));
+    let status = manager.inspect("many_c").unwrap();
+    assert_eq!(
+        status.holders[0].expires_at,
+        manager.inspect("many_a").unwrap().holders[0].expires_at
+    );
+}
+
+#[test]
+fn test_deadlock_detection() {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    let manager = Arc::new(LockManager::new());
+    manager.acquire("dl_a", "w1", None).unwrap();
+    manager.acquire("dl_b", "w2", None).unwrap();
+    // Waiting on a lock you hold can never succeed
+    assert!(matches!(
+        manager.acquire_wait("dl_a", "w1", None, Duration::from_secs(5)),
+        Err(LockError::Deadlock)
+    ));
+
+    let waiter = {
+        let manager = manager.clone();
+        thread::spawn(move || manager.acquire_wait("dl_a", "w2", None, Duration::from_secs(5)))
+    };
+    thread::sleep(Duration::from_millis(100));
+    // w2 waits for w1, so w1 waiting for w2 closes the cycle
+    assert!(matches!(
+        manager.acquire_wait("dl_b", "w1", None, Duration::from_secs(5)),
+        Err(LockError::Deadlock)
+    ));
+    // The other participant is unaffected and gets the lock once w1 backs off
+    manager.release("dl_a", "w1").unwrap();
+    assert!(waiter.join().unwrap().is_ok());
+}
+
+#[test]
+fn test_reentrant_acquire() {
+    let manager = LockManager::new();
+    let exclusive = LockKind::Exclusive;
+    let outer = manager
+        .acquire_reentrant("re_a", "w1", exclusive, None, None)
+        .unwrap();
+    let inner = manager
+        .acquire_reentrant("re_a", "w1", LockKind::Shared, None, None)
+        .unwrap();
+    assert!(inner > outer);
+    assert_eq!(manager.inspect("re_a").unwrap().holders[0].holds, 2);
+    // Without the flag, or for another owner, the lock is still taken
+    assert!(manager.acquire("re_a", "w1", None).is_err());
+    assert!(
+        manager
+            .acquire_reentrant("re_a", "w2", exclusive, None, None)
+            .is_err()
+    );
+
+    manager.release("re_a", "w1").unwrap();
+    assert!(manager.is_locked("re_a"));
+    manager.release("re_a", "w1").unwrap();
+    assert!(!manager.is_locked("re_a"));
+
+    // A shared hold cannot be upgraded by nesting
+    manager
+        .acquire_with_kind("re_b", "w1", LockKind::Shared, None)
+        .unwrap();
+    assert!(
+        manager
+            .acquire_reentrant("re_b", "w1", exclusive, None, None)
+            .is_err()
+    );
+}
+
+#[test]
+fn test_millisecond_expiry() {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let manager = LockManager::new();
+    let start = Instant::now();
+    manager
+        .acquire_with_ttl("ms_res", "w1", Some(Duration::from_millis(150)))
+        .unwrap();
+    let ttl_ms = manager.inspect("ms_res").unwrap().holders[0]
+        .ttl_ms
+        .unwrap();
+    assert!(ttl_ms <= 150 && ttl_ms > 100);
+    while manager.is_locked("ms_res") {
+        thread::sleep(Duration::from_millis(5));
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(150));
+    assert!(
+        elapsed < Duration::from_millis(250),
+        "released after {:?}",
+        elapsed
+    );
+
+    // Renewing pushes the deadline out by the new ttl
+    manager
+        .acquire_with_ttl("ms_res", "w1", Some(Duration::from_millis(100)))
+        .unwrap();
+    manager
+        .renew_with_ttl("ms_res", "w1", Duration::from_secs(30))
+        .unwrap();
+    thread::sleep(Duration::from_millis(200));
+    assert!(manager.is_locked("ms_res"));
+}
+
+/// The system clocks, with the wall clock stepped by `offset_secs`.
+#[derive(Debug, Default)]
+struct SkewedClock {
+    offset_secs: std::sync::atomic::AtomicI64,
+}
+
+impl SkewedClock {
+    fn step(&self, secs: i64) {
+        use std::sync::atomic::Ordering;
+        self.offset_secs.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+impl lockserver::Clock for SkewedClock {
+    fn now(&self) -> std::time::Instant {
+        std::time::Instant::now()
+    }
+
+    fn wall(&self) -> std::time::SystemTime {
+        use std::sync::atomic::Ordering;
+        let offset = self.offset_secs.load(Ordering::SeqCst);
+        let now = std::time::SystemTime::now();
+        let step = std::time::Duration::from_secs(offset.unsigned_abs());
+        if offset < 0 { now - step } else { now + step }
+    }
+}
+
+#[test]
+fn test_expiry_ignores_wall_clock_steps() {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    let clock = Arc::new(SkewedClock::default());
+    let manager = LockManager::with_clock(clock.clone());
+    manager
+        .acquire_with_ttl("skew_short", "w1", Some(Duration::from_millis(300)))
+        .unwrap();
+    manager.acquire("skew_long", "w1", Some(30)).unwrap();
+    let reported = manager.inspect("skew_long").unwrap().holders[0].expires_at;
+
+    // A step back must not keep the lock alive...
+    clock.step(-3600);
+    thread::sleep(Duration::from_millis(500));
+    assert!(!manager.is_locked("skew_short"));
+    // ...and a step forward must not release it early.
+    clock.step(7200);
+    thread::sleep(Duration::from_millis(100));
+    assert!(manager.is_locked("skew_long"));
+    // Reported times follow the wall clock.
+    let status = manager.inspect("skew_long").unwrap();
+    let expires_at = status.holders[0].expires_at.unwrap();
+    assert!(expires_at.abs_diff(reported.unwrap() + 3600) <= 1);
+    assert!(status.holders[0].ttl.unwrap() <= 30);
+}
+
+#[test]
+fn test_shutdown_stops_expiry_worker() {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    let manager = LockManager::new();
+    manager
+        .acquire_with_ttl("shutdown_res", "w1", Some(Duration::from_millis(50)))
+        .unwrap();
+    let started = Instant::now();
+    manager.shutdown().unwrap();
+    assert!(started.elapsed() < Duration::from_millis(500));
+
+    // With the worker gone, nothing expires until expiry is run by hand.
+    thread::sleep(Duration::from_millis(150));
+    assert!(manager.is_locked("shutdown_res"));
+    manager.run_expiry();
+    assert!(!manager.is_locked("shutdown_res"));
+}
+
+#[test]
+fn test_parallel_acquires_on_independent_resources() {
+    use std::collections::HashSet;
+    use std::thread;
+
+    let manager = Arc::new(LockManager::new());
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let manager = manager.clone();
+            thread::spawn(move || {
+                let resource = format!("par_{t}");
+                let owner = format!("w{t}");
+                let mut tokens = Vec::new();
+                for i in 0..500 {
+                    let ttl = (i % 2 == 0).then_some(60);
+                    tokens.push(manager.acquire(&resource, &owner, ttl).unwrap());
+                    manager.release(&resource, &owner).unwrap();
+                }
+                tokens
+            })
+        })
+        .collect();
+    let mut all = HashSet::new();
+    for handle in handles {
+        let tokens = handle.join().unwrap();
+        assert!(tokens.windows(2).all(|w| w[0] < w[1]));
+        all.extend(tokens);
+    }
+    // Fencing tokens stay unique across shards.
+    assert_eq!(all.len(), 8 * 500);
+    assert!(manager.list("par_", None, 100).locks.is_empty());
+}
+
+#[tokio::test]
+async fn test_acquire_async_waits_for_release() {
+    let manager = Arc::new(LockManager::new());
+    manager.acquire("async_res", "w1", None).unwrap();
+    let waiter = {
+        let manager = manager.clone();
+        tokio::spawn(async move {
+            manager
+                .acquire_async(
+                    "async_res",
+                    "w2",
+                    LockKind::Exclusive,
+                    None,
+                    None,
+                    Duration::from_secs(5),
+                )
+                .await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    manager.release("async_res", "w1").unwrap();
+    assert!(waiter.await.unwrap().is_ok());
+    assert!(manager.release("async_res", "w2").is_ok());
+}
+
+#[tokio::test]
+async fn test_acquire_async_timeout_leaves_queue() {
+    let manager = LockManager::new();
+    manager.acquire("async_to", "w1", None).unwrap();
+    let res = manager
+        .acquire_async(
+            "async_to",
+            "w2",
+            LockKind::Exclusive,
+            None,
+            None,
+            Duration::from_millis(50),
+        )
+        .await;
+    assert!(matches!(res, Err(LockError::Timeout)));
+
+    // A cancelled future gives up its place in the queue too.
+    let pending = manager.acquire_async(
+        "async_to",
+        "w3",
+        LockKind::Exclusive,
+        None,
+        None,
+        Duration::from_secs(5),
+    );
+    assert!(
+        tokio::time::timeout(Duration::from_millis(50), pending)
+            .await
+            .is_err()
+    );
+    manager.release("async_to", "w1").unwrap();
+    assert!(manager.acquire("async_to", "w4", None).is_ok());
+}
+
+#[tokio::test]
+async fn test_wait_for_release() {
+    let manager = Arc::new(LockManager::new());
+    manager
+        .wait_for_release("idle", Duration::ZERO)
+        .await
+        .unwrap();
+
+    manager.acquire("watched", "w1", None).unwrap();
+    assert!(matches!(
+        manager
+            .wait_for_release("watched", Duration::from_millis(50))
+            .await,
+        Err(LockError::Timeout)
+    ));
+    let watcher = {
+        let manager = manager.clone();
+        tokio::spawn(async move {
+            manager
+                .wait_for_release("watched", Duration::from_secs(5))
+                .await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    manager.release("watched", "w1").unwrap();
+    assert!(watcher.await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_async_expiry_task() {
+    let manager = LockManager::with_async_expiry();
+    manager
+        .acquire_with_ttl("async_ttl", "w1", Some(Duration::from_millis(50)))
+        .unwrap();
+    manager
+        .wait_for_release("async_ttl", Duration::from_secs(2))
+        .await
+        .unwrap();
+    assert!(!manager.is_locked("async_ttl"));
+    manager.shutdown().unwrap();
+}
+
+#[test]
+fn test_session_ttl_too_long_is_rejected() {
+    let manager = LockManager::new();
+    assert!(matches!(
+        manager.create_session(u64::MAX),
+        Err(LockError::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        manager.create_session(0),
+        Err(LockError::InvalidRequest(_))
+    ));
+}
+
+#[test]
+fn test_permit_expiration_too_long_is_rejected() {
+    let manager = LockManager::new();
+    assert!(matches!(
+        manager.acquire_permits("bucket_max", "w1", 1, 2, Some(u64::MAX)),
+        Err(LockError::InvalidRequest(_))
+    ));
+    // The rejected request took no permits.
+    assert!(
+        manager
+            .acquire_permits("bucket_max", "w2", 2, 2, None)
+            .is_ok()
+    );
+}
+
+#[test]
+fn test_expiration_too_long_is_rejected() {
+    let manager = LockManager::new();
+    assert!(matches!(
+        manager.acquire("ttl_max", "o1", Some(u64::MAX)),
+        Err(LockError::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        manager.acquire_with_ttl("ttl_max", "o1", Some(Duration::MAX)),
+        Err(LockError::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        manager.acquire_many(&["ttl_max", "ttl_max2"], "o1", Some(u64::MAX)),
+        Err(LockError::InvalidRequest(_))
+    ));
+    // Nothing was granted by the rejected requests.
+    assert!(manager.inspect("ttl_max").is_none());
+    manager.acquire("ttl_max", "o1", Some(10)).unwrap();
+    assert!(matches!(
+        manager.renew("ttl_max", "o1", u64::MAX),
+        Err(LockError::InvalidRequest(_))
+    ));
+    // The lock keeps its old expiration.
+    let status = manager.inspect("ttl_max").unwrap();
+    assert!(status.holders[0].ttl.unwrap() <= 10);
+}
+
+#[tokio::test]
+async fn test_wait_timeout_too_long_is_rejected() {
+    let manager = LockManager::new();
+    manager.acquire("wait_max", "w1", None).unwrap();
+    let forever = Duration::from_secs(u64::MAX);
+    assert!(matches!(
+        manager.acquire_wait("wait_max", "w2", None, forever),
+        Err(LockError::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        manager
+            .acquire_async("wait_max", "w2", LockKind::Exclusive, None, None, forever)
+            .await,
+        Err(LockError::InvalidRequest(_))
+    ));
+    assert!(matches!(
+        manager.wait_for_release("wait_max", forever).await,
+        Err(LockError::InvalidRequest(_))
+    ));
+    // Nobody was left queued.
+    manager.release("wait_max", "w1").unwrap();
+    assert!(manager.acquire("wait_max", "w3", None).is_ok());
+}
+
+#[tokio::test]
+async fn test_long_poll_keeps_place_in_queue() {
+    use lockserver::LongPoll;
+
+    let (_clock, manager) = manual_manager();
+    let manager = Arc::new(manager);
+    manager.acquire("poll_res", "holder", None).unwrap();
+    let poll = |timeout| LongPoll {
+        ticket: "t1",
+        timeout,
+    };
+
+    // The first poll times out, but the waiter stays queued...
+    let first = manager
+        .acquire_long_poll(
+            "poll_res",
+            "first",
+            LockKind::Exclusive,
+            None,
+            None,
+            poll(Duration::from_millis(20)),
+        )
+        .await;
+    assert!(matches!(first, Err(LockError::Timeout)));
+    let second = {
+        let manager = manager.clone();
+        tokio::spawn(async move {
+            manager
+                .acquire_async(
+                    "poll_res",
+                    "second",
+                    LockKind::Exclusive,
+                    None,
+                    None,
+                    Duration::from_secs(5),
+                )
+                .await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(manager.inspect("poll_res").unwrap().waiters, 2);
+
+    // ...so polling again goes ahead of a waiter that arrived in between.
+    let first = {
+        let manager = manager.clone();
+        tokio::spawn(async move {
+            manager
+                .acquire_long_poll(
+                    "poll_res",
+                    "first",
+                    LockKind::Exclusive,
+                    None,
+                    None,
+                    poll(Duration::from_secs(5)),
+                )
+                .await
+        })
+    };
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    manager.release("poll_res", "holder").unwrap();
+    assert!(first.await.unwrap().is_ok());
+    assert!(!second.is_finished());
+    manager.release("poll_res", "first").unwrap();
+    assert!(second.await.unwrap().is_ok());
+}
+
+#[tokio::test]
+async fn test_abandoned_long_poll_leaves_queue() {
+    use lockserver::LongPoll;
+
+    let (clock, manager) = manual_manager();
+    manager.acquire("poll_gone", "holder", None).unwrap();
+    let poll = LongPoll {
+        ticket: "t1",
+        timeout: Duration::from_millis(20),
+    };
+    let res = manager
+        .acquire_long_poll("poll_gone", "gone", LockKind::Exclusive, None, None, poll)
+        .await;
+    assert!(matches!(res, Err(LockError::Timeout)));
+    manager.release("poll_gone", "holder").unwrap();
+    // Parked at the head of the queue, the waiter still goes first...
+    assert!(manager.acquire("poll_gone", "other", None).is_err());
+    // ...until it has failed to poll again for too long.
+    advance(&clock, &manager, 60_000);
+    assert!(manager.acquire("poll_gone", "other", None).is_ok());
 }
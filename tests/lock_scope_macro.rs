@@ -1,4 +1,4 @@
-use lockserver::{LockserverClient, lock_scope};
+use lockserver::{LockserverClient, lock_scope, read_scope, write_scope};
 
 #[test]
 
@@ -33,3 +33,54 @@ fn test_lock_scope_non_blocking() {
         });
     });
 }
+
+#[test]
+fn test_read_and_write_scope() {
+    let client = LockserverClient::new_with_env(
+        Some("127.0.0.1:8080"),
+        Some("worker1"),
+        None::<String>, // Use env or default for secret
+    );
+    let _ = std::panic::catch_unwind(|| {
+        read_scope!(&client, "resource_rw", {
+            // shared critical section
+        });
+        write_scope!(&client, "resource_rw", non_blocking, {
+            // exclusive critical section
+        });
+    });
+}
+
+#[test]
+fn test_lock_scope_many() {
+    let client = LockserverClient::new_with_env(
+        Some("127.0.0.1:8080"),
+        Some("worker1"),
+        None::<String>, // Use env or default for secret
+    );
+    lock_scope!(&client, many: &["batch_a", "batch_b"], {
+        assert!(client.inspect("batch_a").unwrap().is_some());
+        assert!(client.inspect("batch_b").unwrap().is_some());
+    });
+    assert!(client.inspect("batch_a").unwrap().is_none());
+    assert!(client.inspect("batch_b").unwrap().is_none());
+}
+
+#[test]
+fn test_lock_scope_reentrant() {
+    let client = LockserverClient::new_with_env(
+        Some("127.0.0.1:8080"),
+        Some("reentrant_worker"),
+        None::<String>, // Use env or default for secret
+    )
+    .with_reentrant(true);
+    lock_scope!(&client, "reentrant_res", {
+        lock_scope!(&client, "reentrant_res", {
+            let status = client.inspect("reentrant_res").unwrap().unwrap();
+            assert_eq!(status.holders[0].holds, 2);
+        });
+        // The inner scope only gave up its own hold
+        assert!(client.inspect("reentrant_res").unwrap().is_some());
+    });
+    assert!(client.inspect("reentrant_res").unwrap().is_none());
+}
@@ -0,0 +1,320 @@
+use reqwest::Method;
+use reqwest::blocking::Client;
+use serde_json::{Map, Value, json};
+use std::collections::BTreeSet;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SECRET: &str = "openapi-test-secret";
+const PORT: u16 = 18111;
+
+/// A lockserver process; killed on drop.
+struct Server(Child);
+
+impl Server {
+    fn start() -> Self {
+        let child = Command::new(env!("CARGO_BIN_EXE_lockserver"))
+            .args(["--bind", "127.0.0.1", "--port", &PORT.to_string()])
+            .env("LOCKSERVER_SECRET", SECRET)
+            .env_remove("LOCKSERVER_ADMIN_SECRET")
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start lockserver");
+        let server = Server(child);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while reqwest::blocking::get(url("/openapi.json")).is_err() {
+            assert!(Instant::now() < deadline, "lockserver did not start");
+            thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn url(path: &str) -> String {
+    format!("http://127.0.0.1:{}{}", PORT, path)
+}
+
+/// Every route the server answers, by method and documented path, as it lists them.
+fn served() -> BTreeSet<(String, String)> {
+    let output = Command::new(env!("CARGO_BIN_EXE_lockserver"))
+        .arg("--routes")
+        .output()
+        .expect("failed to run lockserver");
+    assert!(output.status.success());
+    String::from_utf8(output.stdout)
+        .unwrap()
+        .lines()
+        .map(|line| {
+            let (method, path) = line.split_once(' ').unwrap();
+            (method.to_lowercase(), path.to_string())
+        })
+        .collect()
+}
+
+/// Follow a `$ref` into the spec's components.
+fn resolve<'a>(spec: &'a Value, schema: &'a Value) -> &'a Value {
+    match schema["$ref"].as_str() {
+        Some(reference) => {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            resolve(spec, &spec["components"]["schemas"][name])
+        }
+        None => schema,
+    }
+}
+
+/// The required properties of an object schema, with their schemas.
+fn required(spec: &Value, schema: &Value) -> Map<String, Value> {
+    let schema = resolve(spec, schema);
+    let mut fields = Map::new();
+    for part in schema["allOf"].as_array().into_iter().flatten() {
+        fields.extend(required(spec, part));
+    }
+    for name in schema["required"].as_array().into_iter().flatten() {
+        let name = name.as_str().unwrap();
+        fields.insert(name.to_string(), schema["properties"][name].clone());
+    }
+    fields
+}
+
+/// A value the schema accepts.
+fn sample(spec: &Value, schema: &Value) -> Value {
+    let schema = resolve(spec, schema);
+    if let Some(values) = schema["enum"].as_array() {
+        return values[0].clone();
+    }
+    match schema["type"].as_str() {
+        Some("string") => json!("openapi"),
+        Some("integer") => json!(1),
+        Some("boolean") => json!(false),
+        Some("array") => json!([sample(spec, &schema["items"])]),
+        _ => Value::Object(
+            required(spec, schema)
+                .iter()
+                .map(|(name, field)| (name.clone(), sample(spec, field)))
+                .collect(),
+        ),
+    }
+}
+
+/// Why `value` does not fit `schema`, if it does not.
+fn mismatch(spec: &Value, schema: &Value, value: &Value) -> Option<String> {
+    let schema = resolve(spec, schema);
+    if let Some(parts) = schema["allOf"].as_array() {
+        return parts.iter().find_map(|part| mismatch(spec, part, value));
+    }
+    if let Some(parts) = schema["oneOf"].as_array() {
+        return match parts
+            .iter()
+            .any(|part| mismatch(spec, part, value).is_none())
+        {
+            true => None,
+            false => Some(format!("{} fits none of the alternatives", value)),
+        };
+    }
+    if let Some(values) = schema["enum"].as_array()
+        && !values.contains(value)
+    {
+        return Some(format!("{} is not one of {:?}", value, values));
+    }
+    let types: Vec<&str> = match &schema["type"] {
+        Value::String(kind) => vec![kind],
+        Value::Array(kinds) => kinds.iter().filter_map(Value::as_str).collect(),
+        _ => return None,
+    };
+    let fits = |kind: &str| match kind {
+        "null" => value.is_null(),
+        "boolean" => value.is_boolean(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "number" => value.is_number(),
+        "string" => value.is_string(),
+        "array" => value.is_array(),
+        "object" => value.is_object(),
+        _ => true,
+    };
+    if !types.iter().any(|kind| fits(kind)) {
+        return Some(format!("{} is not {}", value, types.join(" or ")));
+    }
+    match value {
+        Value::Array(items) => items
+            .iter()
+            .find_map(|item| mismatch(spec, &schema["items"], item)),
+        Value::Object(fields) => {
+            let names = schema["required"].as_array().into_iter().flatten();
+            if let Some(name) = names
+                .filter_map(Value::as_str)
+                .find(|n| !fields.contains_key(*n))
+            {
+                return Some(format!("`{}` is missing", name));
+            }
+            fields.iter().find_map(|(name, field)| {
+                let property = schema["properties"].get(name)?;
+                mismatch(spec, property, field).map(|e| format!("`{}`: {}", name, e))
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Send `req`, and check that the status it gets is one `op` documents and that the body
+/// fits the schema documented for it. Returns the status and body.
+fn checked(
+    spec: &Value,
+    op: &Value,
+    req: reqwest::blocking::RequestBuilder,
+    context: &str,
+) -> (u16, Value) {
+    let resp = req.send().unwrap();
+    let status = resp.status().as_u16();
+    let body: Value = resp.json().unwrap_or_default();
+    let documented = &op["responses"][status.to_string()];
+    assert!(
+        documented.is_object(),
+        "{} answers {}, which is not documented",
+        context,
+        status
+    );
+    let schema = &documented["content"]["application/json"]["schema"];
+    if !schema.is_null()
+        && let Some(e) = mismatch(spec, schema, &body)
+    {
+        panic!("{} answers {} with {}: {}", context, status, body, e);
+    }
+    (status, body)
+}
+
+/// Whether the server failed to parse the request, as opposed to rejecting its content.
+fn unparsed(resp: reqwest::blocking::Response) -> bool {
+    let status = resp.status();
+    let body: Value = resp.json().unwrap_or_default();
+    status == 400
+        && body["message"]
+            .as_str()
+            .is_some_and(|m| m.contains("deserialize"))
+}
+
+#[test]
+fn test_spec_matches_handlers() {
+    let _server = Server::start();
+    let http = Client::new();
+    let spec: Value = http
+        .get(url("/openapi.json"))
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert!(spec["openapi"].as_str().unwrap().starts_with("3."));
+
+    // The spec documents exactly the routes the server answers...
+    let documented: BTreeSet<(String, String)> = spec["paths"]
+        .as_object()
+        .unwrap()
+        .iter()
+        .flat_map(|(path, item)| {
+            let methods = item.as_object().unwrap().keys();
+            methods.map(move |method| (method.clone(), path.clone()))
+        })
+        .collect();
+    let served = served();
+    let undocumented: Vec<_> = served.difference(&documented).collect();
+    assert!(
+        undocumented.is_empty(),
+        "served but not documented: {:?}",
+        undocumented
+    );
+
+    // ...and each of them is served as documented.
+    for (path, item) in spec["paths"].as_object().unwrap() {
+        for (method, op) in item.as_object().unwrap() {
+            let context = format!("{} {}", method.to_uppercase(), path);
+            let method = Method::from_bytes(method.to_uppercase().as_bytes()).unwrap();
+            let target = url(&path.replace("{resource}", "openapi/res"));
+            let body_schema = &op["requestBody"]["content"]["application/json"]["schema"];
+            let body = (!body_schema.is_null()).then(|| sample(&spec, body_schema));
+            let query: Vec<(String, Value)> = op["parameters"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter(|p| p["in"] == "query" && p["required"] == true)
+                .map(|p| {
+                    (
+                        p["name"].as_str().unwrap().to_string(),
+                        sample(&spec, &p["schema"]),
+                    )
+                })
+                .collect();
+            let query: Vec<(String, String)> = query
+                .into_iter()
+                .map(|(name, value)| {
+                    (
+                        name,
+                        value.as_str().map_or(value.to_string(), str::to_string),
+                    )
+                })
+                .collect();
+            let request = |body: Option<&Value>| {
+                let mut req = http.request(method.clone(), &target).query(&query);
+                if let Some(body) = body {
+                    req = req.json(body);
+                }
+                req
+            };
+
+            // Every documented operation is served, behind the secret unless the spec
+            // says it needs none.
+            let (status, error) = checked(&spec, op, request(body.as_ref()), &context);
+            if op["security"] == json!([{}]) {
+                assert_eq!(status, 200, "{} is not served", context);
+                continue;
+            }
+            assert_eq!(status, 401, "{} is not served", context);
+            assert_eq!(error["code"], "unauthorized", "{}", context);
+
+            // A body with just the documented required fields is accepted, and whatever
+            // the server answers is documented...
+            let authorized = request(body.as_ref()).header("X-LOCKSERVER-SECRET", SECRET);
+            let (status, error) = checked(&spec, op, authorized, &context);
+            assert!(
+                status != 400
+                    || !error["message"]
+                        .as_str()
+                        .is_some_and(|m| m.contains("deserialize")),
+                "{} rejects its documented body",
+                context
+            );
+
+            let Some(body) = body else { continue };
+            // ...and leaving out any of them is not.
+            for field in body.as_object().unwrap().keys() {
+                let mut partial = body.clone();
+                partial.as_object_mut().unwrap().remove(field);
+                let resp = request(Some(&partial))
+                    .header("X-LOCKSERVER-SECRET", SECRET)
+                    .send()
+                    .unwrap();
+                assert!(unparsed(resp), "{} does not require `{}`", context, field);
+            }
+        }
+    }
+
+    // A granted lock and a conflicting one answer with their documented shapes.
+    let put = &spec["paths"]["/v1/locks/{resource}"]["put"];
+    let acquire = |owner: &str| {
+        http.put(url("/v1/locks/openapi/held"))
+            .header("X-LOCKSERVER-SECRET", SECRET)
+            .json(&json!({ "owner": owner }))
+    };
+    let (status, granted) = checked(&spec, put, acquire("first"), "PUT /v1/locks/{resource}");
+    assert_eq!(status, 200);
+    assert!(granted["token"].is_u64());
+    let (status, conflict) = checked(&spec, put, acquire("second"), "PUT /v1/locks/{resource}");
+    assert_eq!(status, 409);
+    assert_eq!(conflict["code"], "already_locked");
+}
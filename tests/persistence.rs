@@ -0,0 +1,120 @@
+use lockserver::LockManager;
+use std::path::PathBuf;
+
+fn data_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("lockserver-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn test_state_survives_restart() {
+    let dir = data_dir("restart");
+    let token = {
+        let manager = LockManager::with_data_dir(&dir).unwrap();
+        assert!(manager.acquire("held", "owner1", None).is_ok());
+        assert!(manager.acquire("released", "owner1", None).is_ok());
+        assert!(manager.release("released", "owner1").is_ok());
+        let exclusive = lockserver::LockKind::Exclusive;
+        for _ in 0..2 {
+            assert!(
+                manager
+                    .acquire_reentrant("nested", "owner1", exclusive, None, None)
+                    .is_ok()
+            );
+        }
+        assert!(manager.release("nested", "owner1").is_ok());
+        assert!(
+            manager
+                .acquire_permits("bucket", "owner1", 2, 3, None)
+                .is_ok()
+        );
+        manager.acquire("other", "owner2", Some(60)).unwrap()
+    };
+    let manager = LockManager::with_data_dir(&dir).unwrap();
+    assert!(manager.is_locked("held"));
+    assert!(manager.is_locked("other"));
+    assert!(!manager.is_locked("released"));
+    assert_eq!(manager.inspect("nested").unwrap().holders[0].holds, 1);
+    assert!(manager.acquire("held", "owner2", None).is_err());
+    assert_eq!(manager.permits_in_use("bucket"), 2);
+    // Fencing tokens keep increasing across restarts
+    assert!(manager.acquire("released", "owner2", None).unwrap() > token);
+    assert!(manager.release("held", "owner1").is_ok());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_expired_while_down() {
+    let dir = data_dir("expired");
+    {
+        let manager = LockManager::with_data_dir(&dir).unwrap();
+        assert!(manager.acquire("short", "owner1", Some(1)).is_ok());
+        assert!(manager.acquire("long", "owner1", Some(60)).is_ok());
+    }
+    std::thread::sleep(std::time::Duration::from_secs(2));
+    let manager = LockManager::with_data_dir(&dir).unwrap();
+    assert!(!manager.is_locked("short"));
+    assert!(manager.is_locked("long"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_restores_second_timestamps() {
+    // State written before expirations had millisecond precision.
+    let dir = data_dir("seconds");
+    std::fs::create_dir_all(&dir).unwrap();
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let snapshot = serde_json::json!({
+        "seq": 1,
+        "next_token": 1,
+        "locks": {"old": {"kind": "exclusive", "holders": {
+            "owner1": {"acquired_at": now, "expire_at": now + 60}
+        }}},
+        "semaphores": {}
+    });
+    std::fs::write(dir.join("snapshot.json"), snapshot.to_string()).unwrap();
+    let record = serde_json::json!({"seq": 2, "event": {"op": "renew",
+        "resource": "old", "owner": "owner1", "expire_at": now + 120}});
+    std::fs::write(dir.join("wal.log"), format!("{}\n", record)).unwrap();
+
+    let manager = LockManager::with_data_dir(&dir).unwrap();
+    let holder = &manager.inspect("old").unwrap().holders[0];
+    assert_eq!(holder.acquired_at, now);
+    assert_eq!(holder.expires_at, Some(now + 120));
+    drop(manager);
+    // Saved again in the new format, the times are unchanged
+    let manager = LockManager::with_data_dir(&dir).unwrap();
+    assert_eq!(
+        manager.inspect("old").unwrap().holders[0].expires_at,
+        Some(now + 120)
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_clear_survives_restart() {
+    let dir = data_dir("clear");
+    let token = {
+        let manager = LockManager::with_data_dir(&dir).unwrap();
+        manager.acquire("a", "owner1", None).unwrap();
+        manager
+            .acquire_permits("bucket", "owner1", 1, 2, None)
+            .unwrap();
+        let token = manager.acquire("b", "owner2", Some(60)).unwrap();
+        assert_eq!(manager.clear().unwrap(), 2);
+        assert!(!manager.is_locked("a"));
+        manager.shutdown().unwrap();
+        token
+    };
+    let manager = LockManager::with_data_dir(&dir).unwrap();
+    assert!(!manager.is_locked("a"));
+    assert!(!manager.is_locked("b"));
+    assert_eq!(manager.permits_in_use("bucket"), 0);
+    // Fencing tokens keep increasing after the state is cleared.
+    assert!(manager.acquire("a", "owner3", None).unwrap() > token);
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,102 @@
+use lockserver::LockserverClient;
+use lockserver::client::LockMode;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+const SECRET: &str = "quorum-test-secret";
+
+/// Three independent lockserver processes; killed on drop.
+struct Servers {
+    addrs: Vec<String>,
+    children: Vec<Child>,
+}
+
+impl Servers {
+    fn start(first_port: u16) -> Self {
+        let addrs: Vec<String> = (first_port..first_port + 3)
+            .map(|port| format!("127.0.0.1:{}", port))
+            .collect();
+        let children = (first_port..first_port + 3)
+            .map(|port| {
+                Command::new(env!("CARGO_BIN_EXE_lockserver"))
+                    .args(["--bind", "127.0.0.1", "--port", &port.to_string()])
+                    .env("LOCKSERVER_SECRET", SECRET)
+                    .stdout(Stdio::null())
+                    .spawn()
+                    .expect("failed to start lockserver")
+            })
+            .collect();
+        // Wait until every server accepts connections.
+        for addr in &addrs {
+            for _ in 0..50 {
+                if std::net::TcpStream::connect(addr).is_ok() {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(100));
+            }
+        }
+        Servers { addrs, children }
+    }
+}
+
+impl Drop for Servers {
+    fn drop(&mut self) {
+        for child in &mut self.children {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+#[test]
+fn test_quorum_acquire_and_release() {
+    let servers = Servers::start(18091);
+    let a = LockserverClient::new_quorum(&servers.addrs, "worker_a", SECRET);
+    let b = LockserverClient::new_quorum(&servers.addrs, "worker_b", SECRET);
+
+    let grant = a
+        .acquire_quorum("quorum_res", LockMode::NonBlocking, 10)
+        .unwrap();
+    assert!(grant.validity > Duration::from_secs(9));
+    assert!(grant.validity < Duration::from_secs(10));
+    assert!(
+        b.acquire_quorum("quorum_res", LockMode::NonBlocking, 10)
+            .is_err()
+    );
+    // Quorum locks need an expiration.
+    assert!(
+        a.acquire_with_mode("other_res", LockMode::NonBlocking)
+            .is_err()
+    );
+
+    a.release("quorum_res").unwrap();
+    let token = b
+        .acquire_with_mode_and_expire("quorum_res", LockMode::NonBlocking, Some(10))
+        .unwrap();
+    let _guard = lockserver::LockGuard::new(&b, "quorum_res", token);
+}
+
+#[test]
+fn test_quorum_needs_majority() {
+    let servers = Servers::start(18094);
+    // Another owner holds the lock on two of the three servers.
+    for addr in &servers.addrs[..2] {
+        LockserverClient::new(addr, "other", SECRET)
+            .acquire_with_mode("quorum_res", LockMode::NonBlocking)
+            .unwrap();
+    }
+    let client = LockserverClient::new_quorum(&servers.addrs, "worker", SECRET);
+    assert!(
+        client
+            .acquire_quorum("quorum_res", LockMode::NonBlocking, 10)
+            .is_err()
+    );
+    // The grant on the third server was undone.
+    let third = LockserverClient::new(&servers.addrs[2], "third", SECRET);
+    assert!(
+        third
+            .acquire_with_mode("quorum_res", LockMode::NonBlocking)
+            .is_ok()
+    );
+}
@@ -0,0 +1,79 @@
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+const SECRET: &str = "shutdown-test-secret";
+const PORT: u16 = 18101;
+
+fn start(dir: &Path, extra: &[&str]) -> Child {
+    let child = Command::new(env!("CARGO_BIN_EXE_lockserver"))
+        .args(["--bind", "127.0.0.1", "--port", &PORT.to_string()])
+        .arg("--data-dir")
+        .arg(dir)
+        .args(extra)
+        .env("LOCKSERVER_SECRET", SECRET)
+        .stdout(Stdio::null())
+        .spawn()
+        .expect("failed to start lockserver");
+    // Wait for the server to accept requests.
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while reqwest::blocking::get(format!("http://127.0.0.1:{}/locks", PORT)).is_err() {
+        assert!(Instant::now() < deadline, "lockserver did not start");
+        thread::sleep(Duration::from_millis(50));
+    }
+    child
+}
+
+/// Send SIGTERM and wait for a clean exit.
+fn terminate(mut child: Child) {
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let deadline = Instant::now() + Duration::from_secs(10);
+    loop {
+        if let Some(status) = child.try_wait().unwrap() {
+            assert!(status.success(), "lockserver exited with {}", status);
+            return;
+        }
+        if Instant::now() > deadline {
+            let _ = child.kill();
+            panic!("lockserver did not exit on SIGTERM");
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn acquire(resource: &str) -> u16 {
+    reqwest::blocking::Client::new()
+        .post(format!("http://127.0.0.1:{}/acquire", PORT))
+        .header("X-LOCKSERVER-SECRET", SECRET)
+        .json(&serde_json::json!({"resource": resource, "owner": "w1"}))
+        .send()
+        .unwrap()
+        .status()
+        .as_u16()
+}
+
+#[test]
+fn test_sigterm_keeps_or_releases_locks() {
+    let dir = std::env::temp_dir().join(format!("lockserver-sigterm-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+
+    let server = start(&dir, &[]);
+    assert_eq!(acquire("kept"), 200);
+    terminate(server);
+
+    // Locks are persisted across a graceful restart by default...
+    let server = start(&dir, &["--release-on-shutdown"]);
+    assert_eq!(acquire("kept"), 409);
+    terminate(server);
+
+    // ...and released when asked to.
+    let server = start(&dir, &[]);
+    assert_eq!(acquire("kept"), 200);
+    terminate(server);
+    let _ = std::fs::remove_dir_all(&dir);
+}
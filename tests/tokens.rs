@@ -0,0 +1,380 @@
+use lockserver::auth::{Scope, TokenStore};
+use reqwest::blocking::{Client, Response};
+use serde_json::{Value, json};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn tokens_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+        "lockserver-tokens-{}-{}.json",
+        name,
+        std::process::id()
+    ))
+}
+
+fn write_tokens(path: &Path, tokens: Value) {
+    fs::write(path, json!({ "tokens": tokens }).to_string()).unwrap();
+}
+
+/// A lockserver process using a token file; killed on drop.
+struct Server {
+    child: Child,
+    port: u16,
+}
+
+impl Server {
+    fn start(port: u16, tokens: &Path) -> Self {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_lockserver"));
+        command.arg("--tokens").arg(tokens);
+        Self::run(port, command)
+    }
+
+    /// Start `command`, a lockserver to listen on `port`.
+    fn run(port: u16, mut command: Command) -> Self {
+        let child = command
+            .args(["--bind", "127.0.0.1", "--port", &port.to_string()])
+            .stdout(Stdio::null())
+            .spawn()
+            .expect("failed to start lockserver");
+        let server = Server { child, port };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while reqwest::blocking::get(server.url("/openapi.json")).is_err() {
+            assert!(Instant::now() < deadline, "lockserver did not start");
+            thread::sleep(Duration::from_millis(50));
+        }
+        server
+    }
+
+    /// Ask the server to re-read its token file.
+    fn reload(&self) {
+        let status = Command::new("kill")
+            .args(["-HUP", &self.child.id().to_string()])
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path)
+    }
+
+    fn put(&self, token: &str, resource: &str) -> Response {
+        Client::new()
+            .put(self.url(&format!("/v1/locks/{}", resource)))
+            .bearer_auth(token)
+            .json(&json!({"owner": "w1"}))
+            .send()
+            .unwrap()
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn code(resp: Response) -> (u16, String) {
+    let status = resp.status().as_u16();
+    let body: Value = resp.json().unwrap();
+    (
+        status,
+        body["code"].as_str().unwrap_or_default().to_string(),
+    )
+}
+
+#[test]
+fn test_token_store() {
+    let path = tokens_file("store");
+    write_tokens(
+        &path,
+        json!([
+            {"token": "a", "identity": "billing", "prefixes": ["billing/"], "scopes": ["acquire"]},
+            {"token": "b", "identity": "ops", "scopes": ["inspect", "admin"]},
+        ]),
+    );
+    let store = TokenStore::from_file(&path).unwrap();
+    assert_eq!(store.len(), 2);
+    let billing = store.authenticate("a").unwrap();
+    assert_eq!(billing.identity, "billing");
+    assert!(billing.allows(Scope::Acquire, Some("billing/invoice")));
+    assert!(!billing.allows(Scope::Acquire, Some("payroll/run")));
+    assert!(!billing.allows(Scope::Release, Some("billing/invoice")));
+    let ops = store.authenticate("b").unwrap();
+    assert!(ops.allows(Scope::Admin, Some("anything")));
+    assert!(store.authenticate("c").is_none());
+
+    // A broken file keeps the current tokens.
+    fs::write(&path, "{").unwrap();
+    assert!(store.reload().is_err());
+    assert_eq!(store.len(), 2);
+    write_tokens(
+        &path,
+        json!([
+            {"token": "a", "identity": "x", "scopes": []},
+            {"token": "a", "identity": "y", "scopes": []},
+        ]),
+    );
+    assert!(store.reload().is_err());
+    assert!(store.authenticate("b").is_some());
+    // Identities namespace lock owners, so may not contain the separator.
+    write_tokens(
+        &path,
+        json!([{"token": "d", "identity": "billing:w1", "scopes": []}]),
+    );
+    assert!(store.reload().is_err());
+    assert_eq!(store.owner(&billing, "w1"), "billing:w1");
+    let session = store.session(&billing, "s1");
+    assert!(store.may_use_session(&billing, &session));
+    assert!(!store.may_use_session(&ops, &session));
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn test_scopes_prefixes_and_rotation() {
+    let path = tokens_file("server");
+    write_tokens(
+        &path,
+        json!([
+            {"token": "billing-1", "identity": "billing", "prefixes": ["billing/"],
+             "scopes": ["acquire", "release", "inspect"]},
+            {"token": "reader", "identity": "dashboard", "scopes": ["inspect"]},
+        ]),
+    );
+    let server = Server::start(18121, &path);
+    let http = Client::new();
+
+    // Unknown tokens are unauthenticated; known ones are limited to their grant.
+    assert_eq!(
+        code(server.put("nope", "billing/a")),
+        (401, "unauthorized".into())
+    );
+    assert_eq!(server.put("billing-1", "billing/a").status(), 200);
+    assert_eq!(
+        code(server.put("billing-1", "payroll/a")),
+        (403, "forbidden".into())
+    );
+    assert_eq!(
+        code(server.put("reader", "billing/b")),
+        (403, "forbidden".into())
+    );
+    let resp = http
+        .get(server.url("/v1/locks/billing/a"))
+        .bearer_auth("reader")
+        .send()
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    // The shared-secret header carries tokens too.
+    let resp = http
+        .post(server.url("/v1/admin/release-owner"))
+        .header("X-LOCKSERVER-SECRET", "reader")
+        .json(&json!({"owner": "w1"}))
+        .send()
+        .unwrap();
+    assert_eq!(code(resp), (403, "forbidden".into()));
+
+    // Listing only shows what the token covers.
+    assert_eq!(server.put("billing-1", "billing/b").status(), 200);
+    write_tokens(
+        &path,
+        json!([
+            {"token": "billing-1", "identity": "billing", "prefixes": ["billing/"],
+             "scopes": ["acquire", "release", "inspect"]},
+            {"token": "billing-2", "identity": "billing", "prefixes": ["billing/a"],
+             "scopes": ["inspect"]},
+            {"token": "reader", "identity": "dashboard", "scopes": ["inspect"]},
+        ]),
+    );
+    server.reload();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    let page: Value = loop {
+        let resp = http
+            .get(server.url("/v1/locks"))
+            .bearer_auth("billing-2")
+            .send()
+            .unwrap();
+        if resp.status() == 200 {
+            break resp.json().unwrap();
+        }
+        assert!(Instant::now() < deadline, "token file was not reloaded");
+        thread::sleep(Duration::from_millis(50));
+    };
+    let locks = page["locks"].as_array().unwrap();
+    assert_eq!(locks.len(), 1);
+    assert_eq!(locks[0]["resource"], "billing/a");
+
+    // Rotate: once the old token is removed, only the new one works.
+    write_tokens(
+        &path,
+        json!([
+            {"token": "billing-2", "identity": "billing", "prefixes": ["billing/"],
+             "scopes": ["acquire", "release", "inspect"]},
+        ]),
+    );
+    server.reload();
+    let deadline = Instant::now() + Duration::from_secs(10);
+    while server.put("billing-1", "billing/c").status() != 401 {
+        assert!(Instant::now() < deadline, "token file was not reloaded");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert_eq!(server.put("billing-2", "billing/c").status(), 200);
+    drop(server);
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn test_admin_secret_only_in_admin_header() {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_lockserver"));
+    command
+        .env("LOCKSERVER_SECRET", "regular")
+        .env("LOCKSERVER_ADMIN_SECRET", "admin");
+    let server = Server::run(18124, command);
+    let http = Client::new();
+    let release_owner = |header: &str, value: &str| {
+        http.post(server.url("/v1/admin/release-owner"))
+            .header(header, value)
+            .json(&json!({"owner": "w1"}))
+            .send()
+            .unwrap()
+    };
+
+    assert_eq!(
+        release_owner("X-LOCKSERVER-ADMIN-SECRET", "admin").status(),
+        200
+    );
+    assert_eq!(release_owner("Authorization", "Bearer admin").status(), 200);
+    assert_eq!(
+        code(release_owner("X-LOCKSERVER-SECRET", "admin")),
+        (401, "unauthorized".into())
+    );
+    assert_eq!(
+        code(release_owner("X-LOCKSERVER-ADMIN-SECRET", "regular")),
+        (403, "forbidden".into())
+    );
+}
+
+#[test]
+fn test_owner_bound_to_identity() {
+    let path = tokens_file("owner");
+    let scopes = json!(["acquire", "release", "inspect"]);
+    write_tokens(
+        &path,
+        json!([
+            {"token": "alice-1", "identity": "alice", "scopes": scopes},
+            {"token": "alice-2", "identity": "alice", "scopes": scopes},
+            {"token": "mallory", "identity": "mallory", "scopes": scopes},
+        ]),
+    );
+    let server = Server::start(18122, &path);
+    let http = Client::new();
+    let release = |token: &str| {
+        http.delete(server.url("/v1/locks/shared/db"))
+            .bearer_auth(token)
+            .query(&[("owner", "w1")])
+            .send()
+            .unwrap()
+    };
+
+    // Locks are held as `identity:owner`, whatever owner is claimed.
+    assert_eq!(server.put("alice-1", "shared/db").status(), 200);
+    let status: Value = http
+        .get(server.url("/v1/locks/shared/db"))
+        .bearer_auth("mallory")
+        .send()
+        .unwrap()
+        .json()
+        .unwrap();
+    assert_eq!(status["holders"][0]["owner"], "alice:w1");
+
+    // Claiming the same owner from another identity gets nowhere...
+    assert_eq!(code(release("mallory")), (403, "not_owner".into()));
+    assert_eq!(
+        code(server.put("mallory", "shared/db")),
+        (409, "already_locked".into())
+    );
+    let resp = http
+        .patch(server.url("/v1/locks/shared/db"))
+        .bearer_auth("mallory")
+        .json(&json!({"owner": "w1", "expire": 1}))
+        .send()
+        .unwrap();
+    assert_eq!(code(resp), (403, "not_owner".into()));
+
+    // ...while any token of the owning identity may release.
+    assert_eq!(release("alice-2").status(), 200);
+    drop(server);
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn test_session_bound_to_identity() {
+    let path = tokens_file("session");
+    let scopes = json!(["acquire", "release", "inspect"]);
+    write_tokens(
+        &path,
+        json!([
+            {"token": "alice-1", "identity": "alice", "scopes": scopes},
+            {"token": "alice-2", "identity": "alice", "scopes": scopes},
+            {"token": "mallory", "identity": "mallory", "scopes": scopes},
+        ]),
+    );
+    let server = Server::start(18123, &path);
+    let http = Client::new();
+    let post = |token: &str, path: &str, body: Value| {
+        http.post(server.url(path))
+            .bearer_auth(token)
+            .json(&body)
+            .send()
+            .unwrap()
+    };
+    let opened: Value = post("alice-1", "/v1/session", json!({"ttl": 30}))
+        .json()
+        .unwrap();
+    let session = opened["session"].as_str().unwrap();
+
+    // Another identity may not keep the session alive, close it, or hold locks under it...
+    for path in ["/v1/session/heartbeat", "/v1/session/close"] {
+        let resp = post("mallory", path, json!({ "session": session }));
+        assert_eq!(code(resp), (403, "forbidden".into()), "{}", path);
+    }
+    let resp = http
+        .put(server.url("/v1/locks/shared/job"))
+        .bearer_auth("mallory")
+        .json(&json!({"owner": "w1", "session": session}))
+        .send()
+        .unwrap();
+    assert_eq!(code(resp), (403, "forbidden".into()));
+    let resp = post(
+        "mallory",
+        "/v1/acquire-batch",
+        json!({"resources": ["shared/a"], "owner": "w1", "session": session}),
+    );
+    assert_eq!(code(resp), (403, "forbidden".into()));
+
+    // ...while any token of the identity that opened it may.
+    let resp = http
+        .put(server.url("/v1/locks/shared/job"))
+        .bearer_auth("alice-2")
+        .json(&json!({"owner": "w1", "session": session}))
+        .send()
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let resp = post(
+        "alice-2",
+        "/v1/session/heartbeat",
+        json!({ "session": session }),
+    );
+    assert_eq!(resp.status(), 200);
+    let resp = post(
+        "alice-1",
+        "/v1/session/close",
+        json!({ "session": session }),
+    );
+    assert_eq!(resp.status(), 200);
+    drop(server);
+    let _ = fs::remove_file(&path);
+}